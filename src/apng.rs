@@ -0,0 +1,727 @@
+use crate::ffi::State;
+use crate::iter::ChunksIter;
+use crate::rustimpl::{add_chunks_after_idat, add_chunks_before_idat, auto_choose_color, check_chunk_length, check_memory_limit, check_png_color_validity, check_trailing_data, chunk_offset, compress_image_data, lodepng_color_mode_equal, lodepng_convert, lodepng_inspect, needs_color_convert, read_chunk_checked, sort_palette, ChunkBuilder, IdatDecoder};
+use crate::warning;
+use crate::{new_bitmap, zero_vec, Bitmap, ChunkPosition, ChunkRef, ColorMode, ColorType, Error, Image, WarningKind, RGBA};
+use rgb::bytemuck;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// What happens to the frame's area of the canvas before the next frame is rendered (`dispose_op` of `fcTL`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DisposeOp {
+    /// Leave the canvas as it is (`APNG_DISPOSE_OP_NONE`)
+    None = 0,
+    /// Clear the frame's area to transparent black (`APNG_DISPOSE_OP_BACKGROUND`)
+    Background = 1,
+    /// Restore the frame's area to what it was before this frame (`APNG_DISPOSE_OP_PREVIOUS`)
+    Previous = 2,
+}
+
+/// How the frame is drawn on the canvas (`blend_op` of `fcTL`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlendOp {
+    /// Replace the frame's area, including alpha (`APNG_BLEND_OP_SOURCE`)
+    Source = 0,
+    /// Alpha-blend over the canvas (`APNG_BLEND_OP_OVER`)
+    Over = 1,
+}
+
+/// One frame of an APNG, as stored in the file. See `Decoder::decode_animation`.
+#[derive(Debug)]
+pub struct Frame {
+    /// Position of the frame's image on the canvas
+    pub x_offset: u32,
+    pub y_offset: u32,
+    /// Numerator of the delay in seconds
+    pub delay_num: u16,
+    /// Denominator of the delay in seconds. 0 means 100.
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+    /// Pixels of the frame's area, in `info_raw()` color mode of the `Decoder`
+    pub image: Image,
+}
+
+impl Frame {
+    /// How long the frame is shown for
+    #[must_use]
+    pub fn delay(&self) -> Duration {
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den };
+        Duration::from_secs_f64(f64::from(self.delay_num) / f64::from(den))
+    }
+}
+
+/// Frames of an APNG file, or a single frame of a regular PNG file
+#[derive(Debug)]
+pub struct Animation {
+    /// Size of the canvas
+    pub width: usize,
+    pub height: usize,
+    /// How many times to play the animation. 0 means forever.
+    pub num_plays: u32,
+    /// False if the default image (`IDAT`) isn't part of the animation. It's not decoded then.
+    pub default_image_is_first_frame: bool,
+    pub frames: Vec<Frame>,
+    /// color mode of frames' images
+    raw_mode: ColorMode,
+}
+
+impl Animation {
+    /// Number of frames in the animation (`num_frames` of `acTL`)
+    #[inline]
+    #[must_use]
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Renders the frames on the canvas, as they're meant to be displayed
+    ///
+    /// Yields the whole canvas after each frame, in 8-bit RGBA.
+    /// Delays of the frames are in `frames`.
+    #[must_use]
+    pub fn composite(&self) -> Compositor<'_, RGBA> {
+        Compositor::new(self, 8)
+    }
+
+    /// Same as `composite()`, but in 16-bit RGBA.
+    ///
+    /// Unlike `Image::RGBA16`, the values are native `u16`, not in big-endian byte order.
+    #[must_use]
+    pub fn composite16(&self) -> Compositor<'_, rgb::Rgba<u16>> {
+        Compositor::new(self, 16)
+    }
+}
+
+/// `fcTL` with data from `IDAT` or `fdAT` chunks
+struct FrameData<'a> {
+    w: u32,
+    h: u32,
+    x_offset: u32,
+    y_offset: u32,
+    delay_num: u16,
+    delay_den: u16,
+    dispose_op: DisposeOp,
+    blend_op: BlendOp,
+    /// compressed image data
+    data: Vec<&'a [u8]>,
+    from_idat: bool,
+}
+
+fn read_chunk_actl(data: &[u8]) -> Result<(u32, u32), Error> {
+    if data.len() != 8 {
+        return Err(Error::new(97));
+    }
+    let num_frames = u32::from_be_bytes(data[0..4].try_into().unwrap());
+    let num_plays = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    if num_frames == 0 {
+        return Err(Error::new(97));
+    }
+    Ok((num_frames, num_plays))
+}
+
+fn read_chunk_fctl<'a>(data: &[u8], w: u32, h: u32) -> Result<FrameData<'a>, Error> {
+    if data.len() != 26 {
+        return Err(Error::new(98));
+    }
+    let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+    let frame = FrameData {
+        w: u32_at(4),
+        h: u32_at(8),
+        x_offset: u32_at(12),
+        y_offset: u32_at(16),
+        delay_num: u16::from_be_bytes([data[20], data[21]]),
+        delay_den: u16::from_be_bytes([data[22], data[23]]),
+        dispose_op: match data[24] {
+            0 => DisposeOp::None,
+            1 => DisposeOp::Background,
+            2 => DisposeOp::Previous,
+            _ => return Err(Error::new(98)),
+        },
+        blend_op: match data[25] {
+            0 => BlendOp::Source,
+            1 => BlendOp::Over,
+            _ => return Err(Error::new(98)),
+        },
+        data: Vec::new(),
+        from_idat: false,
+    };
+    /*frame must be non-empty and entirely within the canvas*/
+    if frame.w == 0 || frame.h == 0 ||
+        u64::from(frame.x_offset) + u64::from(frame.w) > u64::from(w) ||
+        u64::from(frame.y_offset) + u64::from(frame.h) > u64::from(h) {
+        return Err(Error::new(98));
+    }
+    Ok(frame)
+}
+
+/*fcTL and fdAT chunks share one sequence, which must count up from 0 without gaps*/
+fn check_sequence(data: &[u8], next_sequence: &mut u32) -> Result<(), Error> {
+    let seq = u32::from_be_bytes(data.get(..4).ok_or(Error::new(99))?.try_into().unwrap());
+    if seq != *next_sequence {
+        return Err(Error::new(99));
+    }
+    *next_sequence += 1;
+    Ok(())
+}
+
+pub(crate) fn decode_animation(state: &mut State, inp: &[u8]) -> Result<Animation, Error> {
+    let (info, w, h) = lodepng_inspect(&state.decoder, inp, false)?;
+    state.info_png = info;
+    /*same limit as decode_generic. Frames can't be larger than the canvas*/
+    let numpixels = (w as usize).checked_mul(h as usize).ok_or(Error::new(92))?;
+    if numpixels > (isize::MAX as usize - 1) / 4 / 2 {
+        return Err(Error::new(92));
+    }
+
+    let mut found_iend = false;
+    let mut unknown = false;
+    let mut critical_pos = ChunkPosition::IHDR;
+    let mut actl = None;
+    let mut frames: Vec<FrameData<'_>> = Vec::new();
+    let mut default_image = Vec::new();
+    let mut next_sequence = 0;
+
+    let chunks = ChunksIter { data: inp.get(33..).ok_or(Error::new(27))? };
+    for ch in chunks {
+        let ch: ChunkRef<'_> = ch?;
+        let offset = chunk_offset(inp, &ch);
+        /*errors get the chunk type and position*/
+        (|| -> Result<(), Error> {
+            check_chunk_length(&state.decoder, ch.len())?;
+            let data = ch.data();
+            /*other chunks are checked by read_chunk_checked*/
+            let is_animation_chunk = matches!(&ch.name(), b"IDAT" | b"acTL") || (matches!(&ch.name(), b"fcTL" | b"fdAT") && actl.is_some());
+            if is_animation_chunk && !state.decoder.ignore_crc && !unknown && !ch.check_crc() {
+                return Err(ch.crc_error());
+            }
+            match &ch.name() {
+                b"IDAT" => {
+                    critical_pos = ChunkPosition::IDAT;
+                    match frames.last_mut() {
+                        Some(frame) if frame.from_idat => frame.data.push(data),
+                        _ => default_image.push(data),
+                    }
+                },
+                b"acTL" => {
+                    if actl.is_some() || critical_pos == ChunkPosition::IDAT {
+                        return Err(Error::new(97));
+                    }
+                    actl = Some(read_chunk_actl(data)?);
+                },
+                b"fcTL" if actl.is_some() => {
+                    check_sequence(data, &mut next_sequence)?;
+                    let mut frame = read_chunk_fctl(data, w, h)?;
+                    if frames.last().is_some_and(|f| f.data.is_empty()) {
+                        /*previous frame has no image data*/
+                        return Err(Error::new(100));
+                    }
+                    /*fcTL before IDAT makes the default image the first frame*/
+                    if critical_pos != ChunkPosition::IDAT {
+                        if frame.x_offset != 0 || frame.y_offset != 0 || frame.w != w || frame.h != h {
+                            /*the default image has the size of the canvas*/
+                            return Err(Error::new(98));
+                        }
+                        frame.from_idat = true;
+                    }
+                    frames.push(frame);
+                },
+                b"fdAT" if actl.is_some() => {
+                    check_sequence(data, &mut next_sequence)?;
+                    match frames.last_mut() {
+                        /*fdAT can't continue the default image or come before it*/
+                        Some(frame) if !frame.from_idat && critical_pos == ChunkPosition::IDAT => frame.data.push(&data[4..]),
+                        _ => return Err(Error::new(100)),
+                    }
+                },
+                _ => read_chunk_checked(&mut state.info_png, &state.decoder, &mut critical_pos, &mut unknown, ch, offset)?,
+            }
+            if ch.is_type(b"IEND") {
+                found_iend = true;
+                check_trailing_data(&mut state.info_png, &state.decoder, inp, offset + ch.len() + 12)?;
+            }
+            Ok(())
+        })().map_err(|e| e.in_chunk(ch.name(), offset))?;
+        if found_iend {
+            break;
+        }
+    }
+    if !found_iend {
+        warning::warn(&mut state.info_png, &state.decoder, WarningKind::MissingIend, None, inp.len())?;
+    }
+    if default_image.is_empty() && !frames.first().is_some_and(|f| f.from_idat) {
+        /*error: no image data*/
+        return Err(Error::new(91));
+    }
+
+    let num_plays = match actl {
+        Some((num_frames, num_plays)) => {
+            if frames.len() != num_frames as usize || frames.last().is_some_and(|f| f.data.is_empty()) {
+                return Err(Error::new(101));
+            }
+            num_plays
+        },
+        None => {
+            /*not animated. The default image is the only frame*/
+            frames = vec![FrameData {
+                w, h, x_offset: 0, y_offset: 0, delay_num: 0, delay_den: 0,
+                dispose_op: DisposeOp::None, blend_op: BlendOp::Source,
+                data: default_image, from_idat: true,
+            }];
+            0
+        },
+    };
+
+    /*all frames are kept, so they count together towards the memory limit*/
+    let mut memory = 0u64;
+    for frame in &frames {
+        memory += check_memory_limit(state, frame.w, frame.h)?;
+        if memory > state.decoder.limits.max_memory {
+            return Err(Error::new(122));
+        }
+    }
+
+    let convert = needs_color_convert(state)?;
+    let default_image_is_first_frame = frames[0].from_idat;
+    let frames = frames.into_iter().map(|frame| {
+        let (fw, fh) = (frame.w, frame.h);
+        let mut dec = IdatDecoder::new(&state.info_png, fw, fh, frame.data.iter().map(|d| d.len()).sum(), &state.decoder.zlibsettings)?;
+        for data in &frame.data {
+            dec.zlib.push(data)?;
+        }
+        let mut pixels = dec.finish(fw, fh, &state.info_png)?;
+        if convert {
+            let mut out = zero_vec(state.info_raw.raw_size_opt(fw, fh)?)?;
+            lodepng_convert(&mut out, &pixels, &state.info_raw, &state.info_png.color, fw, fh)?;
+            pixels = out;
+        }
+        Ok(Frame {
+            x_offset: frame.x_offset,
+            y_offset: frame.y_offset,
+            delay_num: frame.delay_num,
+            delay_den: frame.delay_den,
+            dispose_op: frame.dispose_op,
+            blend_op: frame.blend_op,
+            image: new_bitmap(pixels, fw, fh, state.info_raw.colortype, state.info_raw.bitdepth)?,
+        })
+    }).collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(Animation {
+        width: w as usize,
+        height: h as usize,
+        num_plays,
+        default_image_is_first_frame,
+        frames,
+        raw_mode: state.info_raw.clone(),
+    })
+}
+
+/// Iterator of fully rendered frames of an animation. See `Animation::composite()`.
+///
+/// Applies `dispose_op` of the previous frame and `blend_op` of the current frame.
+pub struct Compositor<'anim, Pixel> {
+    animation: &'anim Animation,
+    bitdepth: u32,
+    next_frame: usize,
+    /// channels are 0-255 or 0-65535, depending on bitdepth
+    canvas: Vec<[u16; 4]>,
+    /// previous frame's area and what to put there before drawing the next frame
+    dispose: Option<(Rect, Option<Vec<[u16; 4]>>)>,
+    _pixel: PhantomData<Pixel>,
+}
+
+impl<'anim, Pixel> Compositor<'anim, Pixel> {
+    fn new(animation: &'anim Animation, bitdepth: u32) -> Self {
+        Self {
+            animation,
+            bitdepth,
+            next_frame: 0,
+            canvas: Vec::new(),
+            dispose: None,
+            _pixel: PhantomData,
+        }
+    }
+
+    /// Draws the next frame, and returns the canvas
+    fn render_next(&mut self) -> Option<Result<&[[u16; 4]], Error>> {
+        let frame = self.animation.frames.get(self.next_frame)?;
+        let is_first = self.next_frame == 0;
+        self.next_frame += 1;
+        Some(self.render(frame, is_first).map(|()| &self.canvas[..]))
+    }
+
+    fn render(&mut self, frame: &Frame, is_first: bool) -> Result<(), Error> {
+        let (w, h) = (self.animation.width, self.animation.height);
+        let (fw, fh) = (frame.image.width(), frame.image.height());
+        if fw == 0 || fh == 0 || frame.x_offset as usize + fw > w || frame.y_offset as usize + fh > h {
+            return Err(Error::new(98));
+        }
+        let rect = Rect { x: frame.x_offset, y: frame.y_offset, w: fw as u32, h: fh as u32 };
+        if self.canvas.is_empty() {
+            self.canvas.try_reserve_exact(w * h)?;
+            self.canvas.resize(w * h, [0; 4]);
+        }
+
+        match self.dispose.take() {
+            Some((prev, Some(saved))) => {
+                for (row, saved) in rect_rows(w as u32, prev).zip(saved.chunks_exact(prev.w as usize)) {
+                    self.canvas[row].copy_from_slice(saved);
+                }
+            },
+            Some((prev, None)) => fill_rect(&mut self.canvas, w as u32, prev, [0; 4]),
+            None => {},
+        }
+        self.dispose = match frame.dispose_op {
+            DisposeOp::None => None,
+            /*APNG_DISPOSE_OP_PREVIOUS of the first frame is the same as APNG_DISPOSE_OP_BACKGROUND*/
+            DisposeOp::Previous if !is_first => Some((rect, Some(crop(&self.canvas, w as u32, rect)))),
+            DisposeOp::Background | DisposeOp::Previous => Some((rect, None)),
+        };
+
+        let mode_out = ColorType::RGBA.to_color_mode(self.bitdepth);
+        let mut converted = zero_vec(mode_out.raw_size_opt(rect.w, rect.h)?)?;
+        lodepng_convert(&mut converted, frame.image.bytes(), &mode_out, &self.animation.raw_mode, rect.w, rect.h)?;
+        let max = if self.bitdepth == 16 { u16::MAX } else { 255 };
+        let pixels = converted.chunks_exact(mode_out.bpp() as usize / 8).map(|px| {
+            if self.bitdepth == 16 {
+                [0, 2, 4, 6].map(|i| u16::from_be_bytes([px[i], px[i + 1]]))
+            } else {
+                [0, 1, 2, 3].map(|i| u16::from(px[i]))
+            }
+        });
+        let dest = rect_rows(w as u32, rect).flatten();
+        for (i, src) in dest.zip(pixels) {
+            let dst = &mut self.canvas[i];
+            *dst = match frame.blend_op {
+                BlendOp::Source => src,
+                BlendOp::Over => blend_over(*dst, src, max),
+            };
+        }
+        Ok(())
+    }
+}
+
+/*APNG_BLEND_OP_OVER with straight (non-premultiplied) alpha, rounded to nearest*/
+fn blend_over(dst: [u16; 4], src: [u16; 4], max: u16) -> [u16; 4] {
+    let max = u64::from(max);
+    let (sa, da) = (u64::from(src[3]), u64::from(dst[3]));
+    if sa == max || da == 0 {
+        return src;
+    }
+    if sa == 0 {
+        return dst;
+    }
+    /*alpha of the result, scaled by max*/
+    let dst_weight = da * (max - sa);
+    let out_a = sa * max + dst_weight;
+    let mix = |s: u16, d: u16| ((u64::from(s) * sa * max + u64::from(d) * dst_weight + out_a / 2) / out_a) as u16;
+    [mix(src[0], dst[0]), mix(src[1], dst[1]), mix(src[2], dst[2]), ((out_a + max / 2) / max) as u16]
+}
+
+impl Iterator for Compositor<'_, RGBA> {
+    type Item = Result<Bitmap<RGBA>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (width, height) = (self.animation.width, self.animation.height);
+        Some(self.render_next()?.map(|canvas| Bitmap {
+            buffer: canvas.iter().map(|&[r, g, b, a]| RGBA::new(r as u8, g as u8, b as u8, a as u8)).collect(),
+            width,
+            height,
+        }))
+    }
+}
+
+impl Iterator for Compositor<'_, rgb::Rgba<u16>> {
+    type Item = Result<Bitmap<rgb::Rgba<u16>>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (width, height) = (self.animation.width, self.animation.height);
+        Some(self.render_next()?.map(|canvas| Bitmap {
+            buffer: canvas.iter().map(|&[r, g, b, a]| rgb::Rgba::new(r, g, b, a)).collect(),
+            width,
+            height,
+        }))
+    }
+}
+
+/// Makes an APNG file out of full-canvas RGBA frames. Created with `Encoder::animation`.
+///
+/// Frames are stored as the smallest rectangle that changed since the previous frame,
+/// with `dispose_op` and `blend_op` chosen to make the frames small.
+pub struct AnimationEncoder<'enc> {
+    state: &'enc State,
+    width: u32,
+    height: u32,
+    num_plays: u32,
+    /// pixels of all frames, one after another
+    canvases: Vec<RGBA>,
+    delays: Vec<(u16, u16)>,
+}
+
+/// Area of a frame on the canvas
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+/// Frame as it will be written to the file
+struct EncodedFrame {
+    rect: Rect,
+    dispose_op: DisposeOp,
+    blend_op: BlendOp,
+    /// zlib-compressed scanlines
+    data: Vec<u8>,
+}
+
+impl<'enc> AnimationEncoder<'enc> {
+    pub(crate) fn new(state: &'enc State, width: u32, height: u32) -> Result<Self, Error> {
+        if width == 0 || height == 0 {
+            return Err(Error::new(93));
+        }
+        Ok(Self {
+            state,
+            width,
+            height,
+            num_plays: 0,
+            canvases: Vec::new(),
+            delays: Vec::new(),
+        })
+    }
+
+    /// How many times to play the animation. 0 (the default) means forever.
+    #[inline]
+    pub fn set_num_plays(&mut self, num_plays: u32) {
+        self.num_plays = num_plays;
+    }
+
+    /// Adds a frame shown for `delay_num`/`delay_den` seconds (denominator 0 means 100).
+    ///
+    /// The image must have the size of the whole canvas.
+    pub fn add_frame(&mut self, image: &[RGBA], delay_num: u16, delay_den: u16) -> Result<(), Error> {
+        if image.len() != self.width as usize * self.height as usize {
+            return Err(Error::new(84));
+        }
+        self.canvases.try_reserve(image.len())?;
+        self.canvases.extend_from_slice(image);
+        self.delays.push((delay_num, delay_den));
+        Ok(())
+    }
+
+    /// Number of frames added so far
+    #[inline]
+    #[must_use]
+    pub fn num_frames(&self) -> usize {
+        self.delays.len()
+    }
+
+    /// Compresses all frames and makes the APNG file
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let (w, h) = (self.width, self.height);
+        let num_frames = u32::try_from(self.delays.len()).map_err(|_| Error::new(77))?;
+        if num_frames == 0 {
+            return Err(Error::new(102));
+        }
+        let encoder = &self.state.encoder;
+        let rgba = ColorMode::new();
+        let mut info = self.state.info_png.clone();
+        if encoder.auto_convert {
+            /*all frames share one color mode, so it's chosen as if they were one tall image*/
+            let all_h = h.checked_mul(num_frames).filter(|&all_h| w.checked_mul(all_h).is_some());
+            info.color = match all_h {
+                Some(all_h) => {
+                    let canvases = bytemuck::cast_slice(&self.canvases);
+                    let mut color = auto_choose_color(canvases, w, all_h, &rgba)?;
+                    if color.colortype == ColorType::PALETTE {
+                        sort_palette(&mut color, canvases, w, all_h, &rgba, encoder.palette_sort)?;
+                    }
+                    color
+                },
+                None => rgba.clone(),
+            };
+        }
+        if (info.color.colortype == ColorType::PALETTE || encoder.force_palette) && (info.color.palette().is_empty() || info.color.palette().len() > 256) {
+            return Err(Error::new(68));
+        }
+        if info.interlace_method > 1 {
+            return Err(Error::new(71));
+        }
+        check_png_color_validity(info.color.colortype, info.color.bitdepth())?;
+
+        let compress = |pixels: &[RGBA], rect: Rect| -> Result<Vec<u8>, Error> {
+            let mut converted;
+            let mut image = bytemuck::cast_slice(pixels);
+            if !lodepng_color_mode_equal(&rgba, &info.color) {
+                converted = zero_vec(info.color.raw_size_opt(rect.w, rect.h)?)?;
+                lodepng_convert(&mut converted, image, &info.color, &rgba, rect.w, rect.h)?;
+                image = &converted;
+            }
+            let mut out = Vec::new();
+            compress_image_data(&mut out, image, rect.w, rect.h, &info, encoder, &encoder.zlibsettings)?;
+            Ok(out)
+        };
+
+        let transparent = transparent_pixel(&info.color);
+        let canvas_len = w as usize * h as usize;
+        let mut canvases = self.canvases.chunks_exact(canvas_len);
+        let first = canvases.next().ok_or(Error::new(102))?;
+        let full = Rect { x: 0, y: 0, w, h };
+        let mut frames = Vec::with_capacity(self.delays.len());
+        /*the default image is the first frame, and it replaces the whole canvas when the animation loops*/
+        frames.push(EncodedFrame { rect: full, dispose_op: DisposeOp::None, blend_op: BlendOp::Source, data: compress(first, full)? });
+
+        /*what was on the canvas before the previous frame has been drawn*/
+        let mut base = vec![RGBA::new(0, 0, 0, 0); canvas_len];
+        let mut prev = first;
+        for (i, target) in canvases.enumerate() {
+            let prev_frame = frames.last_mut().ok_or(Error::new(102))?;
+            let mut cleared = prev.to_vec();
+            fill_rect(&mut cleared, w, prev_frame.rect, RGBA::new(0, 0, 0, 0));
+            /*APNG_DISPOSE_OP_PREVIOUS of the first frame is the same as APNG_DISPOSE_OP_BACKGROUND*/
+            let restore_previous = if i > 0 { Some(base) } else { None };
+
+            /*dispose the previous frame in whichever way leaves the fewest pixels to change*/
+            let mut dispose_op = DisposeOp::None;
+            let mut rect = changed_rect(prev, target, w, h);
+            let area = |r: Rect| u64::from(r.w) * u64::from(r.h);
+            let cleared_rect = changed_rect(&cleared, target, w, h);
+            if area(cleared_rect) < area(rect) {
+                (dispose_op, rect) = (DisposeOp::Background, cleared_rect);
+            }
+            if let Some(restored) = &restore_previous {
+                let restored_rect = changed_rect(restored, target, w, h);
+                if area(restored_rect) < area(rect) {
+                    (dispose_op, rect) = (DisposeOp::Previous, restored_rect);
+                }
+            }
+            prev_frame.dispose_op = dispose_op;
+            base = match (dispose_op, restore_previous) {
+                (DisposeOp::Background, _) => cleared,
+                (DisposeOp::Previous, Some(restored)) => restored,
+                _ => prev.to_vec(),
+            };
+
+            let source = crop(target, w, rect);
+            let mut frame = EncodedFrame { rect, dispose_op: DisposeOp::None, blend_op: BlendOp::Source, data: compress(&source, rect)? };
+            /*blending over the canvas lets unchanged pixels be transparent, which compresses better*/
+            if let Some(over) = transparent.and_then(|t| blend_over_pixels(&base, target, w, rect, t)) {
+                if over != source {
+                    let data = compress(&over, rect)?;
+                    if data.len() < frame.data.len() {
+                        frame.blend_op = BlendOp::Over;
+                        frame.data = data;
+                    }
+                }
+            }
+            frames.push(frame);
+            prev = target;
+        }
+
+        let mut out = Vec::new();
+        add_chunks_before_idat(&mut out, w, h, &info, encoder)?;
+        let mut actl = ChunkBuilder::new(&mut out, b"acTL");
+        actl.write_u32be(num_frames);
+        actl.write_u32be(self.num_plays);
+        actl.finish()?;
+        let mut sequence = 0;
+        for (i, (frame, &delay)) in frames.iter().zip(&self.delays).enumerate() {
+            add_chunk_fctl(&mut out, sequence, frame, delay)?;
+            sequence += 1;
+            let mut ch = if i == 0 {
+                ChunkBuilder::new(&mut out, b"IDAT")
+            } else {
+                let mut ch = ChunkBuilder::new(&mut out, b"fdAT");
+                ch.write_u32be(sequence);
+                sequence += 1;
+                ch
+            };
+            ch.extend_from_slice(&frame.data)?;
+            ch.finish()?;
+        }
+        add_chunks_after_idat(&mut out, &info, encoder)?;
+        Ok(out)
+    }
+}
+
+fn add_chunk_fctl(out: &mut Vec<u8>, sequence: u32, frame: &EncodedFrame, (delay_num, delay_den): (u16, u16)) -> Result<(), Error> {
+    let mut ch = ChunkBuilder::new(out, b"fcTL");
+    ch.write_u32be(sequence);
+    ch.write_u32be(frame.rect.w);
+    ch.write_u32be(frame.rect.h);
+    ch.write_u32be(frame.rect.x);
+    ch.write_u32be(frame.rect.y);
+    ch.write_u16be(delay_num);
+    ch.write_u16be(delay_den);
+    ch.push(frame.dispose_op as u8);
+    ch.push(frame.blend_op as u8);
+    ch.finish()
+}
+
+/*bounding box of pixels that differ. Frames can't be empty, so it's at least 1x1*/
+fn changed_rect(base: &[RGBA], target: &[RGBA], w: u32, h: u32) -> Rect {
+    let w = w as usize;
+    let rows = base.chunks_exact(w).zip(target.chunks_exact(w));
+    let (mut x0, mut y0, mut x1, mut y1) = (w, h as usize, 0, 0);
+    for (y, (base_row, target_row)) in rows.enumerate() {
+        let Some(first) = base_row.iter().zip(target_row).position(|(b, t)| b != t) else { continue };
+        let last = base_row.iter().zip(target_row).rposition(|(b, t)| b != t).unwrap_or(first);
+        x0 = x0.min(first);
+        x1 = x1.max(last + 1);
+        y0 = y0.min(y);
+        y1 = y + 1;
+    }
+    if y1 == 0 {
+        return Rect { x: 0, y: 0, w: 1, h: 1 };
+    }
+    Rect { x: x0 as u32, y: y0 as u32, w: (x1 - x0) as u32, h: (y1 - y0) as u32 }
+}
+
+fn rect_rows(w: u32, rect: Rect) -> impl Iterator<Item = std::ops::Range<usize>> {
+    let (w, x, rw) = (w as usize, rect.x as usize, rect.w as usize);
+    (rect.y as usize..(rect.y + rect.h) as usize).map(move |y| y * w + x..y * w + x + rw)
+}
+
+fn crop<T: Copy>(canvas: &[T], w: u32, rect: Rect) -> Vec<T> {
+    rect_rows(w, rect).flat_map(|row| &canvas[row]).copied().collect()
+}
+
+fn fill_rect<T: Copy>(canvas: &mut [T], w: u32, rect: Rect, color: T) {
+    for row in rect_rows(w, rect) {
+        canvas[row].fill(color);
+    }
+}
+
+/*frame that draws target over base with APNG_BLEND_OP_OVER, if translucent pixels don't make that impossible*/
+fn blend_over_pixels(base: &[RGBA], target: &[RGBA], w: u32, rect: Rect, transparent: RGBA) -> Option<Vec<RGBA>> {
+    rect_rows(w, rect).flat_map(|row| base[row.clone()].iter().zip(&target[row])).map(|(b, t)| {
+        if b == t {
+            Some(transparent)
+        } else if t.a == 255 {
+            Some(*t)
+        } else {
+            None
+        }
+    }).collect()
+}
+
+/*a color that stays fully transparent in the PNG's color mode, if the color mode has any*/
+fn transparent_pixel(mode: &ColorMode) -> Option<RGBA> {
+    let bitdepth = mode.bitdepth();
+    let key = mode.key().map(|(r, g, b)| {
+        let to_8bit = |v: u16| if bitdepth >= 16 { (v >> 8) as u8 } else { (u32::from(v) * 255 / ((1 << bitdepth) - 1)) as u8 };
+        RGBA::new(to_8bit(r), to_8bit(g), to_8bit(b), 0)
+    });
+    let palette = mode.palette().iter().copied().filter(|p| p.a == 0);
+    let rgba = ColorMode::new();
+    [RGBA::new(0, 0, 0, 0)].into_iter().chain(key).chain(palette).find(|px| {
+        let mut pixel = [0; 8];
+        let mut back = [0; 4];
+        lodepng_convert(&mut pixel, bytemuck::bytes_of(px), mode, &rgba, 1, 1).is_ok() &&
+            lodepng_convert(&mut back, &pixel, &rgba, mode, 1, 1).is_ok() &&
+            back[3] == 0
+    })
+}
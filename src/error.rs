@@ -4,9 +4,44 @@ use std::error;
 use std::fmt;
 use std::io;
 use std::num::NonZeroU32;
+use std::sync::Arc;
 
-#[derive(Copy, Clone)]
-pub struct Error(NonZeroU32);
+/// Error from the decoder or encoder.
+///
+/// Converts to the numeric `ErrorCode` used by the C API. When the decoder knows where the error happened,
+/// it also records the chunk type, the byte offset of the chunk in the file, and expected vs actual values.
+#[derive(Clone)]
+pub struct Error {
+    code: NonZeroU32,
+    /// Most errors have no context, so they don't need to allocate
+    context: Option<Box<Context>>,
+}
+
+#[derive(Clone, Default)]
+struct Context {
+    chunk_type: Option<[u8; 4]>,
+    offset: Option<u64>,
+    mismatch: Option<(u64, u64)>,
+    source: Option<Arc<io::Error>>,
+}
+
+/// General category of an `Error`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The file is not a valid PNG, or is corrupted
+    Format,
+    /// CRC or Adler-32 checksum doesn't match the data
+    Checksum,
+    /// Valid, but not supported by this library or by the chosen settings
+    Unsupported,
+    /// Exceeds `Limits` or the maximum size supported, or memory allocation failed
+    Limits,
+    /// Reading or writing failed
+    Io,
+    /// Invalid image or settings given to the encoder
+    Encode,
+}
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -24,7 +59,7 @@ impl ErrorCode {
     pub fn to_result(self) -> Result<(), Error> {
         match NonZeroU32::new(self.0) {
             None => Ok(()),
-            Some(err) => Err(Error(err)),
+            Some(code) => Err(Error { code, context: None }),
         }
     }
 }
@@ -34,7 +69,86 @@ impl Error {
     #[cold]
     #[must_use]
     pub const fn new(code: u32) -> Self {
-        Self(if let Some(s) = NonZeroU32::new(code) { s } else { panic!() })
+        Self {
+            code: if let Some(s) = NonZeroU32::new(code) { s } else { panic!() },
+            context: None,
+        }
+    }
+
+    /// Numeric code, the same as used by the C version of the library
+    #[inline]
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode(self.code.get())
+    }
+
+    /// General category of the error
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        match self.code.get() {
+            57 | 58 => ErrorKind::Checksum,
+            63 | 77 | 83 | 92 | 119..=124 => ErrorKind::Limits,
+            78 | 79 => ErrorKind::Io,
+            56 | 59 | 62 | 69 | 87 | 137 => ErrorKind::Unsupported,
+            60 | 61 | 64 | 66..=68 | 71 | 80..=82 | 84 | 86 | 88 | 90 | 95 | 96 | 102 | 106 | 134..=136 | 138 => ErrorKind::Encode,
+            _ => ErrorKind::Format,
+        }
+    }
+
+    /// Type of the chunk in which the error was found, e.g. `b"IDAT"`
+    #[must_use]
+    pub fn chunk_type(&self) -> Option<[u8; 4]> {
+        self.context.as_ref()?.chunk_type
+    }
+
+    /// Byte offset of the start of the offending chunk (its length field) in the file
+    #[must_use]
+    pub fn offset(&self) -> Option<u64> {
+        self.context.as_ref()?.offset
+    }
+
+    /// The value the decoder expected, e.g. CRC computed from the chunk data, or required chunk size
+    #[must_use]
+    pub fn expected(&self) -> Option<u64> {
+        Some(self.context.as_ref()?.mismatch?.0)
+    }
+
+    /// The value found in the file, e.g. CRC stored in the chunk, or actual chunk size
+    #[must_use]
+    pub fn actual(&self) -> Option<u64> {
+        Some(self.context.as_ref()?.mismatch?.1)
+    }
+
+    #[cold]
+    fn context_mut(&mut self) -> &mut Context {
+        self.context.get_or_insert_with(Default::default)
+    }
+
+    /// Adds chunk type and its offset, unless a more specific location has been set already
+    #[cold]
+    pub(crate) fn in_chunk(mut self, chunk_type: [u8; 4], offset: usize) -> Self {
+        let ctx = self.context_mut();
+        if ctx.chunk_type.is_none() && ctx.offset.is_none() {
+            ctx.chunk_type = Some(chunk_type);
+            ctx.offset = Some(offset as u64);
+        }
+        self
+    }
+
+    /// Adds the offset of a problem that isn't in any chunk
+    #[cold]
+    pub(crate) fn at_offset(mut self, offset: usize) -> Self {
+        let ctx = self.context_mut();
+        if ctx.offset.is_none() {
+            ctx.offset = Some(offset as u64);
+        }
+        self
+    }
+
+    #[cold]
+    pub(crate) fn with_mismatch(mut self, expected: u64, actual: u64) -> Self {
+        self.context_mut().mismatch = Some((expected, actual));
+        self
     }
 }
 
@@ -48,14 +162,15 @@ impl From<ErrorCode> for Result<(), Error> {
 impl From<Error> for ErrorCode {
     #[inline(always)]
     fn from(err: Error) -> Self {
-        Self(err.0.get())
+        err.code()
     }
 }
 
 impl fmt::Debug for Error {
     #[cold]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ({})", ErrorCode(self.0.get()).as_str(), self.0)
+        fmt::Display::fmt(self, f)?;
+        write!(f, " ({})", self.code)
     }
 }
 
@@ -69,21 +184,45 @@ impl fmt::Debug for ErrorCode {
 impl fmt::Display for Error {
     #[cold]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(ErrorCode(self.0.get()).as_str())
+        f.write_str(self.code().as_str())?;
+        let Some(ctx) = &self.context else { return Ok(()) };
+        if let Some(chunk_type) = ctx.chunk_type {
+            write!(f, " in {} chunk", String::from_utf8_lossy(&chunk_type))?;
+        }
+        if let Some(offset) = ctx.offset {
+            write!(f, " at byte {offset}")?;
+        }
+        if let Some((expected, actual)) = ctx.mismatch {
+            if self.kind() == ErrorKind::Checksum {
+                write!(f, " (expected {expected:08X}, found {actual:08X})")?;
+            } else {
+                write!(f, " (expected {expected}, found {actual})")?;
+            }
+        }
+        if let Some(source) = &ctx.source {
+            write!(f, ": {source}")?;
+        }
+        Ok(())
     }
 }
 
-impl error::Error for Error {}
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.context.as_ref()?.source.as_deref().map(|e| e as _)
+    }
+}
 
 #[doc(hidden)]
 impl std::convert::From<io::Error> for Error {
     #[cold]
     fn from(err: io::Error) -> Self {
-        match err.kind() {
+        let mut e = match err.kind() {
             io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof => Self::new(78),
             io::ErrorKind::OutOfMemory => Self::new(83),
             _ => Self::new(79),
-        }
+        };
+        e.context_mut().source = Some(Arc::new(err));
+        e
     }
 }
 
@@ -199,6 +338,51 @@ impl ErrorCode {
             92 => "too many pixels, not supported\0",
             93 => "zero width or height is invalid\0",
             94 => "header chunk must have a size of 13 bytes\0",
+            95 => "interlaced images and custom zlib encoders can\'t be encoded row by row\0",
+            96 => "number of rows given to the encoder doesn\'t match the image height\0",
+            97 => "invalid acTL chunk, or acTL after image data\0",
+            98 => "invalid fcTL chunk, or frame outside of the image\0",
+            99 => "APNG chunk sequence number out of order\0",
+            100 => "APNG frame data without a frame, or frame without data\0",
+            101 => "number of APNG frames doesn\'t match acTL chunk\0",
+            102 => "animation has no frames\0",
+            103 => "invalid gAMA chunk size\0",
+            104 => "invalid cHRM chunk size\0",
+            105 => "invalid sRGB chunk size or rendering intent\0",
+            106 => "sRGB and iCCP chunks can\'t be used together\0",
+            107 => "invalid cICP chunk size, matrix coefficients or range flag\0",
+            108 => "invalid mDCv chunk size\0",
+            109 => "invalid cLLi chunk size\0",
+            110 => "eXIf data must start with II or MM byte order header\0",
+            111 => "EXIF IFD is truncated or points outside of the eXIf chunk\0",
+            112 => "invalid sPLT chunk: sample depth must be 8 or 16, and entries must fill the chunk\0",
+            113 => "hIST chunk length doesn\'t match the palette, or there\'s no palette\0",
+            114 => "invalid oFFs chunk size, unit or offset\0",
+            115 => "invalid sCAL chunk unit, or its sizes are not positive numbers\0",
+            116 => "invalid pCAL chunk: wrong equation type, number of parameters, or x0 equals x1\0",
+            117 => "invalid sTER chunk size or mode\0",
+            118 => "invalid sBIT chunk size for the color type, or bits out of range of the bit depth\0",
+            119 => "image width is larger than Limits::max_width\0",
+            120 => "image height is larger than Limits::max_height\0",
+            121 => "number of pixels is larger than Limits::max_pixels\0",
+            122 => "decoding would use more memory than Limits::max_memory\0",
+            123 => "chunk is larger than Limits::max_chunk_size\0",
+            124 => "decompressed text is larger than Limits::max_text_size\0",
+            125 => "there is data after the IEND chunk\0",
+            126 => "tIME chunk has an invalid date or time\0",
+            127 => "chunk must come before the PLTE or IDAT chunk\0",
+            128 => "chunk must not appear more than once\0",
+            129 => "text keyword has leading, trailing or consecutive spaces, or non-printable characters\0",
+            130 => "IDAT chunks must be consecutive\0",
+            131 => "palette image has no PLTE chunk\0",
+            132 => "PLTE chunk size is not a multiple of 3, or it has more entries than the bit depth allows\0",
+            133 => "PLTE chunk is not allowed in greyscale images\0",
+            134 => "critical chunks can\'t be removed or added, only ancillary ones\0",
+            135 => "chunk type must be 4 ASCII letters\0",
+            136 => "the deflate compressor failed\0",
+            137 => "animated PNGs can\'t be optimized\0",
+            138 => "the optimized image doesn\'t decode to the same pixels\0",
+            139 => "ancillary chunk has invalid data\0",
             _ => "unknown error code\0",
         }.as_bytes()
     }
@@ -207,7 +391,7 @@ impl ErrorCode {
 impl From<TryReserveError> for Error {
     #[cold]
     fn from(_: TryReserveError) -> Self {
-        Self(NonZeroU32::new(83).unwrap())
+        Self::new(83)
     }
 }
 
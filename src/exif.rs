@@ -0,0 +1,86 @@
+use crate::Error;
+
+/// The most useful tags from the first IFD of the `eXIf` chunk
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Exif {
+    /// 1 is upright, 3 is rotated 180°, 6 needs rotating 90° clockwise, 8 needs rotating 90° counter-clockwise.
+    /// 2, 4, 5 and 7 are the mirrored variants of these.
+    pub orientation: Option<u16>,
+    /// Modification date as `YYYY:MM:DD HH:MM:SS`
+    pub date_time: Option<String>,
+    /// Camera manufacturer
+    pub make: Option<String>,
+    /// Camera model
+    pub model: Option<String>,
+}
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+
+/*eXIf data must start with a TIFF header in either byte order*/
+pub(crate) fn has_valid_header(data: &[u8]) -> bool {
+    matches!(data.get(..4), Some(b"II*\0" | b"MM\0*"))
+}
+
+struct Reader<'data> {
+    data: &'data [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let b = self.data.get(pos..pos.checked_add(2)?)?;
+        let b = [b[0], b[1]];
+        Some(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let b = self.data.get(pos..pos.checked_add(4)?)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Some(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    /*values of up to 4 bytes are stored in the entry itself, longer ones at an offset*/
+    fn ascii(&self, value: usize, count: u32) -> Option<String> {
+        let count = count as usize;
+        let start = if count <= 4 { value } else { self.u32_at(value)? as usize };
+        let bytes = self.data.get(start..start.checked_add(count)?)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+impl Exif {
+    /// Parses raw `eXIf` chunk data (TIFF structure), as found in `Info::exif`.
+    ///
+    /// Only IFD0 is read. Tags with unexpected types are ignored.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if !has_valid_header(data) {
+            return Err(Error::new(110));
+        }
+        let r = Reader { data, big_endian: data[0] == b'M' };
+        let ifd = r.u32_at(4).ok_or(Error::new(111))? as usize;
+        let num_entries = r.u16_at(ifd).ok_or(Error::new(111))?;
+        let mut exif = Self::default();
+        for i in 0..num_entries as usize {
+            let entry = i.checked_mul(12).and_then(|e| e.checked_add(ifd)?.checked_add(2)).ok_or(Error::new(111))?;
+            let value = entry.checked_add(8).ok_or(Error::new(111))?;
+            let (Some(tag), Some(typ), Some(count)) = (r.u16_at(entry), r.u16_at(entry + 2), r.u32_at(entry + 4)) else {
+                return Err(Error::new(111));
+            };
+            match (tag, typ) {
+                (TAG_ORIENTATION, TYPE_SHORT) if count == 1 => exif.orientation = r.u16_at(value),
+                (TAG_DATE_TIME, TYPE_ASCII) => exif.date_time = r.ascii(value, count),
+                (TAG_MAKE, TYPE_ASCII) => exif.make = r.ascii(value, count),
+                (TAG_MODEL, TYPE_ASCII) => exif.model = r.ascii(value, count),
+                _ => {},
+            }
+        }
+        Ok(exif)
+    }
+}
@@ -3,6 +3,7 @@
 #![allow(non_upper_case_globals)]
 
 use crate::rustimpl::RGBA;
+use crate::Warning;
 use std::fmt;
 use std::io;
 use std::num::NonZeroU8;
@@ -204,7 +205,207 @@ pub struct Time {
     pub second: u8,
 }
 
+/// Chromaticities of the white point and the primaries (`cHRM` chunk), as CIE x and y times 100000
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Chromaticities {
+    pub white_x: u32,
+    pub white_y: u32,
+    pub red_x: u32,
+    pub red_y: u32,
+    pub green_x: u32,
+    pub green_y: u32,
+    pub blue_x: u32,
+    pub blue_y: u32,
+}
+
+/// Rendering intent of the `sRGB` chunk
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RenderingIntent {
+    Perceptual = 0,
+    RelativeColorimetric = 1,
+    Saturation = 2,
+    AbsoluteColorimetric = 3,
+}
+
+/// Embedded ICC profile (`iCCP` chunk)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IccProfile {
+    /// Name of the profile, 1-79 bytes of Latin-1
+    pub name: Box<[u8]>,
+    /// Uncompressed profile data
+    pub data: Vec<u8>,
+}
+
+/// Number of significant bits in each channel of the original image (`sBIT` chunk)
+///
+/// For greyscale images `red`, `green` and `blue` are all the same. In images without alpha channel `alpha` is 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SignificantBits {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+impl SignificantBits {
+    /// Values stored in the `sBIT` chunk for the given color type
+    pub(crate) fn channels(&self, colortype: ColorType) -> ([u8; 4], usize) {
+        let grey = self.red.max(self.green).max(self.blue);
+        match colortype {
+            ColorType::GREY => ([grey, 0, 0, 0], 1),
+            ColorType::GREY_ALPHA => ([grey, self.alpha, 0, 0], 2),
+            ColorType::RGBA => ([self.red, self.green, self.blue, self.alpha], 4),
+            _ => ([self.red, self.green, self.blue, 0], 3),
+        }
+    }
+}
+
+/// Unit of `ImageOffset`
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OffsetUnit {
+    Pixel = 0,
+    Micrometer = 1,
+}
+
+/// Position of the image on a page (`oFFs` chunk)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImageOffset {
+    /// must not be `i32::MIN`
+    pub x: i32,
+    /// must not be `i32::MIN`
+    pub y: i32,
+    pub unit: OffsetUnit,
+}
+
+/// Unit of `PhysicalScale`
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScaleUnit {
+    Meter = 1,
+    Radian = 2,
+}
+
+/// Physical size of a pixel (`sCAL` chunk)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PhysicalScale {
+    pub unit: ScaleUnit,
+    /// must be positive
+    pub pixel_width: f64,
+    /// must be positive
+    pub pixel_height: f64,
+}
+
+/// Formula of `PixelCalibration`, where `p` are the parameters, and `x` is `(x1 - x0)`-scaled pixel value
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CalibrationEquation {
+    /// `p0 + p1 * x`, 2 parameters
+    Linear = 0,
+    /// `p0 + p1 * e^(p2 * x)`, 3 parameters
+    Exponential = 1,
+    /// `p0 + p1 * p2^(x / p3)`, 4 parameters
+    ArbitraryBaseExponential = 2,
+    /// `p0 + p1 * sinh(p2 * (x - p3))`, 4 parameters
+    HyperbolicSine = 3,
+}
+
+impl CalibrationEquation {
+    /// Number of parameters the equation needs
+    #[must_use]
+    pub fn num_params(self) -> usize {
+        match self {
+            Self::Linear => 2,
+            Self::Exponential => 3,
+            Self::ArbitraryBaseExponential | Self::HyperbolicSine => 4,
+        }
+    }
+}
+
+/// Mapping of pixel values to physical quantities (`pCAL` chunk)
+#[derive(Clone, Debug, PartialEq)]
+pub struct PixelCalibration {
+    /// Name of the calibration, 1-79 bytes of Latin-1
+    pub name: Box<[u8]>,
+    /// Pixel value mapped to the start of the range. Must differ from `x1`.
+    pub x0: i32,
+    /// Pixel value mapped to the end of the range
+    pub x1: i32,
+    pub equation: CalibrationEquation,
+    /// Name of the unit, Latin-1, can be empty
+    pub unit: Box<[u8]>,
+    /// As many as `equation.num_params()`
+    pub params: Vec<f64>,
+}
+
+/// Layout of a stereo pair (`sTER` chunk)
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// Right-eye image on the left
+    CrossFuse = 0,
+    /// Left-eye image on the left
+    DivergingFuse = 1,
+}
+
+/// One color of a suggested palette
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SuggestedColor {
+    /// For 8-bit palettes the values are 0-255
+    pub color: rgb::RGBA16,
+    /// Relative usage of the color, 0 if unknown
+    pub frequency: u16,
+}
+
+/// Suggested palette (`sPLT` chunk)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SuggestedPalette {
+    /// Name of the palette, 1-79 bytes of Latin-1
+    pub name: Box<[u8]>,
+    /// 8 or 16
+    pub sample_depth: u8,
+    pub entries: Vec<SuggestedColor>,
+}
+
+/// Coding-independent code points (`cICP` chunk), as defined in ITU-T H.273
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cicp {
+    /// e.g. 1 is BT.709/sRGB, 9 is BT.2020, 12 is Display P3
+    pub color_primaries: u8,
+    /// e.g. 13 is sRGB, 16 is PQ, 18 is HLG
+    pub transfer_function: u8,
+    /// must be 0, because PNG is always RGB
+    pub matrix_coefficients: u8,
+    /// false if the image uses "narrow" (video) range
+    pub video_full_range: bool,
+}
+
+/// Mastering display color volume (`mDCv` chunk)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MasteringDisplay {
+    /// CIE x and y of red, green and blue primaries, in units of 0.00002
+    pub primaries: [(u16, u16); 3],
+    /// CIE x and y of the white point, in units of 0.00002
+    pub white_point: (u16, u16),
+    /// in units of 0.0001 cd/m²
+    pub max_luminance: u32,
+    /// in units of 0.0001 cd/m²
+    pub min_luminance: u32,
+}
+
+/// Content light level (`cLLi` chunk)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContentLightLevel {
+    /// maximum content light level (MaxCLL), in units of 0.0001 cd/m²
+    pub max_cll: u32,
+    /// maximum frame-average light level (MaxFALL), in units of 0.0001 cd/m²
+    pub max_fall: u32,
+}
+
 /// Information about the PNG image, except pixels, width and height
+///
+/// Typed ancillary chunks and warnings are in `InfoExtra`, and are used as fields of `Info`, e.g. `info.gamma`.
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct Info {
@@ -268,6 +469,49 @@ pub struct Info {
     ///  Similar to the non-international text chunks, but with additional strings
     ///  "langtags" and "transkeys".
     pub(crate) itexts: Vec<IntlText>,
+
+    /// typed ancillary chunks and warnings, through `Deref` to `InfoExtra`.
+    /// Boxed, because the C struct has only a pointer for them.
+    pub(crate) extra: Option<Box<InfoExtra>>,
+}
+
+/// Typed ancillary chunks and decoder warnings. They're fields of `Info` through `Deref`, but aren't in the C API.
+#[derive(Debug, Clone, Default)]
+pub struct InfoExtra {
+    /// gamma of the image (`gAMA` chunk), times 100000. For example 45455 is 1/2.2.
+    pub gamma: Option<u32>,
+    /// white point and primaries (`cHRM` chunk)
+    pub chromaticities: Option<Chromaticities>,
+    /// the image is sRGB (`sRGB` chunk). `gamma` and `chromaticities` are only a fallback then.
+    pub srgb_intent: Option<RenderingIntent>,
+    /// color profile (`iCCP` chunk). Can't be used together with `srgb_intent`.
+    /// Not set by a decoder with a custom zlib decoder, see `Decoder::get_icc` then.
+    pub icc_profile: Option<IccProfile>,
+    /// color space code points (`cICP` chunk). Takes precedence over all other color space chunks.
+    pub cicp: Option<Cicp>,
+    /// HDR mastering display (`mDCv` chunk)
+    pub mastering_display: Option<MasteringDisplay>,
+    /// HDR content light level (`cLLi` chunk)
+    pub content_light_level: Option<ContentLightLevel>,
+    /// raw EXIF metadata (`eXIf` chunk) in TIFF format, starting with `II` or `MM`. See `Info::exif_tags`.
+    pub exif: Option<Vec<u8>>,
+    /// suggested palettes (`sPLT` chunks)
+    pub suggested_palettes: Vec<SuggestedPalette>,
+    /// usage frequency of every `PLTE` entry (`hIST` chunk). Empty if there's none.
+    /// The encoder can make it with `EncoderSettings::add_histogram`.
+    pub histogram: Vec<u16>,
+    /// position on a page (`oFFs` chunk)
+    pub offset: Option<ImageOffset>,
+    /// physical size of pixels (`sCAL` chunk)
+    pub physical_scale: Option<PhysicalScale>,
+    /// meaning of pixel values (`pCAL` chunk)
+    pub calibration: Option<PixelCalibration>,
+    /// the image is a stereo pair side by side (`sTER` chunk)
+    pub stereo: Option<StereoLayout>,
+    /// precision of the original samples (`sBIT` chunk). See `DecoderSettings::use_significant_bits`.
+    pub significant_bits: Option<SignificantBits>,
+    /// problems the decoder worked around, in order they were found. Cleared when decoding starts.
+    pub warnings: Vec<Warning>,
 }
 
 #[derive(Debug, Clone)]
@@ -295,6 +539,31 @@ pub struct DecoderSettings {
     pub color_convert: bool,
     pub read_text_chunks: bool,
     pub remember_unknown_chunks: bool,
+    /// when converting to `info_raw`, rescale samples to full range based on their precision in the `sBIT` chunk
+    pub use_significant_bits: bool,
+    /// maximum image size and memory use
+    pub limits: Limits,
+    /// bit set of `WarningKind::bit()` of warnings that are errors instead
+    pub fatal_warnings: u32,
+}
+
+/// Limits for decoding untrusted files. By default there are no limits.
+///
+/// More limits may be added later, so start from `Limits::new()` and change fields or use the setters.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// width × height
+    pub max_pixels: u64,
+    /// bytes allocated for the decoded image and decompressed scanlines
+    pub max_memory: u64,
+    /// length of any single chunk
+    pub max_chunk_size: u32,
+    /// total size of decompressed `zTXt`, `iTXt` and `iCCP` chunks, and other texts
+    pub max_text_size: u64,
 }
 
 /// automatically use color type with less bits per pixel if losslessly possible. Default: `AUTO`
@@ -316,6 +585,44 @@ pub enum FilterStrategy {
     PREDEFINED,
 }
 
+/// Order of palette entries when `auto_convert` makes a palette. Default: `NONE`
+///
+/// All orders except `NONE` put transparent entries first, so that the `tRNS` chunk is shorter.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PaletteSort {
+    /// in the order the colors are found in the image, or the order of the input palette
+    NONE = 0,
+    /// only move transparent entries first
+    ALPHA,
+    /// from dark to bright
+    LUMINANCE,
+    /// the most used colors first
+    FREQUENCY,
+    /// colors that are often next to each other in the image are near each other in the palette,
+    /// starting from the most used color
+    NEIGHBORS,
+}
+
+/// New color of fully transparent pixels, set by the encoder before filtering. Default: `NONE`
+///
+/// Applies to 8 and 16-bit RGBA and grey+alpha images. The alpha stays 0.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlphaCleanup {
+    /// keep the color
+    NONE = 0,
+    /// black
+    BLACK,
+    /// color of the previous pixel in the row, which the `Sub` filter predicts. Black at the start of a row.
+    LEFT,
+    /// color of the pixel above, which the `Up` filter predicts. Black in the first row.
+    UP,
+    /// a single grey that no visible pixel has, so that `auto_convert` can use a color key (`tRNS`) instead
+    /// of an alpha channel. Black if every grey is used.
+    KEY,
+}
+
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub struct EncoderSettings {
@@ -345,6 +652,14 @@ pub struct EncoderSettings {
     pub add_id: bool,
     /// encode text chunks as zTXt chunks instead of tEXt chunks, and use compression in iTXt chunks
     pub text_compression: bool,
+    /// add `hIST` chunk with usage of palette colors, unless `Info::histogram` is already set
+    pub add_histogram: bool,
+    /// add `iDOT` chunk, and split image data in two halves that can be decompressed in parallel
+    pub add_idot: bool,
+    /// order of colors in a palette made by `auto_convert`
+    pub palette_sort: PaletteSort,
+    /// color of fully transparent pixels
+    pub alpha_cleanup: AlphaCleanup,
 }
 
 unsafe impl Send for EncoderSettings {}
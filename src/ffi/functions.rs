@@ -44,8 +44,9 @@ macro_rules! lode_try_state {
     ($state:expr, $e:expr) => {{
         match $e {
             Err(err) => {
-                $state = ErrorCode::from(err);
-                return ErrorCode::from(err);
+                let code = ErrorCode::from(err);
+                $state = code;
+                return code;
             },
             Ok(ok) => {
                 $state = ErrorCode(0);
@@ -33,6 +33,28 @@ mod error;
 pub use crate::error::*;
 mod iter;
 use crate::iter::{ChunksIterFragile, ITextKeysIter, TextKeysIter};
+mod stream;
+pub use crate::stream::{RowReader, RowWriter};
+mod partial;
+pub use crate::partial::{DecodeEvent, PartialDecoder};
+mod progressive;
+pub use crate::progressive::PreviewFill;
+mod apng;
+pub use crate::apng::{Animation, AnimationEncoder, BlendOp, Compositor, DisposeOp, Frame};
+mod exif;
+pub use crate::exif::Exif;
+mod metadata;
+mod warning;
+mod recover;
+pub use crate::recover::{MissingFill, Recovery};
+mod lint;
+pub use crate::lint::{validate, Issue, Report};
+mod rewrite;
+pub use crate::rewrite::ChunkRewriter;
+mod parallel;
+mod optimize;
+pub use crate::optimize::{optimize, KeepMetadata, OptimizeOptions};
+pub use crate::warning::{Warning, WarningKind};
 
 pub use rgb::bytemuck;
 pub use rgb::bytemuck::Pod;
@@ -43,6 +65,7 @@ use std::cmp;
 use std::convert::TryInto;
 use std::fmt;
 use std::fs;
+use std::io::{Read, Write};
 use std::mem;
 use std::num::NonZeroU8;
 use std::os::raw::c_uint;
@@ -65,15 +88,25 @@ pub use crate::ffi::EncoderSettings;
 pub use crate::ffi::ErrorCode;
 #[doc(inline)]
 pub use crate::ffi::FilterStrategy;
+#[doc(inline)]
+pub use crate::ffi::Limits;
+#[doc(inline)]
+pub use crate::ffi::PaletteSort;
+#[doc(inline)]
+pub use crate::ffi::AlphaCleanup;
 #[doc(hidden)]
 pub use crate::ffi::State;
 #[doc(inline)]
 pub use crate::ffi::Time;
+#[doc(inline)]
+pub use crate::ffi::{Chromaticities, Cicp, ContentLightLevel, IccProfile, MasteringDisplay, RenderingIntent, SuggestedColor, SuggestedPalette};
+#[doc(inline)]
+pub use crate::ffi::{CalibrationEquation, ImageOffset, OffsetUnit, PhysicalScale, PixelCalibration, ScaleUnit, SignificantBits, StereoLayout};
 
 #[doc(inline)]
 pub use crate::ffi::ColorMode;
 #[doc(inline)]
-pub use crate::ffi::Info;
+pub use crate::ffi::{Info, InfoExtra};
 
 pub use iter::ChunksIter;
 
@@ -172,10 +205,6 @@ impl ColorMode {
         self.colortype.bpp_(self.bitdepth)
     }
 
-    pub(crate) fn clear_key(&mut self) {
-        self.key_defined = 0;
-    }
-
     /// `tRNS` chunk
     #[cold]
     pub fn set_key(&mut self, r: u16, g: u16, b: u16) {
@@ -322,6 +351,34 @@ impl Default for Info {
     }
 }
 
+/*what Info derefs to when nothing has been set yet, so that reading fields doesn't allocate*/
+static NO_EXTRA: InfoExtra = InfoExtra {
+    gamma: None, chromaticities: None, srgb_intent: None, icc_profile: None,
+    cicp: None, mastering_display: None, content_light_level: None,
+    exif: None,
+    suggested_palettes: Vec::new(),
+    histogram: Vec::new(),
+    offset: None, physical_scale: None, calibration: None, stereo: None,
+    significant_bits: None,
+    warnings: Vec::new(),
+};
+
+impl std::ops::Deref for Info {
+    type Target = InfoExtra;
+
+    #[inline]
+    fn deref(&self) -> &InfoExtra {
+        self.extra.as_deref().unwrap_or(&NO_EXTRA)
+    }
+}
+
+impl std::ops::DerefMut for Info {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut InfoExtra {
+        self.extra.get_or_insert_with(Box::default)
+    }
+}
+
 impl Info {
     #[inline]
     #[must_use]
@@ -336,6 +393,7 @@ impl Info {
             texts: Vec::new(),
             itexts: Vec::new(),
             phys_defined: false, phys_x: 0, phys_y: 0, phys_unit: 0,
+            extra: None,
         }
     }
 
@@ -427,6 +485,45 @@ impl Info {
     pub fn try_unknown_chunks(&self, position: ChunkPosition) -> ChunksIter<'_> {
         ChunksIter::new(&self.unknown_chunks[position as usize])
     }
+
+    /// Orientation, date and camera tags from the `eXIf` chunk, if there is one
+    pub fn exif_tags(&self) -> Result<Option<Exif>, Error> {
+        self.exif.as_deref().map(Exif::parse).transpose()
+    }
+
+    /// Transfer function and primaries that apply to the image.
+    ///
+    /// Color chunks override each other in order of precedence: `cICP`, `iCCP`, `sRGB`, and finally `gAMA` and `cHRM`.
+    #[must_use]
+    pub fn color_space(&self) -> ColorSpace {
+        if let Some(cicp) = &self.cicp {
+            return ColorSpace {
+                transfer: match cicp.transfer_function {
+                    8 => TransferFunction::Linear,
+                    13 => TransferFunction::Srgb,
+                    16 => TransferFunction::Pq,
+                    18 => TransferFunction::Hlg,
+                    other => TransferFunction::Cicp(other),
+                },
+                primaries: match cicp.color_primaries {
+                    1 => Primaries::Bt709,
+                    9 => Primaries::Bt2020,
+                    12 => Primaries::DisplayP3,
+                    other => Primaries::Cicp(other),
+                },
+            };
+        }
+        if self.icc_profile.is_some() {
+            return ColorSpace { transfer: TransferFunction::IccProfile, primaries: Primaries::IccProfile };
+        }
+        if self.srgb_intent.is_some() {
+            return ColorSpace { transfer: TransferFunction::Srgb, primaries: Primaries::Bt709 };
+        }
+        ColorSpace {
+            transfer: self.gamma.map_or(TransferFunction::Unknown, TransferFunction::Gamma),
+            primaries: self.chromaticities.map_or(Primaries::Unknown, Primaries::Chromaticities),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -481,6 +578,35 @@ impl Encoder {
         self.state.encoder.text_compression = compr;
     }
 
+    /// Write `hIST` chunk with usage frequency of palette colors
+    #[inline(always)]
+    pub fn set_histogram(&mut self, add: bool) {
+        self.state.encoder.add_histogram = add;
+    }
+
+    /// Write an `iDOT` chunk, and image data split in two halves that decoders can decompress in parallel.
+    ///
+    /// That's how Apple's encoder writes PNG files. The `parallel` feature makes the halves compressed
+    /// and decompressed on two threads. It doesn't apply to interlaced images. Makes files slightly larger.
+    #[inline(always)]
+    pub fn set_idot(&mut self, add: bool) {
+        self.state.encoder.add_idot = add;
+    }
+
+    /// Order of colors in the palette when `auto_convert` chooses a palette image.
+    /// Sorted palettes often compress better. Input palettes are reordered too.
+    #[inline(always)]
+    pub fn set_palette_sort(&mut self, sort: PaletteSort) {
+        self.state.encoder.palette_sort = sort;
+    }
+
+    /// Replace the color of fully transparent pixels, which is invisible, with one that compresses better.
+    /// Changes the RGB values of the image, so they won't decode the same.
+    #[inline(always)]
+    pub fn set_alpha_cleanup(&mut self, cleanup: AlphaCleanup) {
+        self.state.encoder.alpha_cleanup = cleanup;
+    }
+
     /// Compress using another zlib implementation. It's gzip header + deflate + adler32 checksum.
     #[inline(always)]
     #[allow(deprecated)]
@@ -547,6 +673,60 @@ impl Encoder {
         self.state.encode_file(filepath, image, w, h)
     }
 
+    /// Encode rows one by one while writing the file, using Encoder's settings
+    ///
+    /// Only the previous row and the zlib window are kept in memory, and image data is written in `IDAT` chunks as soon as it's compressed.
+    /// Since the image isn't known up front, `auto_convert` is ignored and the PNG has the color mode of `info_png().color`.
+    /// Interlacing isn't supported.
+    ///
+    ///  ```no_run
+    ///  # use lodepng::*; let mut encoder = Encoder::new();
+    ///  # fn render_row(_y: usize) -> Vec<RGBA> { vec![] }
+    ///  encoder.info_raw_mut().colortype = ColorType::RGBA;
+    ///  encoder.info_png_mut().color.colortype = ColorType::RGB;
+    ///  let mut rows = encoder.stream(std::fs::File::create("huge.png")?, 20000, 20000)?;
+    ///  for y in 0..20000 {
+    ///      rows.write_rows(&render_row(y))?;
+    ///  }
+    ///  rows.finish()?;
+    ///  # Ok::<_, Box<dyn std::error::Error>>(())
+    ///  ```
+    pub fn stream<W: Write>(&self, writer: W, w: usize, h: usize) -> Result<RowWriter<'_, W>, Error> {
+        let w = w.try_into().map_err(|_| Error::new(93))?;
+        let h = h.try_into().map_err(|_| Error::new(93))?;
+        if let Some(filters) = &self.predefined_filters {
+            if filters.len() < h as usize {
+                return Err(Error::new(88));
+            }
+        }
+        RowWriter::new(writer, w, h, &self.state, &self.state.encoder)
+    }
+
+    /// Make an animated PNG (APNG) of the given canvas size, using Encoder's settings
+    ///
+    /// Frames are RGBA and `info_raw` is ignored. With `auto_convert` one color mode is chosen for all frames.
+    ///
+    ///  ```no_run
+    ///  # use lodepng::*; let encoder = Encoder::new();
+    ///  # let frames: Vec<Vec<RGBA>> = vec![];
+    ///  let mut anim = encoder.animation(100, 100)?;
+    ///  for frame in &frames {
+    ///      anim.add_frame(frame, 1, 25)?;
+    ///  }
+    ///  std::fs::write("anim.png", anim.encode()?)?;
+    ///  # Ok::<_, Box<dyn std::error::Error>>(())
+    ///  ```
+    pub fn animation(&self, w: usize, h: usize) -> Result<AnimationEncoder<'_>, Error> {
+        let w = w.try_into().map_err(|_| Error::new(93))?;
+        let h = h.try_into().map_err(|_| Error::new(93))?;
+        if let Some(filters) = &self.predefined_filters {
+            if filters.len() < h as usize {
+                return Err(Error::new(88));
+            }
+        }
+        AnimationEncoder::new(&self.state, w, h)
+    }
+
     #[inline(always)]
     pub fn settings_mut(&mut self) -> &mut EncoderSettings {
         &mut self.state.encoder
@@ -609,7 +789,26 @@ impl Decoder {
         self.state.remember_unknown_chunks(true_or_false);
     }
 
-    /// Decompress ICC profile from `iCCP` chunk. Only available if `remember_unknown_chunks` was set.
+    /// rescale samples using the precision from the `sBIT` chunk when converting colors (off by default)
+    #[inline(always)]
+    pub fn use_significant_bits(&mut self, true_or_false: bool) {
+        self.state.use_significant_bits(true_or_false);
+    }
+
+    /// Refuse to decode files that are too large. Each limit has its own error code.
+    #[inline(always)]
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.state.decoder.limits = limits;
+    }
+
+    /// Make these kinds of warnings errors. By default all are only collected in `info_png().warnings`.
+    ///
+    /// Use `&WarningKind::ALL` to be strict.
+    pub fn set_fatal_warnings(&mut self, kinds: &[WarningKind]) {
+        self.state.decoder.fatal_warnings = kinds.iter().fold(0, |bits, k| bits | k.bit());
+    }
+
+    /// Decompressed ICC profile from `iCCP` chunk. Same as `info_png().icc_profile`, or decompressed from the remembered chunk with a custom zlib decoder.
     #[inline(always)]
     pub fn get_icc(&self) -> Result<Vec<u8>, Error> {
         self.state.get_icc()
@@ -633,6 +832,51 @@ impl Decoder {
         self.state.decode(input)
     }
 
+    /// Load PNG from buffer like `decode`, and also return problems that the decoder worked around
+    ///
+    /// The warnings are also in `info_png().warnings`. See `set_fatal_warnings` to make them errors.
+    pub fn decode_with_warnings<Bytes: AsRef<[u8]>>(&mut self, input: Bytes) -> Result<(Image, Vec<Warning>), Error> {
+        let image = self.decode(input)?;
+        Ok((image, self.state.info_png.warnings.clone()))
+    }
+
+    /// Load PNG from buffer like `decode`, and show interlaced images while they're being decoded
+    ///
+    /// For Adam7-interlaced images `callback(pass, preview)` is called after each of the 7 passes.
+    /// The preview is the whole image in `info_raw()` color mode, with pixels of the later passes filled in from the decoded ones.
+    /// After pass 7 the preview is the final image. The callback isn't called for non-interlaced images.
+    ///
+    ///  ```no_run
+    ///  # use lodepng::*; let mut decoder = Decoder::new();
+    ///  # let slice = [0u8]; #[allow(unused_variables)] fn show(_buf: &[u8]) {}
+    ///  decoder.info_raw_mut().colortype = ColorType::RGBA;
+    ///  let image = decoder.decode_progressive(&slice, PreviewFill::Bilinear, |_pass, preview| show(preview))?;
+    ///  # Ok::<_, Error>(())
+    ///  ```
+    pub fn decode_progressive<Bytes: AsRef<[u8]>>(&mut self, input: Bytes, fill: PreviewFill, mut callback: impl FnMut(usize, &[u8])) -> Result<Image, Error> {
+        let (data, w, h) = progressive::decode_progressive(&mut self.state, input.as_ref(), fill, &mut callback)?;
+        new_bitmap(data, w, h, self.state.info_raw.colortype, self.state.info_raw.bitdepth)
+    }
+
+    /// Decode as much as possible of a truncated or corrupt file
+    ///
+    /// Rows after the damage are filled as specified by `fill`. For Adam7-interlaced images, the passes decoded completely are used,
+    /// and missing pixels are copied from the nearest decoded ones. The image data is always decompressed with the built-in zlib decoder.
+    ///
+    /// Fails only if the header is broken, or if a critical chunk before the image data is, e.g. the palette.
+    pub fn decode_recovering<Bytes: AsRef<[u8]>>(&mut self, input: Bytes, fill: MissingFill) -> Result<(Image, Recovery), Error> {
+        let (data, w, h, recovery) = recover::decode_recovering(&mut self.state, input.as_ref(), fill)?;
+        Ok((new_bitmap(data, w, h, self.state.info_raw.colortype, self.state.info_raw.bitdepth)?, recovery))
+    }
+
+    /// Load all frames of an animated PNG (APNG) using Decoder's settings
+    ///
+    /// Frames are in the `info_raw()` color mode, and aren't composited. Regular PNG files are returned as a single frame.
+    /// Frame sequence numbers are checked, and frames outside of the canvas are an error.
+    pub fn decode_animation<Bytes: AsRef<[u8]>>(&mut self, input: Bytes) -> Result<Animation, Error> {
+        apng::decode_animation(&mut self.state, input.as_ref())
+    }
+
     /// Decode a file from disk using Decoder's settings
     #[allow(deprecated)]
     #[inline(always)]
@@ -640,6 +884,28 @@ impl Decoder {
         self.state.decode_file(filepath)
     }
 
+    /// Reads all chunks into `info_png`, without decompressing the image. Returns (width, height)
+    ///
+    /// Unlike `inspect` it reads the whole file, including metadata after the image data.
+    /// Image data is read only to skip it, and isn't checked. Use `read_metadata_seek` to avoid reading it.
+    pub fn read_metadata<R: Read>(&mut self, mut reader: R) -> Result<(usize, usize), Error> {
+        let (w, h) = metadata::read_metadata(&mut self.state, &mut reader, metadata::skip_by_reading)?;
+        Ok((w as usize, h as usize))
+    }
+
+    /// Same as `read_metadata`, but seeks past image data instead of reading it
+    ///
+    ///  ```no_run
+    ///  # use lodepng::*; let mut decoder = Decoder::new();
+    ///  let (width, height) = decoder.read_metadata_seek(std::fs::File::open("photo.png")?)?;
+    ///  let exif = decoder.info_png().exif_tags()?;
+    ///  # Ok::<_, Box<dyn std::error::Error>>(())
+    ///  ```
+    pub fn read_metadata_seek<R: Read + std::io::Seek>(&mut self, mut reader: R) -> Result<(usize, usize), Error> {
+        let (w, h) = metadata::read_metadata(&mut self.state, &mut reader, metadata::skip_by_seeking)?;
+        Ok((w as usize, h as usize))
+    }
+
     /// Updates `info_png`. Returns (width, height)
     #[allow(deprecated)]
     #[inline(always)]
@@ -647,6 +913,32 @@ impl Decoder {
         self.state.inspect(input)
     }
 
+    /// Decode rows one by one while reading the file, using Decoder's settings
+    ///
+    /// Reads the header and metadata before the image data immediately.
+    /// Non-interlaced images keep only two scanlines and the zlib window in memory.
+    /// Interlaced images have to be decoded whole before the first row can be returned.
+    ///
+    ///  ```no_run
+    ///  # use lodepng::*; let mut decoder = Decoder::new();
+    ///  # #[allow(unused_variables)] fn do_stuff<T>(_buf: T) {}
+    ///  decoder.info_raw_mut().colortype = ColorType::RGBA;
+    ///  let mut rows = decoder.stream(std::fs::File::open("huge.png")?)?;
+    ///  while let Some(row) = rows.next_row()? {
+    ///      do_stuff(row);
+    ///  }
+    ///  # Ok::<_, Box<dyn std::error::Error>>(())
+    ///  ```
+    pub fn stream<R: Read>(&self, reader: R) -> Result<RowReader<R>, Error> {
+        RowReader::new(reader, self.state.decoder.clone(), self.state.info_raw.clone())
+    }
+
+    /// Decode a file that arrives in pieces, using Decoder's settings. See `PartialDecoder::feed`.
+    #[must_use]
+    pub fn partial(&self) -> PartialDecoder {
+        PartialDecoder::new(self.state.decoder.clone(), self.state.info_raw.clone())
+    }
+
     /// use custom zlib decoder instead of built in one
     #[inline(always)]
     pub fn set_custom_zlib(&mut self, callback: ffi::custom_decompress_callback, context: *const c_void) {
@@ -738,8 +1030,20 @@ impl State {
         self.decoder.remember_unknown_chunks = true_or_false;
     }
 
-    /// Decompress ICC profile from `iCCP` chunk. Only available if `remember_unknown_chunks` was set.
+    /// rescale samples using the precision from the `sBIT` chunk when converting colors (off by default)
+    #[inline(always)]
+    pub fn use_significant_bits(&mut self, true_or_false: bool) {
+        self.decoder.use_significant_bits = true_or_false;
+    }
+
+    /// Decompressed ICC profile from `iCCP` chunk. Same as `info_png().icc_profile`, or decompressed from the remembered chunk with a custom zlib decoder.
     pub fn get_icc(&self) -> Result<Vec<u8>, Error> {
+        if let Some(icc) = &self.info_png().icc_profile {
+            let mut data = Vec::new();
+            data.try_reserve_exact(icc.data.len())?;
+            data.extend_from_slice(&icc.data);
+            return Ok(data);
+        }
         let iccp = self.info_png().get("iCCP");
         if iccp.is_none() {
             return Err(Error::new(89));
@@ -953,6 +1257,53 @@ pub enum ChunkPosition {
     IDAT = 2,
 }
 
+/// Effective color space of the image, see `Info::color_space`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ColorSpace {
+    pub transfer: TransferFunction,
+    pub primaries: Primaries,
+}
+
+/// How pixel values map to light intensity
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransferFunction {
+    /// The sRGB curve
+    Srgb,
+    /// Linear light
+    Linear,
+    /// SMPTE ST 2084 (Perceptual Quantizer)
+    Pq,
+    /// ARIB STD-B67 (Hybrid Log-Gamma)
+    Hlg,
+    /// Gamma from the `gAMA` chunk, times 100000 (e.g. 45455 for 1/2.2)
+    Gamma(u32),
+    /// Other transfer characteristics code from ITU-T H.273
+    Cicp(u8),
+    /// Defined by the embedded ICC profile
+    IccProfile,
+    /// No color space information in the file
+    Unknown,
+}
+
+/// Color primaries and white point
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Primaries {
+    /// ITU-R BT.709, same as sRGB
+    Bt709,
+    /// ITU-R BT.2020
+    Bt2020,
+    /// DCI-P3 primaries with D65 white point
+    DisplayP3,
+    /// From the `cHRM` chunk
+    Chromaticities(Chromaticities),
+    /// Other color primaries code from ITU-T H.273
+    Cicp(u8),
+    /// Defined by the embedded ICC profile
+    IccProfile,
+    /// No color space information in the file
+    Unknown,
+}
+
 /// Low-level representation of an image
 ///
 /// Takes any pixel type, but for safety the type has to be marked as "plain old data"
@@ -1318,6 +1669,14 @@ impl<'a> ChunkRef<'a> {
         crc == checksum
     }
 
+    /*error 57 with the computed and the stored CRC*/
+    #[cold]
+    pub(crate) fn crc_error(&self) -> Error {
+        let length = self.len();
+        let stored = u32::from_be_bytes(self.data[length + 8.. length + 8 + 4].try_into().unwrap());
+        Error::new(57).with_mismatch(self.crc().into(), stored.into())
+    }
+
     #[cfg(fuzzing)]
     /// Disable crc32 checks so that random data from fuzzer gets actually parsed
     #[inline(always)]
@@ -1407,6 +1766,64 @@ impl DecoderSettings {
     }
 }
 
+impl Limits {
+    /// No limits, same as `default()`
+    #[inline(always)]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn set_max_width(&mut self, max: u32) {
+        self.max_width = max;
+    }
+
+    #[inline]
+    pub fn set_max_height(&mut self, max: u32) {
+        self.max_height = max;
+    }
+
+    /// width × height
+    #[inline]
+    pub fn set_max_pixels(&mut self, max: u64) {
+        self.max_pixels = max;
+    }
+
+    /// Bytes allocated for the decoded image and decompressed scanlines
+    #[inline]
+    pub fn set_max_memory(&mut self, max: u64) {
+        self.max_memory = max;
+    }
+
+    /// Length of any single chunk
+    #[inline]
+    pub fn set_max_chunk_size(&mut self, max: u32) {
+        self.max_chunk_size = max;
+    }
+
+    /// Total size of decompressed `zTXt`, `iTXt` and `iCCP` chunks, and other texts
+    #[inline]
+    pub fn set_max_text_size(&mut self, max: u64) {
+        self.max_text_size = max;
+    }
+}
+
+impl Default for Limits {
+    /// No limits
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_width: u32::MAX,
+            max_height: u32::MAX,
+            max_pixels: u64::MAX,
+            max_memory: u64::MAX,
+            max_chunk_size: u32::MAX,
+            max_text_size: u64::MAX,
+        }
+    }
+}
+
 impl Default for DecoderSettings {
     #[inline]
     fn default() -> Self {
@@ -1414,6 +1831,9 @@ impl Default for DecoderSettings {
             color_convert: true,
             read_text_chunks: true,
             remember_unknown_chunks: false,
+            use_significant_bits: false,
+            limits: Limits::default(),
+            fatal_warnings: 0,
             ignore_crc: false,
             zlibsettings: DecompressSettings::new(),
         }
@@ -1445,6 +1865,10 @@ impl Default for EncoderSettings {
             predefined_filters: ptr::null_mut(),
             add_id: false,
             text_compression: true,
+            add_histogram: false,
+            add_idot: false,
+            palette_sort: PaletteSort::NONE,
+            alpha_cleanup: AlphaCleanup::NONE,
         }
     }
 }
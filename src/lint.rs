@@ -0,0 +1,235 @@
+use crate::rustimpl::{adam7_pass_values, linebytes_rounded, lodepng_inspect, read_chunk_checked};
+use crate::zlib::{adler32_update, check_zlib_stream};
+use crate::{ChunkPosition, ChunkRef, ColorType, DecoderSettings, Error, Info};
+use flate2::{Decompress, FlushDecompress, Status};
+
+/// Problem found by `validate`
+#[derive(Debug, Clone)]
+pub struct Issue {
+    /// Description, code, chunk type and offset of the problem
+    pub error: Error,
+    /// `Decoder` can't decode the file because of it.
+    /// Other issues are violations of the spec that the decoder works around.
+    pub fatal: bool,
+}
+
+/// Result of `validate`
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Problems in the order they were found
+    pub issues: Vec<Issue>,
+    /// Types of all chunks in the file, in order
+    pub chunks: Vec<[u8; 4]>,
+}
+
+impl Report {
+    /// No problems have been found
+    #[inline]
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// None of the problems stop `Decoder` from decoding the file
+    #[must_use]
+    pub fn is_decodable(&self) -> bool {
+        !self.issues.iter().any(|i| i.fatal)
+    }
+
+    fn add(&mut self, error: Error, fatal: bool) {
+        self.issues.push(Issue { error, fatal });
+    }
+}
+
+/// Chunks that may appear only once
+const UNIQUE: [&[u8; 4]; 22] = [
+    b"IHDR", b"PLTE", b"IEND", b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"sBIT", b"bKGD", b"hIST",
+    b"pHYs", b"tIME", b"cICP", b"mDCv", b"cLLi", b"eXIf", b"oFFs", b"pCAL", b"sCAL", b"sTER", b"acTL",
+];
+
+/// Checks conformance of a PNG file to the spec, without decoding the pixels
+///
+/// Unlike the decoder, it doesn't stop at the first problem. It checks CRCs, chunk order and sizes, duplicate chunks,
+/// text keywords, `tIME` ranges, the zlib stream with its Adler-32, filter types of scanlines, and data after `IEND`.
+#[must_use]
+pub fn validate(png: &[u8]) -> Report {
+    let mut report = Report::default();
+    let mut decoder = DecoderSettings::new();
+    /*CRCs are checked here for every chunk, not only until the first unknown one*/
+    decoder.ignore_crc = true;
+    let (mut info, w, h) = match lodepng_inspect(&decoder, png, false) {
+        Ok(header) => header,
+        Err(e) => {
+            report.add(e, true);
+            return report;
+        },
+    };
+    report.chunks.push(*b"IHDR");
+
+    let mut critical_pos = ChunkPosition::IHDR;
+    let mut unknown = false;
+    let mut idat = Vec::new();
+    let mut first_idat = None;
+    let mut idat_ended = false;
+    let mut found_iend = false;
+    let mut pos = 33;
+    while let Some(header) = png.get(pos..pos + 8) {
+        let name: [u8; 4] = header[4..8].try_into().unwrap();
+        let ch = match ChunkRef::new(&png[pos..]) {
+            Ok(ch) => ch,
+            Err(e) => {
+                report.add(e.in_chunk(name, pos), true);
+                break;
+            },
+        };
+        let at = |e: Error| e.in_chunk(name, pos);
+        if UNIQUE.contains(&&name) && report.chunks.contains(&name) {
+            report.add(at(Error::new(128)), false);
+        }
+        report.chunks.push(name);
+        let crc_ok = ch.check_crc();
+        if !crc_ok {
+            report.add(at(ch.crc_error()), !(ch.is_ancillary() || &name == b"IEND"));
+        }
+        if &name == b"IDAT" {
+            if idat_ended {
+                report.add(at(Error::new(130)), false);
+            }
+            first_idat.get_or_insert(pos);
+            idat.extend_from_slice(ch.data());
+            critical_pos = ChunkPosition::IDAT;
+        } else {
+            idat_ended |= critical_pos == ChunkPosition::IDAT;
+            match &name {
+                b"PLTE" => check_plte(&mut report, &info, critical_pos, ch.data(), at),
+                b"tEXt" | b"zTXt" | b"iTXt" => {
+                    let keyword = ch.data().split(|&c| c == 0).next().unwrap_or_default();
+                    /*other lengths are an error of the decoder*/
+                    if (1..=79).contains(&keyword.len()) && !keyword_is_valid(keyword) {
+                        report.add(at(Error::new(129)), false);
+                    }
+                },
+                _ => {},
+            }
+            /*like the decoder, skips an ancillary chunk with a bad CRC*/
+            if crc_ok || !ch.is_ancillary() {
+                if let Err(e) = read_chunk_checked(&mut info, &decoder, &mut critical_pos, &mut unknown, ch, pos) {
+                    report.add(e, true);
+                }
+            }
+            for warning in info.warnings.drain(..) {
+                report.add(warning.into(), false);
+            }
+        }
+        pos += ch.len() + 12;
+        if &name == b"IEND" {
+            found_iend = true;
+            break;
+        }
+    }
+    if !found_iend {
+        report.add(Error::new(52).at_offset(pos.min(png.len())), false);
+    } else if pos < png.len() {
+        report.add(Error::new(125).at_offset(pos), false);
+    }
+    if info.color.colortype == ColorType::PALETTE && info.color.palette().is_empty() {
+        report.add(Error::new(131), true);
+    }
+    match first_idat {
+        Some(offset) => check_image_data(&mut report, &idat, &info, w, h, offset),
+        /*no image data*/
+        None => report.add(Error::new(91), true),
+    }
+    report
+}
+
+fn check_plte(report: &mut Report, info: &Info, critical_pos: ChunkPosition, data: &[u8], at: impl Fn(Error) -> Error) {
+    let color = &info.color;
+    if critical_pos == ChunkPosition::IDAT {
+        report.add(at(Error::new(127)), false);
+    }
+    if matches!(color.colortype, ColorType::GREY | ColorType::GREY_ALPHA) {
+        report.add(at(Error::new(133)), false);
+    }
+    let max_entries = if color.colortype == ColorType::PALETTE { 1 << color.bitdepth() } else { 256 };
+    if data.is_empty() || data.len() % 3 != 0 || data.len() / 3 > max_entries {
+        report.add(at(Error::new(132)), false);
+    }
+}
+
+/*keywords are 1-79 printable Latin-1 characters, with single spaces only between words*/
+fn keyword_is_valid(keyword: &[u8]) -> bool {
+    keyword.first() != Some(&b' ') && keyword.last() != Some(&b' ') &&
+        !keyword.windows(2).any(|w| w == b"  ") &&
+        keyword.iter().all(|&c| (32..=126).contains(&c) || c >= 161)
+}
+
+/*decompresses the image data in pieces to check the filter type of every scanline, the size, and the Adler-32*/
+fn check_image_data(report: &mut Report, idat: &[u8], info: &Info, w: u32, h: u32, first_idat: usize) {
+    let at = |e: Error| e.in_chunk(*b"IDAT", first_idat);
+    if let Err(e) = check_zlib_stream(idat) {
+        report.add(at(e), true);
+        return;
+    }
+    let bpp = info.color.bpp_();
+    /*(length of scanlines with the filter byte, number of scanlines) of the image or each Adam7 pass*/
+    let passes: Vec<(usize, usize)> = if info.interlace_method == 0 {
+        vec![(1 + linebytes_rounded(w, bpp), h as usize)]
+    } else {
+        adam7_pass_values(w, h, bpp).filter(|p| p.filtered_len > 0).map(|p| (1 + linebytes_rounded(p.w, bpp), p.h as usize)).collect()
+    };
+    let expected: usize = passes.iter().map(|&(len, n)| len * n).sum();
+    let mut filter_bytes = passes.iter().scan(0, |start, &(len, n)| {
+        let pass_start = *start;
+        *start += len * n;
+        Some((0..n).map(move |row| pass_start + row * len))
+    }).flatten().peekable();
+
+    let deflate = &idat[2..];
+    let mut dec = Decompress::new(false);
+    let mut buf = vec![0; 1 << 16];
+    let mut adler = (1, 0);
+    let mut bad_filter = false;
+    let stream_end = loop {
+        let (read, written) = (dec.total_in() as usize, dec.total_out() as usize);
+        let res = dec.decompress(&deflate[read..], &mut buf, FlushDecompress::None);
+        let end = dec.total_out() as usize;
+        let produced = &buf[..end - written];
+        adler = adler32_update(adler, produced);
+        while let Some(&filter_pos) = filter_bytes.peek() {
+            if filter_pos >= end {
+                break;
+            }
+            if produced[filter_pos - written] > 4 && !bad_filter {
+                report.add(at(Error::new(36)), true);
+                bad_filter = true;
+            }
+            filter_bytes.next();
+        }
+        match res {
+            Ok(Status::StreamEnd) => break true,
+            Ok(_) if dec.total_in() as usize == read && end == written => break false,
+            Ok(_) => {},
+            Err(_) => break false,
+        }
+    };
+    if !stream_end {
+        report.add(at(Error::new(23)), true);
+        return;
+    }
+    let total = dec.total_out() as usize;
+    if total != expected {
+        report.add(at(Error::new(91).with_mismatch(expected as u64, total as u64)), true);
+    }
+    let adler_pos = dec.total_in() as usize;
+    match deflate.get(adler_pos..adler_pos + 4) {
+        Some(stored) => {
+            let stored = u32::from_be_bytes(stored.try_into().unwrap());
+            let computed = (adler.1 << 16) | adler.0;
+            if stored != computed {
+                report.add(at(Error::new(58).with_mismatch(computed.into(), stored.into())), true);
+            }
+        },
+        None => report.add(at(Error::new(53)), true),
+    }
+}
@@ -0,0 +1,74 @@
+use crate::ffi::State;
+use crate::rustimpl::{check_chunk_length, lodepng_inspect, read_chunk_checked};
+use crate::stream::{read_exact, read_fully};
+use crate::warning;
+use crate::{ChunkPosition, ChunkRef, Error, WarningKind};
+use std::io::{self, Read, Seek, SeekFrom};
+
+/*reads all chunks into state.info_png, passing over image data with the skip function*/
+pub(crate) fn read_metadata<R: Read>(state: &mut State, reader: &mut R, skip: fn(&mut R, u64) -> Result<(), Error>) -> Result<(u32, u32), Error> {
+    let mut header = [0; 33];
+    if read_fully(reader, &mut header)? != header.len() {
+        /*error: the data length is smaller than the length of a PNG header*/
+        return Err(Error::new(27));
+    }
+    let (info, w, h) = lodepng_inspect(&state.decoder, &header, false)?;
+    state.info_png = info;
+
+    let mut critical_pos = ChunkPosition::IHDR;
+    /*CRCs aren't checked after an unknown chunk, same as decode*/
+    let mut unknown = false;
+    let mut chunk = Vec::new();
+    /*file offset of the next chunk, for errors and warnings*/
+    let mut offset = 33;
+    loop {
+        let mut chunk_header = [0; 8];
+        match read_fully(reader, &mut chunk_header)? {
+            8 => {},
+            0 => {
+                warning::warn(&mut state.info_png, &state.decoder, WarningKind::MissingIend, None, offset)?;
+                break;
+            },
+            _ => return Err(Error::new(30)),
+        }
+        let len = crate::chunk_length(&chunk_header);
+        let chunk_offset = offset;
+        check_chunk_length(&state.decoder, len).map_err(|e| e.in_chunk(chunk_header[4..8].try_into().unwrap(), chunk_offset))?;
+        offset += len + 12;
+        let name = &chunk_header[4..8];
+        if name == b"IDAT" || name == b"fdAT" {
+            if name == b"IDAT" {
+                critical_pos = ChunkPosition::IDAT;
+            }
+            /*image data and its CRC aren't read*/
+            skip(reader, len as u64 + 4)?;
+            continue;
+        }
+        chunk.clear();
+        chunk.try_reserve(len + 12)?;
+        chunk.extend_from_slice(&chunk_header);
+        chunk.resize(len + 12, 0);
+        read_exact(reader, &mut chunk[8..])?;
+
+        let ch = ChunkRef::new(&chunk)?;
+        read_chunk_checked(&mut state.info_png, &state.decoder, &mut critical_pos, &mut unknown, ch, chunk_offset)?;
+        if ch.is_type(b"IEND") {
+            break;
+        }
+    }
+    Ok((w, h))
+}
+
+pub(crate) fn skip_by_reading<R: Read>(reader: &mut R, len: u64) -> Result<(), Error> {
+    if io::copy(&mut reader.take(len), &mut io::sink())? != len {
+        /*chunk broken off at end of file*/
+        return Err(Error::new(30));
+    }
+    Ok(())
+}
+
+pub(crate) fn skip_by_seeking<R: Read + Seek>(reader: &mut R, len: u64) -> Result<(), Error> {
+    let len = len.try_into().map_err(|_| Error::new(63))?;
+    reader.seek(SeekFrom::Current(len))?;
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::parallel::run_all;
+use crate::rustimpl::{direct_color_mode, fit_significant_bits, get_color_profile, lodepng_color_mode_equal, lodepng_convert, lodepng_decode, lodepng_encode, palette_color_mode, sort_palette};
+use crate::{zero_vec, ChunksIter, ColorMode, ColorType, CompressSettings, Decoder, Encoder, Error, FilterStrategy, Info, PaletteSort};
+use std::slice;
+
+/// Metadata chunks that `optimize` keeps
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum KeepMetadata {
+    /// Everything the decoder has read, including text and unknown chunks, except `iDOT` that belongs to the old image data
+    #[default]
+    All,
+    /// Only chunks that change how the colors look: `gAMA`, `cHRM`, `sRGB`, `iCCP`, `cICP`, `mDCv` and `cLLi`
+    ColorSpace,
+    /// No ancillary chunks, except `tRNS`
+    None,
+}
+
+/// What `optimize` tries
+#[derive(Debug, Clone)]
+pub struct OptimizeOptions {
+    /// Filter strategies to try. `PREDEFINED` is skipped.
+    pub filters: Vec<FilterStrategy>,
+    /// zlib compression levels to try, 0-9
+    pub levels: Vec<u8>,
+    /// Try smaller color types and bit depths, every `PaletteSort`, and no alpha channel if it's opaque
+    pub reduce_color: bool,
+    /// Try both interlaced (Adam7) and non-interlaced images. If false, interlacing of the input is kept.
+    pub try_interlace: bool,
+    /// Chunks to copy from the input file
+    pub keep: KeepMetadata,
+}
+
+impl Default for OptimizeOptions {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            filters: vec![FilterStrategy::ZERO, FilterStrategy::MINSUM, FilterStrategy::ENTROPY, FilterStrategy::BRUTE_FORCE],
+            levels: vec![6, 9],
+            reduce_color: true,
+            try_interlace: true,
+            keep: KeepMetadata::All,
+        }
+    }
+}
+
+/// Makes a PNG file smaller, without changing its pixels
+///
+/// Color modes and interlacing are compared with quick settings first, and then the best of them is compressed with
+/// every filter strategy and zlib level from the options. Trials run on multiple threads if the `parallel` feature is enabled.
+///
+/// The result is decoded and compared with the input before it's returned. With `KeepMetadata::All`, the input is
+/// returned unchanged if it can't be made smaller. Animated PNGs aren't supported.
+///
+/// ```rust
+/// # use lodepng::*; let png = encode32(&[RGBA::new(0,0,0,255); 64], 8, 8)?;
+/// let smaller = optimize(&png, &OptimizeOptions::default())?;
+/// assert!(smaller.len() <= png.len());
+/// # Ok::<_, Error>(())
+/// ```
+pub fn optimize<Bytes: AsRef<[u8]>>(png: Bytes, options: &OptimizeOptions) -> Result<Vec<u8>, Error> {
+    let png = png.as_ref();
+    let chunks = ChunksIter::new(png.get(33..).ok_or(Error::new(27))?);
+    if chunks.map_while(Result::ok).any(|ch| &ch.name() == b"acTL") {
+        return Err(Error::new(137));
+    }
+    let mut decoder = Decoder::new();
+    decoder.color_convert(false);
+    decoder.remember_unknown_chunks(true);
+    decoder.use_significant_bits(false);
+    let (raw, w, h) = lodepng_decode(&mut decoder.state, png)?;
+    let raw = &raw[..];
+    let source = decoder.info_png();
+    let raw_mode = &source.color;
+
+    let kept = kept_metadata(source, options.keep);
+    let interlace_methods = if options.try_interlace { &[0, 1][..] } else { slice::from_ref(&source.interlace_method) };
+    let mut infos = Vec::new();
+    for color in color_modes(raw, w, h, raw_mode, options.reduce_color)? {
+        for &interlace_method in interlace_methods {
+            infos.push(trial_info(&kept, color.clone(), interlace_method));
+        }
+    }
+
+    /*the same quick settings as the encoder's defaults choose the color mode and interlacing*/
+    let default_level = CompressSettings::new().level();
+    let quick_level = options.levels.iter().copied().min().unwrap_or(default_level);
+    let mut candidates = run_all(infos.iter().map(|info| {
+        move || encode_trial(raw, w, h, raw_mode, info, FilterStrategy::MINSUM, quick_level, true)
+    }).collect()).into_iter().collect::<Result<Vec<_>, _>>()?;
+    let best = (0..candidates.len()).min_by_key(|&i| candidates[i].len()).ok_or(Error::new(138))?;
+    let info = &infos[best];
+
+    let filters = if options.filters.is_empty() { &[FilterStrategy::MINSUM][..] } else { &options.filters };
+    let levels = if options.levels.is_empty() { slice::from_ref(&default_level) } else { &options.levels };
+    let trials: Vec<_> = filters.iter().filter(|&&f| f != FilterStrategy::PREDEFINED)
+        .flat_map(|&filter| levels.iter().map(move |&level| (filter, level)))
+        .collect();
+    for png in run_all(trials.into_iter().map(|(filter, level)| {
+        move || encode_trial(raw, w, h, raw_mode, info, filter, level, false)
+    }).collect()) {
+        candidates.push(png?);
+    }
+    candidates.sort_by_key(Vec::len);
+
+    if options.keep == KeepMetadata::All && candidates[0].len() >= png.len() {
+        return Ok(png.to_vec());
+    }
+    let expected = to_rgba16(raw, raw_mode, w, h)?;
+    for candidate in candidates {
+        if decodes_to(&candidate, &expected) {
+            return Ok(candidate);
+        }
+    }
+    Err(Error::new(138))
+}
+
+fn kept_metadata(source: &Info, keep: KeepMetadata) -> Info {
+    let mut info = if keep == KeepMetadata::All {
+        let mut info = source.clone();
+        info.warnings.clear();
+        /*iDOT points into the IDAT chunks of the input. The encoder writes a new one if it's set to*/
+        for (chunks, source_chunks) in info.unknown_chunks.iter_mut().zip(&source.unknown_chunks) {
+            chunks.clear();
+            for ch in ChunksIter::new(source_chunks).filter_map(Result::ok).filter(|ch| !ch.is_type("iDOT")) {
+                chunks.extend_from_slice(ch.whole_chunk_data());
+            }
+        }
+        info
+    } else {
+        let mut info = Info::new();
+        info.color = source.color.clone();
+        if keep == KeepMetadata::ColorSpace {
+            info.gamma = source.gamma;
+            info.chromaticities = source.chromaticities;
+            info.srgb_intent = source.srgb_intent;
+            info.icc_profile = source.icc_profile.clone();
+            info.cicp = source.cicp;
+            info.mastering_display = source.mastering_display;
+            info.content_light_level = source.content_light_level;
+        }
+        info
+    };
+    /*the background is kept as a 16-bit RGB color, and stored in the color mode of each trial*/
+    match background_rgb16(source).filter(|_| info.background_defined) {
+        Some([r, g, b]) => (info.background_r, info.background_g, info.background_b) = (r, g, b),
+        None => info.background_defined = false,
+    }
+    info
+}
+
+/*the input's own color mode, and with reduce_color the smallest mode without a palette, and palettes in every order*/
+fn color_modes(raw: &[u8], w: u32, h: u32, raw_mode: &ColorMode, reduce_color: bool) -> Result<Vec<ColorMode>, Error> {
+    let mut modes = vec![raw_mode.clone()];
+    if reduce_color {
+        let prof = get_color_profile(raw, w, h, raw_mode);
+        modes.push(direct_color_mode(&prof)?);
+        if prof.bits <= 8 && prof.numcolors <= 256 {
+            let palette_mode = palette_color_mode(&prof.palette[..prof.numcolors as usize])?;
+            for sort in [PaletteSort::ALPHA, PaletteSort::LUMINANCE, PaletteSort::FREQUENCY, PaletteSort::NEIGHBORS] {
+                let mut sorted = palette_mode.clone();
+                sort_palette(&mut sorted, raw, w, h, raw_mode, sort)?;
+                modes.push(sorted);
+            }
+            modes.push(palette_mode);
+        }
+    }
+    let mut unique: Vec<ColorMode> = Vec::with_capacity(modes.len());
+    for mode in modes {
+        if !unique.iter().any(|m| lodepng_color_mode_equal(m, &mode)) {
+            unique.push(mode);
+        }
+    }
+    Ok(unique)
+}
+
+fn trial_info(kept: &Info, color: ColorMode, interlace_method: u8) -> Info {
+    let mut info = kept.clone();
+    if info.background_defined {
+        match background_in_mode([info.background_r, info.background_g, info.background_b], &color) {
+            Some([r, g, b]) => (info.background_r, info.background_g, info.background_b) = (r, g, b),
+            /*the background color isn't in the palette, or needs more bits*/
+            None => info.background_defined = false,
+        }
+    }
+    if !lodepng_color_mode_equal(&color, &kept.color) {
+        /*the histogram belongs to the old palette*/
+        info.histogram.clear();
+        info.significant_bits = info.significant_bits.map(|sbit| fit_significant_bits(&sbit, &color));
+    }
+    info.color = color;
+    info.interlace_method = interlace_method;
+    info
+}
+
+/*bKGD of a decoded file as 16-bit RGB*/
+fn background_rgb16(info: &Info) -> Option<[u16; 3]> {
+    let color = &info.color;
+    if color.colortype == ColorType::PALETTE {
+        let c = color.palette().get(info.background_r as usize)?;
+        return Some([c.r, c.g, c.b].map(|v| v as u16 * 257));
+    }
+    let max = ((1u32 << color.bitdepth()) - 1) as u16;
+    let scale = 65535 / max;
+    /*out of range values of a broken file are dropped*/
+    let [r, g, b] = [info.background_r, info.background_g, info.background_b].map(|v| (v <= max).then(|| v * scale));
+    Some([r?, g?, b?])
+}
+
+/*bKGD values for the encoder, which takes a palette index for palette images*/
+fn background_in_mode(rgb16: [u16; 3], color: &ColorMode) -> Option<[u16; 3]> {
+    if color.colortype == ColorType::PALETTE {
+        let rgb8 = rgb16.map(|v| (v % 257 == 0).then_some((v / 257) as u8));
+        let index = color.palette().iter().position(|c| [Some(c.r), Some(c.g), Some(c.b)] == rgb8)? as u16;
+        return Some([index; 3]);
+    }
+    if color.is_greyscale_type() && (rgb16[0] != rgb16[1] || rgb16[0] != rgb16[2]) {
+        return None;
+    }
+    let scale = 65535 / ((1u32 << color.bitdepth()) - 1) as u16;
+    if rgb16.iter().any(|&v| v % scale != 0) {
+        return None;
+    }
+    Some(rgb16.map(|v| v / scale))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encode_trial(raw: &[u8], w: u32, h: u32, raw_mode: &ColorMode, info: &Info, filter: FilterStrategy, level: u8, palette_filter_zero: bool) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder::new();
+    *encoder.info_raw_mut() = raw_mode.clone();
+    *encoder.info_png_mut() = info.clone();
+    encoder.set_auto_convert(false);
+    encoder.set_filter_strategy(filter, palette_filter_zero);
+    encoder.settings_mut().set_level(level);
+    lodepng_encode(raw, w, h, &encoder.state)
+}
+
+fn to_rgba16(raw: &[u8], raw_mode: &ColorMode, w: u32, h: u32) -> Result<Vec<u8>, Error> {
+    let mut out = zero_vec(w as usize * h as usize * 8)?;
+    lodepng_convert(&mut out, raw, &ColorType::RGBA.to_color_mode(16), raw_mode, w, h)?;
+    Ok(out)
+}
+
+fn decodes_to(png: &[u8], expected: &[u8]) -> bool {
+    let mut decoder = Decoder::new();
+    *decoder.info_raw_mut() = ColorType::RGBA.to_color_mode(16);
+    decoder.use_significant_bits(false);
+    lodepng_decode(&mut decoder.state, png).is_ok_and(|(image, _, _)| image == expected)
+}
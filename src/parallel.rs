@@ -0,0 +1,296 @@
+use crate::ffi::State;
+use crate::rustimpl::{filter_rows, linebytes_rounded, ChunkBuilder};
+use crate::zlib::{adler32_update, check_zlib_stream, compression};
+use crate::{zero_vec, ColorMode, CompressSettings, EncoderSettings, Error, FilterStrategy, Info};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::ops::Range;
+
+/*smallest amount of filtered image data per thread that is worth the overhead*/
+#[cfg(feature = "parallel")]
+const MIN_BAND_SIZE: usize = 1 << 20;
+
+/*size of the deflate window*/
+const WINDOW_SIZE: usize = 1 << 15;
+
+/*size of the iDOT chunk with two segments, including its length, type and CRC*/
+const IDOT_CHUNK_SIZE: usize = 12 + 28;
+
+/*runs the jobs on separate threads if the parallel feature is enabled, otherwise one after another*/
+pub(crate) fn run_all<T: Send, F: FnOnce() -> T + Send>(jobs: Vec<F>) -> Vec<T> {
+    #[cfg(feature = "parallel")]
+    {
+        std::thread::scope(|s| {
+            let threads: Vec<_> = jobs.into_iter().map(|job| s.spawn(job)).collect();
+            threads.into_iter().map(|t| t.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect()
+        })
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        jobs.into_iter().map(|job| job()).collect()
+    }
+}
+
+/*splits the image into bands of rows, one per thread, or None if it should be compressed on one thread.
+  Interlaced images and predefined filters (which are listed from the first row) aren't split*/
+#[cfg(feature = "parallel")]
+pub(crate) fn bands(w: u32, h: u32, info_png: &Info, settings: &EncoderSettings) -> Option<Vec<Range<u32>>> {
+    if info_png.interlace_method != 0 || settings.filter_strategy == FilterStrategy::PREDEFINED {
+        return None;
+    }
+    let row_size = 1 + linebytes_rounded(w, info_png.color.bpp_());
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let count = (row_size * h as usize / MIN_BAND_SIZE).min(threads).min(h as usize);
+    if count < 2 {
+        return None;
+    }
+    let rows_per_band = (h as usize + count - 1) / count;
+    Some((0..h).step_by(rows_per_band).map(|y| y..(y + rows_per_band as u32).min(h)).collect())
+}
+
+/*filters and compresses every band separately, into consecutive pieces of one zlib stream.
+  The first piece starts with the zlib header, and the last one ends with the Adler-32.
+  Bands end with a full flush, so they can be decompressed without the bands before.
+  If use_dictionary is set, the data of the previous band is used as a preset dictionary
+  (if it can be filtered again exactly), which compresses better, but needs the previous band to decompress*/
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn compress_bands(inp: &[u8], w: u32, h: u32, bands: &[Range<u32>], color: &ColorMode, settings: &EncoderSettings, zlibsettings: &CompressSettings, use_dictionary: bool) -> Result<Vec<Vec<u8>>, Error> {
+    let level = compression(zlibsettings);
+    let row_size = 1 + linebytes_rounded(w, color.bpp_());
+    /*brute force filter depends on the rows before, so the previous band can't be filtered again exactly*/
+    let use_dictionary = use_dictionary && cfg!(any(feature = "cfzlib", feature = "ngzlib", feature = "zlibrs")) && settings.filter_strategy != FilterStrategy::BRUTE_FORCE;
+    let dictionary_rows = if use_dictionary { (WINDOW_SIZE + row_size - 1) / row_size } else { 0 };
+    let jobs = bands.iter().enumerate().map(|(i, rows)| {
+        let last = i + 1 == bands.len();
+        move || -> Result<_, Error> {
+            let start = rows.start.saturating_sub(dictionary_rows as u32);
+            let mut filtered = Vec::new();
+            filtered.try_reserve_exact((rows.end - start) as usize * row_size)?;
+            filter_rows(&mut filtered, inp, w, h, start..rows.end, color, settings)?;
+            let (dictionary, data) = filtered.split_at((rows.start - start) as usize * row_size);
+            let deflated = deflate_band(data, dictionary, level, last)?;
+            Ok((deflated, adler32_update((1, 0), data), data.len()))
+        }
+    }).collect();
+    let compressed = run_all(jobs).into_iter().collect::<Result<Vec<_>, _>>()?;
+
+    let mut adler = (1, 0);
+    let mut pieces = Vec::with_capacity(compressed.len());
+    for (i, (deflated, band_adler, len)) in compressed.into_iter().enumerate() {
+        let mut piece = Vec::new();
+        piece.try_reserve_exact(deflated.len() + 6)?;
+        if i == 0 {
+            piece.extend_from_slice(&zlib_header(level));
+        }
+        piece.extend_from_slice(&deflated);
+        adler = adler32_combine(adler, band_adler, len);
+        pieces.push(piece);
+    }
+    if let Some(last) = pieces.last_mut() {
+        last.extend_from_slice(&((adler.1 << 16) | adler.0).to_be_bytes());
+    }
+    Ok(pieces)
+}
+
+/*raw deflate data ending with a full flush, or with the final block for the last band*/
+fn deflate_band(data: &[u8], _dictionary: &[u8], level: Compression, last: bool) -> Result<Vec<u8>, Error> {
+    let mut gz = Compress::new(level, false);
+    #[cfg(any(feature = "cfzlib", feature = "ngzlib", feature = "zlibrs"))]
+    if !_dictionary.is_empty() {
+        gz.set_dictionary(&_dictionary[_dictionary.len().saturating_sub(WINDOW_SIZE)..]).map_err(|_| Error::new(136))?;
+    }
+    let flush = if last { FlushCompress::Finish } else { FlushCompress::Full };
+    let mut out = Vec::new();
+    loop {
+        out.try_reserve(data.len() / 4 + 1024)?;
+        let status = gz.compress_vec(&data[gz.total_in() as usize..], &mut out, flush).map_err(|_| Error::new(136))?;
+        /*a flush is complete when all input is used, and there's space left in the output*/
+        let flushed = gz.total_in() as usize == data.len() && out.len() < out.capacity();
+        if status == Status::StreamEnd || (!last && flushed) {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+/*the 2-byte header that zlib writes for the compression level*/
+fn zlib_header(level: Compression) -> [u8; 2] {
+    let level_flag = match level.level() {
+        0..=1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let header = (0x78 << 8) | (level_flag << 6);
+    let header: u16 = header + 31 - header % 31;
+    header.to_be_bytes()
+}
+
+/*checksum of two pieces of data, from the checksums of each*/
+fn adler32_combine((a1, b1): (u32, u32), (a2, b2): (u32, u32), len2: usize) -> (u32, u32) {
+    const BASE: u64 = 65521;
+    let (a1, b1, a2, b2) = (u64::from(a1), u64::from(b1), u64::from(a2), u64::from(b2));
+    let len2 = len2 as u64 % BASE;
+    let a = (a1 + a2 + BASE - 1) % BASE;
+    let b = (b1 + b2 + len2 * (a1 + BASE - 1)) % BASE;
+    (a as u32, b as u32)
+}
+
+/*writes the iDOT chunk and two IDAT chunks, each with half of the rows compressed independently.
+  That's the layout made by Apple's encoder, which decoders can decompress on two threads*/
+pub(crate) fn add_chunks_idat_split(out: &mut Vec<u8>, inp: &[u8], w: u32, h: u32, info_png: &Info, settings: &EncoderSettings) -> Result<(), Error> {
+    let split = h / 2;
+    let pieces = compress_bands(inp, w, h, &[0..split, split..h], &info_png.color, settings, &settings.zlibsettings, false)?;
+    let mut ch = ChunkBuilder::new(out, b"iDOT");
+    for field in [2, 0, split, IDOT_CHUNK_SIZE as u32, split, h - split, (IDOT_CHUNK_SIZE + 12 + pieces[0].len()) as u32] {
+        ch.write_u32be(field);
+    }
+    ch.finish()?;
+    for piece in &pieces {
+        let mut ch = ChunkBuilder::new(out, b"IDAT");
+        ch.extend_from_slice(piece)?;
+        ch.finish()?;
+    }
+    Ok(())
+}
+
+/*where image data of a non-interlaced image can be split, from the iDOT chunk*/
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Restart {
+    /*first row of the second half*/
+    pub row: u32,
+    /*file offset of the IDAT chunk that starts the second half*/
+    pub chunk_offset: usize,
+}
+
+impl Restart {
+    /*iDOT data is: number of halves (2), 0, rows in the first half, offset of the first IDAT from the iDOT chunk,
+      rows in the first half, rows in the second half, offset of the IDAT that starts the second half*/
+    pub fn parse(data: &[u8], idot_offset: usize, h: u32) -> Option<Self> {
+        if data.len() != 28 {
+            return None;
+        }
+        let field = |i: usize| u32::from_be_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        let (top, bottom) = (field(4), field(5));
+        if field(0) != 2 || top == 0 || bottom == 0 || top.checked_add(bottom) != Some(h) {
+            return None;
+        }
+        Some(Self { row: top, chunk_offset: idot_offset.checked_add(field(6) as usize)? })
+    }
+}
+
+/*the image data can be decompressed in halves, if the file has iDOT*/
+pub(crate) fn can_split(state: &State) -> bool {
+    #[allow(deprecated)]
+    let custom = state.decoder.zlibsettings.custom_zlib.is_some();
+    cfg!(feature = "parallel") && !custom && state.info_png.interlace_method == 0
+}
+
+/*decompresses both halves of image data on separate threads, into scanlines for postprocess_scanlines.
+  None if the halves aren't independent, or anything else doesn't match, so the data has to be decompressed as one stream*/
+pub(crate) fn inflate_halves(top: &[u8], bottom: &[u8], restart: Restart, w: u32, h: u32, info_png: &Info, unfiltering_buffer: usize) -> Option<Result<Vec<u8>, Error>> {
+    let row_size = 1 + linebytes_rounded(w, info_png.color.bpp_());
+    let mut scanlines = match zero_vec(unfiltering_buffer + row_size * h as usize) {
+        Ok(s) => s,
+        Err(e) => return Some(Err(e)),
+    };
+    let (top_out, bottom_out) = scanlines[unfiltering_buffer..].split_at_mut(restart.row as usize * row_size);
+    let results = run_all(vec![(top, top_out, true), (bottom, bottom_out, false)].into_iter()
+        .map(|(inp, out, first)| move || inflate_exactly(inp, out, first)).collect());
+    let (Some((_, top_adler)), Some((read, bottom_adler))) = (results[0], results[1]) else { return None };
+    /*only the end of the second half has the checksum, and it's of all the data*/
+    let stored = u32::from_be_bytes(bottom.get(read..read + 4)?.try_into().unwrap());
+    let adler = adler32_combine(top_adler, bottom_adler, (h - restart.row) as usize * row_size);
+    if stored != (adler.1 << 16) | adler.0 {
+        return None;
+    }
+    Some(Ok(scanlines))
+}
+
+/*fills the whole output, and returns bytes of input used and the Adler-32 of the output.
+  The first half starts with the zlib header and has to use all of its input. The second one has to end the stream*/
+fn inflate_exactly(inp: &[u8], out: &mut [u8], first: bool) -> Option<(usize, (u32, u32))> {
+    let inp = if first {
+        check_zlib_stream(inp).ok()?;
+        &inp[2..]
+    } else {
+        inp
+    };
+    let mut dec = Decompress::new(false);
+    let status = dec.decompress(inp, out, FlushDecompress::None).ok()?;
+    let (read, written) = (dec.total_in() as usize, dec.total_out() as usize);
+    let complete = written == out.len() && if first { read == inp.len() && status != Status::StreamEnd } else { status == Status::StreamEnd };
+    if !complete {
+        return None;
+    }
+    Some((read, adler32_update((1, 0), out)))
+}
+
+#[test]
+fn adler32_combined() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(i) >> 5) as u8).collect();
+    let whole = adler32_update((1, 0), &data);
+    for split in [0, 1, 5552, 65521, 99_999] {
+        let (first, second) = data.split_at(split);
+        assert_eq!(whole, adler32_combine(adler32_update((1, 0), first), adler32_update((1, 0), second), second.len()));
+    }
+}
+
+#[test]
+fn bands_decompress_to_filtered_image() {
+    let (w, h) = (300u32, 200u32);
+    let image: Vec<u8> = (0..w * h * 4).map(|i| (i % 251 ^ i / 1200) as u8).collect();
+    let color = ColorMode::default();
+    for strategy in [FilterStrategy::MINSUM, FilterStrategy::BRUTE_FORCE] {
+        let mut settings = EncoderSettings::default();
+        settings.filter_strategy = strategy;
+        let mut serial = Vec::new();
+        filter_rows(&mut serial, &image, w, h, 0..h, &color, &settings).unwrap();
+        for level in [0, 1, 6, 9] {
+            let mut zlibsettings = CompressSettings::default();
+            zlibsettings.set_level(level);
+            let bands = [0..7, 7..100, 100..101, 101..200];
+            let out = compress_bands(&image, w, h, &bands, &color, &settings, &zlibsettings, true).unwrap().concat();
+            let filtered = crate::zlib::decompress_into_vec(&out).unwrap();
+            if strategy == FilterStrategy::MINSUM {
+                assert_eq!(filtered, serial);
+            } else {
+                assert_eq!(filtered.len(), serial.len());
+            }
+        }
+    }
+}
+
+#[test]
+fn bands_with_padding_bits() {
+    let (w, h) = (13u32, 50u32);
+    let image: Vec<u8> = (0..(w * h + 7) / 8).map(|i| (i * 37) as u8).collect();
+    let mut color = ColorMode::default();
+    color.colortype = crate::ColorType::GREY;
+    color.set_bitdepth(1);
+    let settings = EncoderSettings::default();
+    let mut serial = Vec::new();
+    filter_rows(&mut serial, &image, w, h, 0..h, &color, &settings).unwrap();
+    let out = compress_bands(&image, w, h, &[0..20, 20..21, 21..50], &color, &settings, &CompressSettings::default(), true).unwrap();
+    assert_eq!(crate::zlib::decompress_into_vec(&out.concat()).unwrap(), serial);
+}
+
+#[test]
+fn idot_halves() {
+    let (w, h) = (40u32, 31u32);
+    let image: Vec<u8> = (0..w * h * 4).map(|i| (i * 7 % 253) as u8).collect();
+    let mut info = Info::new();
+    info.color.colortype = crate::ColorType::RGBA;
+    let settings = EncoderSettings::default();
+    let mut png = Vec::new();
+    add_chunks_idat_split(&mut png, &image, w, h, &info, &settings).unwrap();
+    let chunks: Vec<_> = crate::ChunksIter::new(&png).map(Result::unwrap).collect();
+    let restart = Restart::parse(chunks[0].data(), 0, h).unwrap();
+    assert_eq!((restart.row, restart.chunk_offset), (15, IDOT_CHUNK_SIZE + chunks[1].len() + 12));
+    assert!(Restart::parse(chunks[0].data(), 0, h + 1).is_none());
+
+    let scanlines = inflate_halves(chunks[1].data(), chunks[2].data(), restart, w, h, &info, 5).unwrap().unwrap();
+    let whole = crate::zlib::decompress_into_vec(&[chunks[1].data(), chunks[2].data()].concat()).unwrap();
+    assert_eq!(&scanlines[5..], whole);
+    /*the second half can't end the stream if the halves are swapped*/
+    assert!(inflate_halves(chunks[2].data(), chunks[1].data(), restart, w, h, &info, 5).is_none());
+}
@@ -0,0 +1,381 @@
+use crate::ffi::State;
+use crate::rustimpl::{check_chunk_length, check_memory_limit, linebits_exact, linebytes_rounded, lodepng_convert, lodepng_inspect, needs_color_convert, read_chunk_checked, set_image_line};
+use crate::warning;
+use crate::stream::Scanlines;
+use crate::{new_bitmap, zero_vec, ChunkPosition, ChunkRef, ColorMode, DecoderSettings, Error, Image, Info, WarningKind};
+use std::collections::VecDeque;
+
+/// IDAT data is inflated in pieces of this size, so that there's never much decompressed data waiting
+const IDAT_PUSH_SIZE: usize = 1 << 14;
+
+/// Progress of `PartialDecoder`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeEvent {
+    /// Width, height and color type are known. See `info_png()`.
+    Header,
+    /// Image data starts, and the palette is known. Only for images with a `PLTE` chunk.
+    Palette,
+    /// This many rows from the top of the image have been decoded. See `image()`.
+    Rows(usize),
+    /// The whole file has been read. See `finish()`.
+    Done,
+}
+
+enum Phase {
+    /// Waiting for the signature and IHDR
+    Header,
+    /// Waiting for a whole chunk, or the header of an IDAT chunk
+    Chunk,
+    /// In the middle of an IDAT chunk
+    Idat { remaining: usize, crc: crc32fast::Hasher },
+    /// After IEND
+    Done,
+    /// Can't resume in the middle of a chunk
+    Failed(Error),
+}
+
+/// Decodes a PNG file fed to it in pieces of any size, e.g. while it's being downloaded
+///
+/// The image is decoded to a full-size buffer, and available rows can be displayed before the rest of the file arrives.
+/// Interlaced images are decoded when their image data is complete.
+///
+///  ```no_run
+///  # use lodepng::*; let decoder = Decoder::new();
+///  # let socket: Vec<Vec<u8>> = vec![]; #[allow(unused_variables)] fn show(_buf: &[u8], rows: usize) {}
+///  let mut partial = decoder.partial();
+///  for bytes in socket {
+///      partial.feed(&bytes)?;
+///      while let Some(event) = partial.next_event() {
+///          if let DecodeEvent::Rows(rows) = event {
+///              show(partial.image(), rows);
+///          }
+///      }
+///  }
+///  let image = partial.finish()?;
+///  # Ok::<_, Error>(())
+///  ```
+pub struct PartialDecoder {
+    state: State,
+    /// input that hasn't been parsed yet starts at `pos`
+    buf: Vec<u8>,
+    pos: usize,
+    /// bytes removed from the start of `buf` so far
+    consumed: usize,
+    /// type and file offset of the chunk being parsed, for errors
+    chunk: Option<([u8; 4], usize)>,
+    phase: Phase,
+    width: u32,
+    height: u32,
+    critical_pos: ChunkPosition,
+    /// CRCs aren't checked after an unknown chunk, same as `decode`
+    unknown: bool,
+    scanlines: Scanlines,
+    idat_ended: bool,
+    convert: bool,
+    image: Vec<u8>,
+    rows: u32,
+    /// row after color conversion
+    out_line: Vec<u8>,
+    events: VecDeque<DecodeEvent>,
+}
+
+impl PartialDecoder {
+    pub(crate) fn new(decoder: DecoderSettings, info_raw: ColorMode) -> Self {
+        Self {
+            state: State {
+                decoder,
+                info_raw,
+                ..State::default()
+            },
+            buf: Vec::new(),
+            pos: 0,
+            consumed: 0,
+            chunk: None,
+            phase: Phase::Header,
+            width: 0,
+            height: 0,
+            critical_pos: ChunkPosition::IHDR,
+            unknown: false,
+            scanlines: Scanlines::default(),
+            idat_ended: false,
+            convert: false,
+            image: Vec::new(),
+            rows: 0,
+            out_line: Vec::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Decodes as much as possible with the data given so far. See `next_event()` for what has changed.
+    ///
+    /// After an error the decoder can't continue.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Phase::Failed(err) = &self.phase {
+            return Err(err.clone());
+        }
+        if !matches!(self.phase, Phase::Done) {
+            self.buf.drain(..self.pos);
+            self.consumed += self.pos;
+            self.pos = 0;
+            self.buf.try_reserve(data.len())?;
+            self.buf.extend_from_slice(data);
+
+            let rows_before = self.rows;
+            let res = self.parse().map_err(|e| match self.chunk {
+                Some((name, offset)) => e.in_chunk(name, offset),
+                None => e,
+            });
+            if self.rows != rows_before {
+                self.events.push_back(DecodeEvent::Rows(self.rows as usize));
+            }
+            if matches!(self.phase, Phase::Done) {
+                self.events.push_back(DecodeEvent::Done);
+            }
+            if let Err(err) = res {
+                self.phase = Phase::Failed(err.clone());
+                self.buf = Vec::new();
+                return Err(err);
+            }
+        } else if !data.is_empty() {
+            if let Err(err) = self.trailing_data() {
+                self.phase = Phase::Failed(err.clone());
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Data after IEND is ignored with a warning
+    fn trailing_data(&mut self) -> Result<(), Error> {
+        if self.state.info_png.warnings.iter().any(|w| w.kind == WarningKind::TrailingData) {
+            return Ok(());
+        }
+        warning::warn(&mut self.state.info_png, &self.state.decoder, WarningKind::TrailingData, None, self.consumed)
+    }
+
+    /// Progress made by `feed()`, oldest first
+    #[inline]
+    pub fn next_event(&mut self) -> Option<DecodeEvent> {
+        self.events.pop_front()
+    }
+
+    fn parse(&mut self) -> Result<(), Error> {
+        loop {
+            let input = &self.buf[self.pos..];
+            match &mut self.phase {
+                Phase::Header => {
+                    let Some(header) = input.get(..33) else { return Ok(()) };
+                    let (info_png, w, h) = lodepng_inspect(&self.state.decoder, header, false)?;
+                    self.state.info_png = info_png;
+                    self.width = w;
+                    self.height = h;
+                    self.pos += 33;
+                    self.phase = Phase::Chunk;
+                    self.events.push_back(DecodeEvent::Header);
+                },
+                Phase::Chunk => {
+                    let Some(header) = input.get(..8) else { return Ok(()) };
+                    let len = crate::chunk_length(header);
+                    let chunk = (header[4..8].try_into().unwrap(), self.consumed + self.pos);
+                    check_chunk_length(&self.state.decoder, len).map_err(|e| e.in_chunk(chunk.0, chunk.1))?;
+                    if &header[4..8] == b"IDAT" {
+                        self.chunk = Some(chunk);
+                        if self.idat_ended {
+                            /*IDAT chunks must be consecutive*/
+                            return Err(Error::new(91));
+                        }
+                        if self.critical_pos != ChunkPosition::IDAT {
+                            self.start_image()?;
+                        }
+                        let mut crc = crc32fast::Hasher::new();
+                        crc.update(b"IDAT");
+                        self.phase = Phase::Idat { remaining: len, crc };
+                        self.pos += 8;
+                        continue;
+                    }
+                    if self.critical_pos == ChunkPosition::IDAT && !self.idat_ended {
+                        self.end_image_data()?;
+                    }
+                    self.chunk = Some(chunk);
+                    let input = &self.buf[self.pos..];
+                    if input.len() < len + 12 {
+                        return Ok(());
+                    }
+                    let ch = ChunkRef::new(input)?;
+                    let is_iend = ch.is_type(b"IEND");
+                    read_chunk_checked(&mut self.state.info_png, &self.state.decoder, &mut self.critical_pos, &mut self.unknown, ch, chunk.1)?;
+                    self.pos += len + 12;
+                    if is_iend {
+                        if !self.idat_ended {
+                            /*error: no image data*/
+                            return Err(Error::new(91));
+                        }
+                        self.consumed += self.pos;
+                        self.chunk = None;
+                        if self.pos < self.buf.len() {
+                            self.trailing_data()?;
+                        }
+                        self.phase = Phase::Done;
+                        self.buf = Vec::new();
+                        self.pos = 0;
+                        return Ok(());
+                    }
+                },
+                Phase::Idat { remaining: 0, crc } => {
+                    let Some(stored) = input.get(..4) else { return Ok(()) };
+                    let (checksum, stored) = (crc.clone().finalize(), u32::from_be_bytes(stored.try_into().unwrap()));
+                    if !self.state.decoder.ignore_crc && !self.unknown && checksum != stored {
+                        return Err(Error::new(57).with_mismatch(checksum.into(), stored.into()));
+                    }
+                    self.pos += 4;
+                    self.phase = Phase::Chunk;
+                },
+                Phase::Idat { remaining, crc } => {
+                    let len = (*remaining).min(input.len()).min(IDAT_PUSH_SIZE);
+                    if len == 0 {
+                        return Ok(());
+                    }
+                    let data = &input[..len];
+                    crc.update(data);
+                    *remaining -= len;
+                    self.pos += len;
+                    self.scanlines.push(data)?;
+                    self.decode_rows()?;
+                },
+                Phase::Done | Phase::Failed(_) => return Ok(()),
+            }
+        }
+    }
+
+    /// Called at the first IDAT, when the palette is known
+    fn start_image(&mut self) -> Result<(), Error> {
+        self.critical_pos = ChunkPosition::IDAT;
+        check_memory_limit(&self.state, self.width, self.height)?;
+        self.convert = needs_color_convert(&mut self.state)?;
+        let (w, h) = (self.width, self.height);
+        self.scanlines = Scanlines::new(&self.state.info_png.color, w, &self.state.decoder.zlibsettings)?;
+        self.image = zero_vec(self.state.info_raw.raw_size_opt(w, h)?)?;
+        if self.convert {
+            self.out_line = zero_vec(linebytes_rounded(w, self.state.info_raw.bpp_()))?;
+        }
+        if !self.state.info_png.color.palette().is_empty() {
+            self.events.push_back(DecodeEvent::Palette);
+        }
+        Ok(())
+    }
+
+    fn decode_rows(&mut self) -> Result<(), Error> {
+        if self.state.info_png.interlace_method != 0 {
+            return Ok(());
+        }
+        let linebits = linebits_exact(self.width, self.state.info_raw.bpp_());
+        while self.rows < self.height && self.scanlines.unfilter_next()? {
+            let line = self.scanlines.line();
+            let line = if self.convert {
+                lodepng_convert(&mut self.out_line, line, &self.state.info_raw, &self.state.info_png.color, self.width, 1)?;
+                &self.out_line
+            } else {
+                line
+            };
+            set_image_line(&mut self.image, line, linebits, self.rows);
+            self.rows += 1;
+        }
+        Ok(())
+    }
+
+    /// Called after the last IDAT chunk
+    fn end_image_data(&mut self) -> Result<(), Error> {
+        self.idat_ended = true;
+        self.scanlines.finish_inflater()?;
+        let (w, h) = (self.width, self.height);
+        if self.state.info_png.interlace_method != 0 {
+            let image = self.scanlines.take_interlaced(w, h, &self.state.info_png)?;
+            if self.convert {
+                lodepng_convert(&mut self.image, &image, &self.state.info_raw, &self.state.info_png.color, w, h)?;
+            } else {
+                self.image = image;
+            }
+            self.rows = h;
+        } else {
+            self.decode_rows()?;
+            if self.rows != h {
+                /*image data ended before the last row*/
+                return Err(Error::new(91));
+            }
+            self.scanlines.check_end()?;
+        }
+        self.scanlines = Scanlines::default();
+        Ok(())
+    }
+
+    /// Width of the image in pixels. 0 before `DecodeEvent::Header`
+    #[inline]
+    #[must_use]
+    pub fn width(&self) -> usize {
+        self.width as usize
+    }
+
+    /// 0 before `DecodeEvent::Header`
+    #[inline]
+    #[must_use]
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    /// Color mode of `image()`. It's final after `DecodeEvent::Palette` or the first `DecodeEvent::Rows`.
+    #[inline]
+    #[must_use]
+    pub fn info_raw(&self) -> &ColorMode {
+        &self.state.info_raw
+    }
+
+    /// Metadata read so far
+    #[inline]
+    #[must_use]
+    pub fn info_png(&self) -> &Info {
+        &self.state.info_png
+    }
+
+    /// Number of rows from the top of the image that have been decoded
+    #[inline]
+    #[must_use]
+    pub fn rows_decoded(&self) -> usize {
+        self.rows as usize
+    }
+
+    /// Whole image buffer in `info_raw()` color mode, with zeros where rows haven't been decoded yet.
+    ///
+    /// Empty until the image data starts.
+    #[inline]
+    #[must_use]
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// True after `IEND` has been read
+    #[inline]
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        matches!(self.phase, Phase::Done) && self.idat_ended
+    }
+
+    /// Returns the decoded image. Fails if the file is incomplete.
+    ///
+    /// A missing `IEND` chunk is only an error if `WarningKind::MissingIend` is fatal.
+    pub fn finish(mut self) -> Result<Image, Error> {
+        if let Phase::Failed(err) = &self.phase {
+            return Err(err.clone());
+        }
+        if !self.is_done() {
+            if self.critical_pos != ChunkPosition::IDAT {
+                return Err(Error::new(52));
+            }
+            /*missing IEND is only a warning, like in decode()*/
+            warning::warn(&mut self.state.info_png, &self.state.decoder, WarningKind::MissingIend, None, self.consumed + self.buf.len())?;
+            if !self.idat_ended {
+                self.end_image_data()?;
+            }
+        }
+        new_bitmap(self.image, self.width, self.height, self.state.info_raw.colortype, self.state.info_raw.bitdepth)
+    }
+}
@@ -0,0 +1,188 @@
+use crate::ffi::State;
+use crate::rustimpl::{adam7_deinterlace_pass, adam7_pass_values, linebits_exact, linebits_rounded, lodepng_convert, lodepng_decode_with, lodepng_inspect, needs_color_convert, read_bit_from_reversed_stream, remove_padding_bits, set_bit_of_reversed_stream, unfilter_scanlines};
+use crate::zlib;
+use crate::{zero_vec, ColorMode, Error};
+
+/// How `Decoder::decode_progressive` fills in pixels of Adam7 passes that haven't been decoded yet
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PreviewFill {
+    /// Copy the nearest decoded pixel above and to the left, which looks blocky
+    #[default]
+    Replicate,
+    /// Blend the nearest decoded pixels around. Palette images and images with less than 8 bits per channel are replicated instead.
+    Bilinear,
+}
+
+/// Spacing of the grid of pixels that are known after each pass
+pub(crate) const KNOWN_AFTER_PASS: [(u8, u8); 7] = [(8, 8), (4, 8), (4, 4), (2, 4), (2, 2), (1, 2), (1, 1)];
+
+struct Progress<'cb> {
+    fill: PreviewFill,
+    callback: &'cb mut dyn FnMut(usize, &[u8]),
+    width: u32,
+    height: u32,
+    /// passes reported so far
+    passes_done: usize,
+    /// start of the next pass in the decompressed data
+    next_pass_start: usize,
+    convert: bool,
+    /// pixels of passes decoded so far, in the PNG's color mode
+    image: Vec<u8>,
+    preview: Vec<u8>,
+}
+
+/*decodes like lodepng_decode, and reports previews of each Adam7 pass as soon as its data has been decompressed*/
+pub(crate) fn decode_progressive(state: &mut State, inp: &[u8], fill: PreviewFill, callback: &mut dyn FnMut(usize, &[u8])) -> Result<(Vec<u8>, u32, u32), Error> {
+    let (_, width, height) = lodepng_inspect(&state.decoder, inp, false)?;
+    let mut progress = Progress {
+        fill,
+        callback,
+        width,
+        height,
+        passes_done: 0,
+        next_pass_start: 0,
+        convert: false,
+        image: Vec::new(),
+        preview: Vec::new(),
+    };
+    let (out, w, h) = lodepng_decode_with(state, inp, &mut |state, dec| progress.on_idat(state, dec))?;
+    if state.info_png.interlace_method != 0 {
+        /*passes that weren't available earlier, e.g. with a custom zlib decoder, are made from the final image*/
+        progress.finish(&out, &state.info_raw)?;
+    }
+    Ok((out, w, h))
+}
+
+impl Progress<'_> {
+    fn on_idat(&mut self, state: &mut State, dec: &mut zlib::Decoder) -> Result<(), Error> {
+        if state.info_png.interlace_method == 0 || self.passes_done >= 7 {
+            return Ok(());
+        }
+        let Some(inflated) = dec.output_mut()? else { return Ok(()) };
+        let (w, h) = (self.width, self.height);
+        let bpp = state.info_png.color.bpp_();
+        for pass in adam7_pass_values(w, h, bpp).skip(self.passes_done) {
+            let Some(filtered) = inflated.get(self.next_pass_start..self.next_pass_start + pass.filtered_len) else {
+                break;
+            };
+            if self.image.is_empty() {
+                self.convert = needs_color_convert(state)?;
+                self.image = zero_vec(state.info_png.color.raw_size_opt(w, h)?)?;
+                self.preview = zero_vec(state.info_raw.raw_size_opt(w, h)?)?;
+            }
+
+            let mut scanlines = Vec::new();
+            scanlines.try_reserve_exact(filtered.len())?;
+            scanlines.extend_from_slice(filtered);
+            unfilter_scanlines(&mut scanlines, 0, pass.w, pass.h, bpp)?;
+            if bpp.get() < 8 {
+                let mut packed = zero_vec(pass.packed_len)?;
+                remove_padding_bits(&mut packed, &scanlines, linebits_exact(pass.w, bpp), linebits_rounded(pass.w, bpp), pass.h);
+                adam7_deinterlace_pass(&mut self.image, &packed, w, bpp, &pass);
+            } else {
+                adam7_deinterlace_pass(&mut self.image, &scanlines, w, bpp, &pass);
+            }
+            self.next_pass_start += pass.filtered_len;
+
+            if self.convert {
+                lodepng_convert(&mut self.preview, &self.image, &state.info_raw, &state.info_png.color, w, h)?;
+            } else {
+                self.preview.copy_from_slice(&self.image);
+            }
+            self.report(&state.info_raw);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &[u8], info_raw: &ColorMode) -> Result<(), Error> {
+        while self.passes_done < 7 {
+            self.preview.clear();
+            self.preview.try_reserve_exact(out.len())?;
+            self.preview.extend_from_slice(out);
+            self.report(info_raw);
+        }
+        Ok(())
+    }
+
+    /// Fills in the preview of the pass that has just been decoded
+    fn report(&mut self, info_raw: &ColorMode) {
+        let known = KNOWN_AFTER_PASS[self.passes_done];
+        fill_preview(&mut self.preview, info_raw, self.width as usize, self.height as usize, self.fill, known);
+        self.passes_done += 1;
+        (self.callback)(self.passes_done, &self.preview);
+    }
+}
+
+/*overwrites all pixels that aren't on the (sx, sy) grid with ones computed from the pixels on the grid*/
+pub(crate) fn fill_preview(buf: &mut [u8], mode: &ColorMode, w: usize, h: usize, fill: PreviewFill, (sx, sy): (u8, u8)) {
+    if sx == 1 && sy == 1 {
+        return;
+    }
+    let bilinear = fill == PreviewFill::Bilinear && !mode.is_palette_type() && (mode.bitdepth() == 8 || mode.bitdepth() == 16);
+    if bilinear {
+        fill_bilinear(buf, w, h, mode.channels().into(), mode.bitdepth() == 16, sx.into(), sy.into());
+    } else {
+        fill_replicate(buf, w, h, mode.bpp() as usize, sx.into(), sy.into());
+    }
+}
+
+fn fill_replicate(buf: &mut [u8], w: usize, h: usize, bpp: usize, sx: usize, sy: usize) {
+    for y in 0..h {
+        let y0 = y - y % sy;
+        for x in 0..w {
+            let x0 = x - x % sx;
+            if x == x0 && y == y0 {
+                continue;
+            }
+            let src = y0 * w + x0;
+            let dst = y * w + x;
+            if bpp >= 8 {
+                let bytewidth = bpp / 8;
+                buf.copy_within(src * bytewidth..(src + 1) * bytewidth, dst * bytewidth);
+            } else {
+                for i in 0..bpp {
+                    let bit = read_bit_from_reversed_stream(src * bpp + i, buf);
+                    set_bit_of_reversed_stream(dst * bpp + i, buf, bit);
+                }
+            }
+        }
+    }
+}
+
+fn fill_bilinear(buf: &mut [u8], w: usize, h: usize, channels: usize, is_16bit: bool, sx: usize, sy: usize) {
+    let bytes = if is_16bit { 2 } else { 1 };
+    let pixel_size = channels * bytes;
+    let sample = |buf: &[u8], px: usize, c: usize| -> u32 {
+        let i = px * pixel_size + c * bytes;
+        if is_16bit { u16::from_be_bytes([buf[i], buf[i + 1]]).into() } else { buf[i].into() }
+    };
+    let area = (sx * sy) as u32;
+    for y in 0..h {
+        let y0 = y - y % sy;
+        /*at the bottom edge there may be no grid row below*/
+        let (y1, wy) = if y0 + sy < h { (y0 + sy, (y - y0) as u32) } else { (y0, 0) };
+        let ny = sy as u32 - wy;
+        for x in 0..w {
+            let x0 = x - x % sx;
+            if x == x0 && y == y0 {
+                continue;
+            }
+            let (x1, wx) = if x0 + sx < w { (x0 + sx, (x - x0) as u32) } else { (x0, 0) };
+            let nx = sx as u32 - wx;
+            let dst = y * w + x;
+            for c in 0..channels {
+                let v = (sample(buf, y0 * w + x0, c) * nx * ny
+                    + sample(buf, y0 * w + x1, c) * wx * ny
+                    + sample(buf, y1 * w + x0, c) * nx * wy
+                    + sample(buf, y1 * w + x1, c) * wx * wy
+                    + area / 2) / area;
+                let i = dst * pixel_size + c * bytes;
+                if is_16bit {
+                    buf[i..i + 2].copy_from_slice(&(v as u16).to_be_bytes());
+                } else {
+                    buf[i] = v as u8;
+                }
+            }
+        }
+    }
+}
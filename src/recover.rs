@@ -0,0 +1,210 @@
+use crate::ffi::State;
+use crate::progressive::{fill_preview, KNOWN_AFTER_PASS};
+use crate::rustimpl::{adam7_expected_size, adam7_pass_values, check_chunk_length, check_memory_limit, convert_decoded, linebytes_rounded, lodepng_inspect, postprocess_scanlines, read_chunk_checked, set_bit_of_reversed_stream};
+use crate::warning;
+use crate::{chunk_length, zero_vec, ChunkPosition, ChunkRef, ColorType, Error, Info, PreviewFill, WarningKind};
+use flate2::{Decompress, FlushDecompress, Status};
+
+/// What `Decoder::decode_recovering` puts in place of pixels that couldn't be decoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum MissingFill {
+    /// All zeros, which is transparent black, or black if the output color type has no alpha
+    #[default]
+    Transparent,
+    /// Color from the `bKGD` chunk, or `Transparent` if there's none
+    Background,
+}
+
+/// How much of a damaged file `Decoder::decode_recovering` could decode
+#[derive(Debug, Clone)]
+pub struct Recovery {
+    /// Rows from the top that have all of their pixels decoded. Equals the height if nothing was missing.
+    pub valid_rows: usize,
+    /// Adam7 passes that have been decoded completely. Always 0 for non-interlaced images.
+    pub passes: usize,
+    /// The first problem found, or `None` if the file is fine
+    pub error: Option<Error>,
+}
+
+impl Recovery {
+    /// True if the whole image has been decoded
+    #[inline]
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+
+    fn note(&mut self, err: Error) {
+        if self.error.is_none() {
+            self.error = Some(err);
+        }
+    }
+}
+
+/*decodes like lodepng_decode, but stops at the first damage in image data instead of failing.
+  Only a broken header or an error in a critical chunk before the image data is fatal*/
+pub(crate) fn decode_recovering(state: &mut State, inp: &[u8], fill: MissingFill) -> Result<(Vec<u8>, u32, u32, Recovery), Error> {
+    let (info, w, h) = lodepng_inspect(&state.decoder, inp, false)?;
+    state.info_png = info;
+    check_memory_limit(state, w, h)?;
+    let mut recovery = Recovery { valid_rows: 0, passes: 0, error: None };
+
+    let idat = read_chunks(state, inp, &mut recovery)?;
+    let info_png = &state.info_png;
+    let bpp = info_png.color.bpp_();
+    let expected = if info_png.interlace_method == 0 {
+        info_png.color.raw_size_idat(w, h).map(|s| s + h as usize)
+    } else {
+        adam7_expected_size(&info_png.color, w, h)
+    }.ok_or(Error::new(92))?;
+    let mut inflated = inflate_prefix(&idat, expected, &mut recovery)?;
+    if inflated.len() < expected {
+        recovery.note(Error::new(91).with_mismatch(expected as u64, inflated.len() as u64));
+    }
+
+    let decoded = if info_png.interlace_method == 0 {
+        let linebytes = linebytes_rounded(w, bpp);
+        let rows = complete_rows(&inflated, linebytes, h);
+        inflated.truncate(rows * (1 + linebytes));
+        let mut decoded = postprocess_scanlines(inflated, 0, w, rows as u32, info_png)?;
+        let raw_size = info_png.color.raw_size_opt(w, h)?;
+        decoded.try_reserve_exact(raw_size - decoded.len())?;
+        decoded.resize(raw_size, 0);
+        recovery.valid_rows = rows;
+        decoded
+    } else {
+        let mut complete = 0;
+        for pass in adam7_pass_values(w, h, bpp) {
+            let Some(data) = inflated.get(complete..complete + pass.filtered_len) else { break };
+            if complete_rows(data, linebytes_rounded(pass.w, bpp), pass.h) < pass.h as usize {
+                break;
+            }
+            complete += pass.filtered_len;
+            recovery.passes += 1;
+        }
+        /*missing passes are decoded from zeros, and replaced with decoded pixels after color conversion*/
+        inflated.truncate(complete);
+        inflated.resize(expected, 0);
+        recovery.valid_rows = if recovery.passes == 7 { h as usize } else { 0 };
+        postprocess_scanlines(inflated, 0, w, h, info_png)?
+    };
+
+    let fill_pixel = match fill {
+        MissingFill::Background => background_pixel(&state.info_png),
+        MissingFill::Transparent => None,
+    };
+    let mut out = convert_decoded(state, decoded, w, h)?;
+    let raw_bpp = state.info_raw.bpp() as usize;
+    if recovery.passes > 0 {
+        fill_preview(&mut out, &state.info_raw, w as usize, h as usize, PreviewFill::Replicate, KNOWN_AFTER_PASS[recovery.passes - 1]);
+    } else if recovery.valid_rows < h as usize {
+        let pixel = match fill_pixel {
+            Some(px) => convert_decoded(state, px, 1, 1)?,
+            None => zero_vec((raw_bpp + 7) / 8)?,
+        };
+        for i in recovery.valid_rows * w as usize..w as usize * h as usize {
+            if raw_bpp >= 8 {
+                let bytes = raw_bpp / 8;
+                out[i * bytes..(i + 1) * bytes].copy_from_slice(&pixel);
+            } else {
+                for bit in 0..raw_bpp {
+                    set_bit_of_reversed_stream(i * raw_bpp + bit, &mut out, (pixel[0] >> (7 - bit)) & 1);
+                }
+            }
+        }
+    }
+    Ok((out, w, h, recovery))
+}
+
+/*collects IDAT data and reads other chunks, until the end of the file or a broken critical chunk*/
+fn read_chunks(state: &mut State, inp: &[u8], recovery: &mut Recovery) -> Result<Vec<u8>, Error> {
+    let mut idat = Vec::new();
+    let mut critical_pos = ChunkPosition::IHDR;
+    let mut unknown = false;
+    let mut pos = 33;
+    while let Some(header) = inp.get(pos..pos + 8) {
+        let len = chunk_length(header);
+        let name: [u8; 4] = header[4..8].try_into().unwrap();
+        if let Err(e) = check_chunk_length(&state.decoder, len) {
+            recovery.note(e.in_chunk(name, pos));
+            break;
+        }
+        let chunk = ChunkRef::new(&inp[pos..]);
+        if &name == b"IDAT" {
+            critical_pos = ChunkPosition::IDAT;
+            /*damaged data is still used, because the decompressor may get further*/
+            match chunk {
+                Ok(ch) if !state.decoder.ignore_crc && !unknown && !ch.check_crc() => recovery.note(ch.crc_error().in_chunk(name, pos)),
+                Err(e) => recovery.note(e.in_chunk(name, pos)),
+                Ok(_) => {},
+            }
+            let data = &inp[pos + 8..(pos + 8 + len).min(inp.len())];
+            idat.try_reserve(data.len())?;
+            idat.extend_from_slice(data);
+        } else {
+            let res = chunk.and_then(|ch| read_chunk_checked(&mut state.info_png, &state.decoder, &mut critical_pos, &mut unknown, ch, pos));
+            if let Err(e) = res {
+                recovery.note(e.in_chunk(name, pos));
+                if name[0] & 32 == 0 {
+                    break;
+                }
+            }
+            if &name == b"IEND" {
+                return Ok(idat);
+            }
+        }
+        pos += len + 12;
+    }
+    if let Err(e) = warning::warn(&mut state.info_png, &state.decoder, WarningKind::MissingIend, None, pos.min(inp.len())) {
+        recovery.note(e);
+    }
+    Ok(idat)
+}
+
+/*decompresses as much as possible, up to max_len bytes. Always uses the built-in decompressor*/
+fn inflate_prefix(data: &[u8], max_len: usize, recovery: &mut Recovery) -> Result<Vec<u8>, Error> {
+    let mut out = zero_vec(max_len)?;
+    let mut dec = Decompress::new(true);
+    loop {
+        let (read, written) = (dec.total_in() as usize, dec.total_out() as usize);
+        match dec.decompress(&data[read..], &mut out[written..], FlushDecompress::None) {
+            Ok(Status::StreamEnd) => break,
+            /*no progress means the data has been cut off, or all pixels are there already*/
+            Ok(_) if dec.total_in() as usize == read && dec.total_out() as usize == written => break,
+            Ok(_) => {},
+            Err(_) => {
+                recovery.note(Error::new(23));
+                break;
+            },
+        }
+    }
+    out.truncate(dec.total_out() as usize);
+    Ok(out)
+}
+
+/*number of whole scanlines from the start, up to the first one with an invalid filter type*/
+fn complete_rows(scanlines: &[u8], linebytes: usize, h: u32) -> usize {
+    scanlines.chunks_exact(1 + linebytes).take(h as usize).take_while(|line| line[0] <= 4).count()
+}
+
+/*the bKGD color as a single opaque pixel in the PNG's color mode*/
+fn background_pixel(info: &Info) -> Option<Vec<u8>> {
+    if !info.background_defined {
+        return None;
+    }
+    let (r, g, b) = (info.background_r, info.background_g, info.background_b);
+    let depth = info.color.bitdepth();
+    let max = ((1u32 << depth) - 1) as u16;
+    let samples = match info.color.colortype {
+        ColorType::PALETTE | ColorType::GREY => vec![r],
+        ColorType::GREY_ALPHA => vec![r, max],
+        ColorType::RGB => vec![r, g, b],
+        ColorType::RGBA => vec![r, g, b, max],
+        _ => return None,
+    };
+    Some(match depth {
+        16 => samples.iter().flat_map(|s| s.to_be_bytes()).collect(),
+        8 => samples.iter().map(|&s| s as u8).collect(),
+        _ => vec![(r as u8) << (8 - depth)],
+    })
+}
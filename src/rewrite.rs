@@ -0,0 +1,224 @@
+use crate::rustimpl::{check_chunk_length, lodepng_chunk_generate_crc, lodepng_inspect};
+use crate::stream::{read_exact, read_fully};
+use crate::{chunk_length, ChunkPosition, ChunkRef, DecoderSettings, Error};
+use std::io::{self, Read, Write};
+
+/// Changes metadata chunks of a PNG file, without decoding or recompressing the image
+///
+/// Image data and critical chunks are copied byte for byte. Only ancillary chunks can be removed, added or replaced.
+/// `iDOT` is dropped if chunks between it and the image data change, because it has the offset of the image data.
+///
+/// ```rust
+/// # use lodepng::*; let png = encode32(&[RGBA::new(0,0,0,255)], 1, 1)?;
+/// let mut rewriter = ChunkRewriter::new();
+/// rewriter.remove("tIME")?;
+/// rewriter.retain(|chunk| !chunk.is_private());
+/// rewriter.replace(ChunkPosition::IHDR, "sRGB", &[0])?;
+/// let png = rewriter.rewrite(&png)?;
+/// # Ok::<_, Error>(())
+/// ```
+#[derive(Default)]
+pub struct ChunkRewriter {
+    remove: Vec<[u8; 4]>,
+    retain: Option<Box<dyn FnMut(ChunkRef<'_>) -> bool + Send>>,
+    /*whole chunks with CRCs, same as Info::unknown_chunks*/
+    insert: [Vec<u8>; 3],
+}
+
+impl ChunkRewriter {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes all chunks of this type, e.g. `"tEXt"`
+    pub fn remove<C: AsRef<[u8]>>(&mut self, chunk_type: C) -> Result<(), Error> {
+        let chunk_type = ancillary_type(chunk_type.as_ref())?;
+        if !self.remove.contains(&chunk_type) {
+            self.remove.push(chunk_type);
+        }
+        Ok(())
+    }
+
+    /// Keeps only ancillary chunks for which the callback returns `true`. Critical chunks are always kept.
+    ///
+    /// It's called for every chunk not removed by type, in the order of the file, e.g. to remove text by its keyword.
+    pub fn retain(&mut self, keep: impl FnMut(ChunkRef<'_>) -> bool + Send + 'static) {
+        self.retain = Some(Box::new(keep));
+    }
+
+    /// Adds a new chunk in the file section after the given position. Chunks in the file are kept.
+    ///
+    /// A chunk at `ChunkPosition::PLTE` is added before image data if there's no `PLTE` chunk.
+    pub fn insert<C: AsRef<[u8]>>(&mut self, position: ChunkPosition, chunk_type: C, data: &[u8]) -> Result<(), Error> {
+        let chunk_type = ancillary_type(chunk_type.as_ref())?;
+        let len: u32 = data.len().try_into().ok().filter(|&len| len < 1 << 31).ok_or(Error::new(63))?;
+        let out = &mut self.insert[position as usize];
+        let start = out.len();
+        out.try_reserve(data.len() + 12)?;
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(&chunk_type);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&[0; 4]);
+        lodepng_chunk_generate_crc(&mut out[start..]);
+        Ok(())
+    }
+
+    /// Removes all chunks of this type from the file, and adds the new one in the given position
+    pub fn replace<C: AsRef<[u8]>>(&mut self, position: ChunkPosition, chunk_type: C, data: &[u8]) -> Result<(), Error> {
+        self.remove(chunk_type.as_ref())?;
+        self.insert(position, chunk_type, data)
+    }
+
+    /// Rewrites a PNG file in memory
+    pub fn rewrite(&mut self, png: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        out.try_reserve(png.len() + self.insert.iter().map(Vec::len).sum::<usize>())?;
+        self.rewrite_stream(png, &mut out)?;
+        Ok(out)
+    }
+
+    /// Reads a PNG file chunk by chunk, and writes the changed file. Image data isn't buffered.
+    ///
+    /// The writer isn't buffered, so use `BufWriter` for files.
+    pub fn rewrite_stream<R: Read, W: Write>(&mut self, mut reader: R, writer: W) -> Result<(), Error> {
+        let mut header = [0; 33];
+        if read_fully(&mut reader, &mut header)? != header.len() {
+            /*error: the data length is smaller than the length of a PNG header*/
+            return Err(Error::new(27));
+        }
+        let decoder = DecoderSettings::new();
+        lodepng_inspect(&decoder, &header, false)?;
+        let mut out = Output { writer, held: Vec::new(), idot_len: 0, changed: false };
+        out.write(&header)?;
+        out.insert(&self.insert[ChunkPosition::IHDR as usize])?;
+
+        let mut plte_written = false;
+        let mut idat_seen = false;
+        let mut chunk = Vec::new();
+        let mut offset = 33;
+        loop {
+            let mut chunk_header = [0; 8];
+            match read_fully(&mut reader, &mut chunk_header)? {
+                8 => {},
+                /*no IEND. Added chunks are kept anyway*/
+                0 => break,
+                _ => return Err(Error::new(30)),
+            }
+            let len = chunk_length(&chunk_header);
+            let name: [u8; 4] = chunk_header[4..8].try_into().unwrap();
+            let chunk_offset = offset;
+            check_chunk_length(&decoder, len).map_err(|e| e.in_chunk(name, chunk_offset))?;
+            offset += len + 12;
+
+            let is_image_data = &name == b"IDAT" || &name == b"fdAT";
+            if (&name == b"IDAT" || &name == b"IEND") && !plte_written {
+                out.insert(&self.insert[ChunkPosition::PLTE as usize])?;
+                plte_written = true;
+            }
+            if is_image_data || &name == b"IEND" {
+                out.release_idot()?;
+                idat_seen = true;
+            }
+            if &name == b"IEND" {
+                out.insert(&self.insert[ChunkPosition::IDAT as usize])?;
+            }
+            if is_image_data {
+                /*copied verbatim without buffering the whole chunk*/
+                out.write(&chunk_header)?;
+                let copied = io::copy(&mut (&mut reader).take(len as u64 + 4), &mut out.writer)?;
+                if copied != len as u64 + 4 {
+                    return Err(Error::new(30).in_chunk(name, chunk_offset));
+                }
+                continue;
+            }
+
+            chunk.clear();
+            chunk.try_reserve(len + 12)?;
+            chunk.extend_from_slice(&chunk_header);
+            chunk.resize(len + 12, 0);
+            read_exact(&mut reader, &mut chunk[8..]).map_err(|e| e.in_chunk(name, chunk_offset))?;
+            let ch = ChunkRef::new(&chunk)?;
+            let keep = !ch.is_ancillary() || (!self.remove.contains(&name) && self.retain.as_mut().map_or(true, |keep| keep(ch)));
+            if keep && &name == b"iDOT" && !idat_seen {
+                out.hold_idot(&chunk)?;
+            } else if keep {
+                out.write(&chunk)?;
+            } else {
+                out.changed = true;
+            }
+            if &name == b"PLTE" {
+                out.insert(&self.insert[ChunkPosition::PLTE as usize])?;
+                plte_written = true;
+            }
+            if &name == b"IEND" {
+                /*data after IEND is dropped*/
+                return Ok(());
+            }
+        }
+        if !plte_written {
+            out.insert(&self.insert[ChunkPosition::PLTE as usize])?;
+        }
+        out.release_idot()?;
+        out.insert(&self.insert[ChunkPosition::IDAT as usize])?;
+        Ok(())
+    }
+}
+
+/*iDOT has the offset of image data relative to itself, so it's held back with the chunks after it until image data,
+  and dropped if any of them were removed or added*/
+struct Output<W> {
+    writer: W,
+    held: Vec<u8>,
+    idot_len: usize,
+    changed: bool,
+}
+
+impl<W: Write> Output<W> {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.idot_len == 0 {
+            self.writer.write_all(data)?;
+        } else {
+            self.held.try_reserve(data.len())?;
+            self.held.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, chunks: &[u8]) -> Result<(), Error> {
+        self.changed |= !chunks.is_empty();
+        self.write(chunks)
+    }
+
+    fn hold_idot(&mut self, idot: &[u8]) -> Result<(), Error> {
+        self.release_idot()?;
+        self.held.try_reserve(idot.len())?;
+        self.held.extend_from_slice(idot);
+        self.idot_len = idot.len();
+        self.changed = false;
+        Ok(())
+    }
+
+    fn release_idot(&mut self) -> Result<(), Error> {
+        if self.idot_len != 0 {
+            let start = if self.changed { self.idot_len } else { 0 };
+            self.writer.write_all(&self.held[start..])?;
+            self.held.clear();
+            self.idot_len = 0;
+        }
+        Ok(())
+    }
+}
+
+/*chunk type of 4 letters, with the lowercase first letter of ancillary chunks*/
+fn ancillary_type(chunk_type: &[u8]) -> Result<[u8; 4], Error> {
+    let chunk_type: [u8; 4] = chunk_type.try_into().map_err(|_| Error::new(135))?;
+    if !chunk_type.iter().all(u8::is_ascii_alphabetic) {
+        return Err(Error::new(135));
+    }
+    if chunk_type[0] & 32 == 0 {
+        return Err(Error::new(134));
+    }
+    Ok(chunk_type)
+}
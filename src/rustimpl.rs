@@ -5,7 +5,9 @@ use crate::ffi::IntlText;
 use crate::ffi::LatinText;
 use crate::ffi::State;
 use crate::ChunkPosition;
+use crate::parallel;
 use crate::zlib;
+use crate::warning;
 use std::borrow::Cow;
 use std::num::NonZeroU8;
 
@@ -15,6 +17,7 @@ use std::cell::Cell;
 use std::collections::HashMap;
 use std::io::prelude::*;
 use std::io;
+use std::ops::Range;
 
 use std::slice;
 
@@ -73,7 +76,7 @@ fn get_palette_translucency(palette: &[RGBA]) -> PaletteTranslucency {
 /*The opposite of the remove_padding_bits function
   olinebits must be >= ilinebits*/
 #[inline(never)]
-fn add_padding_bits_line(out: &mut [u8], inp: &[u8], olinebits: usize, ilinebits: usize, y: u32) {
+pub(crate) fn add_padding_bits_line(out: &mut [u8], inp: &[u8], olinebits: usize, ilinebits: usize, y: u32) {
     let iline = y as usize * ilinebits;
     for i in 0..ilinebits {
         let bit = read_bit_from_reversed_stream(iline + i, inp);
@@ -85,17 +88,17 @@ fn add_padding_bits_line(out: &mut [u8], inp: &[u8], olinebits: usize, ilinebits
 }
 
 #[inline]
-const fn linebits_exact(w: u32, bpp: NonZeroU8) -> usize {
+pub(crate) const fn linebits_exact(w: u32, bpp: NonZeroU8) -> usize {
     w as usize * bpp.get() as usize
 }
 
 #[inline]
-fn linebits_rounded(w: u32, bpp: NonZeroU8) -> usize {
+pub(crate) fn linebits_rounded(w: u32, bpp: NonZeroU8) -> usize {
     linebytes_rounded(w, bpp) * 8
 }
 
 #[inline]
-const fn linebytes_rounded(w: u32, bpp: NonZeroU8) -> usize {
+pub(crate) const fn linebytes_rounded(w: u32, bpp: NonZeroU8) -> usize {
     (w as usize * bpp.get() as usize + 7) / 8
 }
 
@@ -127,8 +130,14 @@ fn filtered_scanlines(out: &mut dyn Write, inp: &[u8], w: u32, h: u32, info_png:
   */
 #[inline(never)]
 fn filter(out: &mut dyn Write, inp: &[u8], w: u32, h: u32, info: &ColorMode, settings: &EncoderSettings) -> Result<(), Error> {
+    filter_rows(out, inp, w, h, 0..h, info, settings)
+}
+
+/*same as filter, but only for the given range of rows. The row above the range is used for filtering*/
+pub(crate) fn filter_rows(out: &mut dyn Write, inp: &[u8], w: u32, h: u32, rows: Range<u32>, info: &ColorMode, settings: &EncoderSettings) -> Result<(), Error> {
     debug_assert!(w != 0);
     debug_assert!(h != 0);
+    debug_assert!(rows.end <= h);
     let bpp = info.bpp_();
     /*the width of a scanline in bytes, not including the filter type*/
     let linebytes = linebytes_rounded(w, bpp);
@@ -140,17 +149,21 @@ fn filter(out: &mut dyn Write, inp: &[u8], w: u32, h: u32, info: &ColorMode, set
     if bpp.get() < 8 && linebits_exact(w, bpp) != linebits_rounded(w, bpp) {
         let mut lines_tmp = zero_vec(linebytes * 2)?;
         let (mut tmp, mut tmp_prev) = lines_tmp.split_at_mut(linebytes);
-        for y in 0..h {
+        if rows.start > 0 {
+            add_padding_bits_line(&mut tmp[..], inp, linebits_rounded(w, bpp), linebits_exact(w, bpp), rows.start - 1);
+        }
+        for y in rows {
             std::mem::swap(&mut tmp, &mut tmp_prev);
             add_padding_bits_line(&mut tmp[..], inp, linebits_rounded(w, bpp), linebits_exact(w, bpp), y);
             f(&mut out_buffer, tmp, if y > 0 { Some(tmp_prev) } else { None });
             out.write_all(&out_buffer)?;
         }
     } else {
-        let mut prevline = None;
         // interlace gives larger buffers
         let inp = inp.get(..h as usize * linebytes).ok_or(Error::new(31))?;
-        for inp in inp.chunks_exact(linebytes) {
+        let (start, end) = (rows.start as usize * linebytes, rows.end as usize * linebytes);
+        let mut prevline = start.checked_sub(linebytes).map(|prev| &inp[prev..start]);
+        for inp in inp[start..end].chunks_exact(linebytes) {
             f(&mut out_buffer, inp, prevline);
             prevline = Some(inp);
             out.write_all(&out_buffer)?;
@@ -160,7 +173,7 @@ fn filter(out: &mut dyn Write, inp: &[u8], w: u32, h: u32, info: &ColorMode, set
 }
 
 #[inline(never)]
-fn make_filter<'a>(w: u32, h: u32, info: &ColorMode, settings: &'a EncoderSettings) -> Result<Box<dyn FnMut(&mut [u8], &[u8], Option<&[u8]>) + 'a>, Error> {
+pub(crate) fn make_filter<'a>(w: u32, h: u32, info: &ColorMode, settings: &'a EncoderSettings) -> Result<Box<dyn FnMut(&mut [u8], &[u8], Option<&[u8]>) + 'a>, Error> {
     let bpp = info.bpp_();
     debug_assert!(w != 0);
     /*bytewidth is used for filtering, is 1 when bpp < 8, number of bytes per pixel otherwise*/
@@ -1151,7 +1164,7 @@ fn read_chunk_text(info: &mut Info, data: &[u8]) -> Result<(), Error> {
 }
 
 /*compressed text chunk (zTXt)*/
-fn read_chunk_ztxt(info: &mut Info, zlibsettings: &DecompressSettings, data: &[u8]) -> Result<(), Error> {
+fn read_chunk_ztxt(info: &mut Info, zlibsettings: &DecompressSettings, max_size: usize, data: &[u8]) -> Result<(), Error> {
     let mut length = 0;
     while length < data.len() && data[length] != 0 {
         length += 1;
@@ -1172,7 +1185,7 @@ fn read_chunk_ztxt(info: &mut Info, zlibsettings: &DecompressSettings, data: &[u
         return Err(Error::new(75)); /*will fail if zlib error, e.g. if length is too small*/
     }
     let inl = &data[string2_begin..];
-    let decoded = zlib::decompress(inl, zlibsettings)?;
+    let decoded = zlib::decompress_limited(inl, zlibsettings, max_size)?;
     info.push_text(key, &decoded)?;
     Ok(())
 }
@@ -1183,7 +1196,7 @@ fn split_at_nul(data: &[u8]) -> (&[u8], &[u8]) {
 }
 
 /*international text chunk (iTXt)*/
-fn read_chunk_itxt(info: &mut Info, zlibsettings: &DecompressSettings, data: &[u8]) -> Result<(), Error> {
+fn read_chunk_itxt(info: &mut Info, zlibsettings: &DecompressSettings, max_size: usize, data: &[u8]) -> Result<(), Error> {
     /*Quick check if the chunk length isn't too small. Even without check
         it'd still fail with other error checks below if it's too short. This just gives a different error code.*/
     if data.len() < 5 {
@@ -1207,7 +1220,7 @@ fn read_chunk_itxt(info: &mut Info, zlibsettings: &DecompressSettings, data: &[u
 
     let decoded;
     let rest = if compressed_flag {
-        decoded = zlib::decompress(data, zlibsettings)?;
+        decoded = zlib::decompress_limited(data, zlibsettings, max_size)?;
         &decoded[..]
     } else {
         data
@@ -1243,21 +1256,291 @@ fn read_chunk_phys(info: &mut Info, data: &[u8]) -> Result<(), Error> {
     Ok(())
 }
 
+fn read_chunk_sbit(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    let depth = if info.color.colortype == ColorType::PALETTE { 8 } else { info.color.bitdepth() as u8 };
+    if data.len() != SignificantBits::default().channels(info.color.colortype).1 || data.iter().any(|&b| b < 1 || b > depth) {
+        return Err(Error::new(118));
+    }
+    info.significant_bits = Some(match *data {
+        [grey] => SignificantBits { red: grey, green: grey, blue: grey, alpha: 0 },
+        [grey, alpha] => SignificantBits { red: grey, green: grey, blue: grey, alpha },
+        [red, green, blue] => SignificantBits { red, green, blue, alpha: 0 },
+        [red, green, blue, alpha] => SignificantBits { red, green, blue, alpha },
+        _ => return Err(Error::new(118)),
+    });
+    Ok(())
+}
+
+fn read_chunk_offs(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    if data.len() != 9 {
+        return Err(Error::new(114));
+    }
+    let x = i32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    let y = i32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let unit = match data[8] {
+        0 => OffsetUnit::Pixel,
+        1 => OffsetUnit::Micrometer,
+        _ => return Err(Error::new(114)),
+    };
+    if x == i32::MIN || y == i32::MIN {
+        return Err(Error::new(114));
+    }
+    info.offset = Some(ImageOffset { x, y, unit });
+    Ok(())
+}
+
+/*floating-point number in the PNG format: optional sign, digits with optional point, optional exponent*/
+fn parse_png_float(s: &[u8]) -> Option<f64> {
+    let mantissa_end = s.iter().position(|&c| c == b'e' || c == b'E').unwrap_or(s.len());
+    let (mantissa, exponent) = s.split_at(mantissa_end);
+    let mantissa = mantissa.strip_prefix(b"+").or_else(|| mantissa.strip_prefix(b"-")).unwrap_or(mantissa);
+    let mut points = 0;
+    let mut digits = 0;
+    for &c in mantissa {
+        match c {
+            b'.' => points += 1,
+            b'0'..=b'9' => digits += 1,
+            _ => return None,
+        }
+    }
+    if points > 1 || digits == 0 {
+        return None;
+    }
+    if let Some(exponent) = exponent.get(1..) {
+        let exponent = exponent.strip_prefix(b"+").or_else(|| exponent.strip_prefix(b"-")).unwrap_or(exponent);
+        if exponent.is_empty() || !exponent.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+    }
+    std::str::from_utf8(s).ok()?.parse().ok().filter(|v: &f64| v.is_finite())
+}
+
+fn read_chunk_scal(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    let (&unit, rest) = data.split_first().ok_or(Error::new(115))?;
+    let unit = match unit {
+        1 => ScaleUnit::Meter,
+        2 => ScaleUnit::Radian,
+        _ => return Err(Error::new(115)),
+    };
+    let mut values = rest.split(|&c| c == 0);
+    let (Some(w), Some(h), None) = (values.next(), values.next(), values.next()) else {
+        return Err(Error::new(115));
+    };
+    let (Some(pixel_width), Some(pixel_height)) = (parse_png_float(w), parse_png_float(h)) else {
+        return Err(Error::new(115));
+    };
+    if pixel_width <= 0. || pixel_height <= 0. {
+        return Err(Error::new(115));
+    }
+    info.physical_scale = Some(PhysicalScale { unit, pixel_width, pixel_height });
+    Ok(())
+}
+
+fn read_chunk_pcal(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    let length = data.iter().take(80).position(|&c| c == 0).ok_or(Error::new(75))?;
+    if length < 1 {
+        return Err(Error::new(89));
+    }
+    let Some(fixed) = data.get(length + 1..length + 11) else {
+        return Err(Error::new(116));
+    };
+    let x0 = i32::from_be_bytes([fixed[0], fixed[1], fixed[2], fixed[3]]);
+    let x1 = i32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+    let equation = match fixed[8] {
+        0 => CalibrationEquation::Linear,
+        1 => CalibrationEquation::Exponential,
+        2 => CalibrationEquation::ArbitraryBaseExponential,
+        3 => CalibrationEquation::HyperbolicSine,
+        _ => return Err(Error::new(116)),
+    };
+    if x0 == x1 || fixed[9] as usize != equation.num_params() {
+        return Err(Error::new(116));
+    }
+    /*unit name, then the parameters, all separated by nul*/
+    let mut strings = data[length + 11..].split(|&c| c == 0);
+    let unit = strings.next().ok_or(Error::new(116))?;
+    let mut params = Vec::new();
+    params.try_reserve_exact(equation.num_params())?;
+    for p in strings {
+        params.push(parse_png_float(p).ok_or(Error::new(116))?);
+    }
+    if params.len() != equation.num_params() {
+        return Err(Error::new(116));
+    }
+    let mut name = Vec::new();
+    name.try_reserve_exact(length)?;
+    name.extend_from_slice(&data[..length]);
+    let mut unit_name = Vec::new();
+    unit_name.try_reserve_exact(unit.len())?;
+    unit_name.extend_from_slice(unit);
+    info.calibration = Some(PixelCalibration {
+        name: name.into_boxed_slice(), x0, x1, equation, unit: unit_name.into_boxed_slice(), params,
+    });
+    Ok(())
+}
+
+fn read_chunk_ster(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    info.stereo = Some(match data {
+        [0] => StereoLayout::CrossFuse,
+        [1] => StereoLayout::DivergingFuse,
+        _ => return Err(Error::new(117)),
+    });
+    Ok(())
+}
+
+fn read_chunk_gama(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    let data: [u8; 4] = data.try_into().map_err(|_| Error::new(103))?;
+    info.gamma = Some(u32::from_be_bytes(data));
+    Ok(())
+}
+
+fn read_chunk_chrm(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    if data.len() != 32 {
+        return Err(Error::new(104));
+    }
+    let v = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    info.chromaticities = Some(Chromaticities {
+        white_x: v(0), white_y: v(4),
+        red_x: v(8), red_y: v(12),
+        green_x: v(16), green_y: v(20),
+        blue_x: v(24), blue_y: v(28),
+    });
+    Ok(())
+}
+
+fn read_chunk_srgb(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    info.srgb_intent = Some(match data {
+        [0] => RenderingIntent::Perceptual,
+        [1] => RenderingIntent::RelativeColorimetric,
+        [2] => RenderingIntent::Saturation,
+        [3] => RenderingIntent::AbsoluteColorimetric,
+        _ => return Err(Error::new(105)),
+    });
+    Ok(())
+}
+
+fn read_chunk_cicp(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    /*only RGB (matrix 0) is allowed in PNG*/
+    let &[color_primaries, transfer_function, 0, range @ (0 | 1)] = data else {
+        return Err(Error::new(107));
+    };
+    info.cicp = Some(Cicp { color_primaries, transfer_function, matrix_coefficients: 0, video_full_range: range == 1 });
+    Ok(())
+}
+
+fn read_chunk_mdcv(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    if data.len() != 24 {
+        return Err(Error::new(108));
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    info.mastering_display = Some(MasteringDisplay {
+        primaries: [(u16_at(0), u16_at(2)), (u16_at(4), u16_at(6)), (u16_at(8), u16_at(10))],
+        white_point: (u16_at(12), u16_at(14)),
+        max_luminance: u32_at(16),
+        min_luminance: u32_at(20),
+    });
+    Ok(())
+}
+
+fn read_chunk_clli(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    if data.len() != 8 {
+        return Err(Error::new(109));
+    }
+    info.content_light_level = Some(ContentLightLevel {
+        max_cll: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        max_fall: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+    });
+    Ok(())
+}
+
+fn read_chunk_splt(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    let length = data.iter().take(80).position(|&c| c == 0).ok_or(Error::new(75))?;
+    if length < 1 {
+        return Err(Error::new(89));
+    }
+    let sample_depth = data.get(length + 1).copied().ok_or(Error::new(112))?;
+    let entries = &data[length + 2..];
+    let entry_size = match sample_depth {
+        8 => 6,
+        16 => 10,
+        _ => return Err(Error::new(112)),
+    };
+    if entries.len() % entry_size != 0 {
+        return Err(Error::new(112));
+    }
+    let mut palette = Vec::new();
+    palette.try_reserve_exact(entries.len() / entry_size)?;
+    palette.extend(entries.chunks_exact(entry_size).map(|e| {
+        let sample = |i: usize| if sample_depth == 8 { e[i].into() } else { u16::from_be_bytes([e[i * 2], e[i * 2 + 1]]) };
+        SuggestedColor {
+            color: rgb::RGBA16::new(sample(0), sample(1), sample(2), sample(3)),
+            frequency: u16::from_be_bytes([e[entry_size - 2], e[entry_size - 1]]),
+        }
+    }));
+    let mut name = Vec::new();
+    name.try_reserve_exact(length)?;
+    name.extend_from_slice(&data[..length]);
+    info.suggested_palettes.try_reserve(1)?;
+    info.suggested_palettes.push(SuggestedPalette { name: name.into_boxed_slice(), sample_depth, entries: palette });
+    Ok(())
+}
+
+/*hIST comes after PLTE, so the palette is already known*/
+fn read_chunk_hist(info: &mut Info, data: &[u8]) -> Result<(), Error> {
+    if info.color.palette().is_empty() || data.len() != info.color.palette().len() * 2 {
+        return Err(Error::new(113));
+    }
+    info.histogram.clear();
+    info.histogram.try_reserve_exact(data.len() / 2)?;
+    info.histogram.extend(data.chunks_exact(2).map(|f| u16::from_be_bytes([f[0], f[1]])));
+    Ok(())
+}
+
+fn read_chunk_iccp(info: &mut Info, zlibsettings: &DecompressSettings, max_size: usize, data: &[u8]) -> Result<(), Error> {
+    let length = data.iter().take(80).position(|&c| c == 0).ok_or(Error::new(75))?;
+    if length < 1 {
+        return Err(Error::new(89));
+    }
+    /*the compression method must be 0*/
+    if data.get(length + 1).copied() != Some(0) {
+        return Err(Error::new(72));
+    }
+    let profile = zlib::decompress_limited(&data[length + 2..], zlibsettings, max_size)?;
+    let mut name = Vec::new();
+    name.try_reserve_exact(length)?;
+    name.extend_from_slice(&data[..length]);
+    info.icc_profile = Some(IccProfile { name: name.into_boxed_slice(), data: profile });
+    Ok(())
+}
+
 #[inline(never)]
 fn add_chunk_idat(out: &mut Vec<u8>, inp: &[u8], w: u32, h: u32, info_png: &Info, settings: &EncoderSettings, zlibsettings: &CompressSettings) -> Result<(), Error> {
     let mut ch = ChunkBuilder::new(out, b"IDAT");
+    compress_image_data(&mut ch, inp, w, h, info_png, settings, zlibsettings)?;
+    ch.finish()
+}
 
+/*filters and compresses the image, writing the zlib stream that goes into IDAT (or fdAT) chunks*/
+pub(crate) fn compress_image_data(out: &mut dyn Write, inp: &[u8], w: u32, h: u32, info_png: &Info, settings: &EncoderSettings, zlibsettings: &CompressSettings) -> Result<(), Error> {
     #[allow(deprecated)]
     if let Some(cb) = zlibsettings.custom_zlib {
         let mut tmp = Vec::new();
         filtered_scanlines(&mut tmp, inp, w, h, info_png, settings)?;
-        (cb)(&tmp, &mut ch, zlibsettings)?;
+        (cb)(&tmp, out, zlibsettings)?;
     } else {
-        let mut z = zlib::new_compressor(ch, zlibsettings);
+        #[cfg(feature = "parallel")]
+        if let Some(bands) = parallel::bands(w, h, info_png, settings) {
+            for piece in parallel::compress_bands(inp, w, h, &bands, &info_png.color, settings, zlibsettings, true)? {
+                out.write_all(&piece)?;
+            }
+            return Ok(());
+        }
+        let mut z = zlib::new_compressor(out, zlibsettings);
         filtered_scanlines(&mut z, inp, w, h, info_png, settings)?;
-        ch = z.finish()?;
+        z.finish()?;
     }
-    ch.finish()
+    Ok(())
 }
 
 fn add_chunk_iend(out: &mut Vec<u8>) -> Result<(), Error> {
@@ -1376,6 +1659,109 @@ fn add_chunk_plte(out: &mut Vec<u8>, info: &ColorMode) -> Result<(), Error> {
     plte.finish()
 }
 
+fn add_chunk_gama(out: &mut Vec<u8>, gamma: u32) -> Result<(), Error> {
+    let mut c = ChunkBuilder::new(out, b"gAMA");
+    c.write_u32be(gamma);
+    c.finish()
+}
+
+fn add_chunk_chrm(out: &mut Vec<u8>, chrm: &Chromaticities) -> Result<(), Error> {
+    let mut c = ChunkBuilder::new(out, b"cHRM");
+    for v in [chrm.white_x, chrm.white_y, chrm.red_x, chrm.red_y, chrm.green_x, chrm.green_y, chrm.blue_x, chrm.blue_y] {
+        c.write_u32be(v);
+    }
+    c.finish()
+}
+
+fn add_chunk_srgb(out: &mut Vec<u8>, intent: RenderingIntent) -> Result<(), Error> {
+    let mut c = ChunkBuilder::new(out, b"sRGB");
+    c.push(intent as u8);
+    c.finish()
+}
+
+fn add_chunk_iccp(out: &mut Vec<u8>, icc: &IccProfile, zlibsettings: &CompressSettings) -> Result<(), Error> {
+    if icc.name.is_empty() || icc.name.len() > 79 {
+        return Err(Error::new(89));
+    }
+    let mut c = ChunkBuilder::new(out, b"iCCP");
+    c.extend_from_slice(&icc.name)?;
+    c.push(0);
+    c.push(0);
+    zlib::compress_into(&mut c, &icc.data, zlibsettings)?;
+    c.finish()
+}
+
+fn add_chunk_cicp(out: &mut Vec<u8>, cicp: &Cicp) -> Result<(), Error> {
+    if cicp.matrix_coefficients != 0 {
+        return Err(Error::new(107));
+    }
+    let mut c = ChunkBuilder::new(out, b"cICP");
+    c.push(cicp.color_primaries);
+    c.push(cicp.transfer_function);
+    c.push(cicp.matrix_coefficients);
+    c.push(cicp.video_full_range.into());
+    c.finish()
+}
+
+fn add_chunk_mdcv(out: &mut Vec<u8>, mdcv: &MasteringDisplay) -> Result<(), Error> {
+    let mut c = ChunkBuilder::new(out, b"mDCv");
+    for (x, y) in mdcv.primaries.iter().chain([&mdcv.white_point]) {
+        c.write_u16be(*x);
+        c.write_u16be(*y);
+    }
+    c.write_u32be(mdcv.max_luminance);
+    c.write_u32be(mdcv.min_luminance);
+    c.finish()
+}
+
+fn add_chunk_clli(out: &mut Vec<u8>, clli: &ContentLightLevel) -> Result<(), Error> {
+    let mut c = ChunkBuilder::new(out, b"cLLi");
+    c.write_u32be(clli.max_cll);
+    c.write_u32be(clli.max_fall);
+    c.finish()
+}
+
+fn add_chunk_exif(out: &mut Vec<u8>, exif: &[u8]) -> Result<(), Error> {
+    if !exif::has_valid_header(exif) {
+        return Err(Error::new(110));
+    }
+    let mut c = ChunkBuilder::new(out, b"eXIf");
+    c.extend_from_slice(exif)?;
+    c.finish()
+}
+
+fn add_chunk_splt(out: &mut Vec<u8>, splt: &SuggestedPalette) -> Result<(), Error> {
+    if splt.name.is_empty() || splt.name.len() > 79 {
+        return Err(Error::new(89));
+    }
+    if splt.sample_depth != 8 && splt.sample_depth != 16 {
+        return Err(Error::new(112));
+    }
+    let mut c = ChunkBuilder::new(out, b"sPLT");
+    c.extend_from_slice(&splt.name)?;
+    c.push(0);
+    c.push(splt.sample_depth);
+    for e in &splt.entries {
+        for v in [e.color.r, e.color.g, e.color.b, e.color.a] {
+            if splt.sample_depth == 8 {
+                c.push(v as u8);
+            } else {
+                c.write_u16be(v);
+            }
+        }
+        c.write_u16be(e.frequency);
+    }
+    c.finish()
+}
+
+fn add_chunk_hist(out: &mut Vec<u8>, histogram: &[u16]) -> Result<(), Error> {
+    let mut c = ChunkBuilder::new(out, b"hIST");
+    for &f in histogram {
+        c.write_u16be(f);
+    }
+    c.finish()
+}
+
 fn add_chunk_time(out: &mut Vec<u8>, time: &Time) -> Result<(), Error> {
     let mut c = ChunkBuilder::new(out, b"tIME");
     c.write_u16be(time.year);
@@ -1397,6 +1783,168 @@ fn add_chunk_phys(out: &mut Vec<u8>, info: &Info) -> Result<(), Error> {
     data.finish()
 }
 
+fn add_chunk_sbit(out: &mut Vec<u8>, sbit: &SignificantBits, color: &ColorMode) -> Result<(), Error> {
+    let depth = if color.colortype == ColorType::PALETTE { 8 } else { color.bitdepth() as u8 };
+    let (values, len) = sbit.channels(color.colortype);
+    let values = &values[..len];
+    if values.iter().any(|&b| b < 1 || b > depth) {
+        return Err(Error::new(118));
+    }
+    let mut data = ChunkBuilder::new(out, b"sBIT");
+    data.extend_from_slice(values)?;
+    data.finish()
+}
+
+/*sBIT made valid for a color mode chosen by auto_convert*/
+pub(crate) fn fit_significant_bits(sbit: &SignificantBits, color: &ColorMode) -> SignificantBits {
+    let depth = if color.colortype == ColorType::PALETTE { 8 } else { color.bitdepth() as u8 };
+    let fit = |b: u8| if b == 0 { depth } else { b.min(depth) };
+    SignificantBits {
+        red: fit(sbit.red),
+        green: fit(sbit.green),
+        blue: fit(sbit.blue),
+        alpha: if color.is_alpha_type() { fit(sbit.alpha) } else { 0 },
+    }
+}
+
+/*Sets the color of pixels with alpha 0 in 8 and 16-bit RGBA and grey+alpha images. Returns None for other color modes.*/
+fn clean_alpha(inp: &[u8], w: u32, h: u32, mode: &ColorMode, cleanup: AlphaCleanup) -> Result<Option<Vec<u8>>, Error> {
+    let depth = mode.bitdepth() as usize;
+    if cleanup == AlphaCleanup::NONE || !matches!(mode.colortype, ColorType::RGBA | ColorType::GREY_ALPHA) || (depth != 8 && depth != 16) {
+        return Ok(None);
+    }
+    let sample = depth / 8;
+    let pixel = mode.channels() as usize * sample;
+    /*color bytes come before alpha bytes*/
+    let color_len = pixel - sample;
+    let w = w as usize;
+    let num_bytes = w * h as usize * pixel;
+    let is_transparent = |px: &[u8]| px[color_len..].iter().all(|&a| a == 0);
+
+    let key = if cleanup == AlphaCleanup::KEY {
+        /*greys of visible pixels, as 16-bit values*/
+        let mut used = vec![false; 1 << 16];
+        for px in inp[..num_bytes].chunks_exact(pixel).filter(|px| !is_transparent(px)) {
+            let samples = px[..color_len].chunks_exact(sample);
+            let value = |s: &[u8]| if sample == 2 { u16::from_be_bytes([s[0], s[1]]) } else { s[0] as u16 * 257 };
+            let mut greys = samples.map(value);
+            let first = greys.next().unwrap_or(0);
+            if greys.all(|v| v == first) {
+                used[first as usize] = true;
+            }
+        }
+        /*black and white fit every bit depth*/
+        let step = if sample == 2 { 1 } else { 257 };
+        [0u16, 65535].into_iter().chain((step..65535).step_by(step as usize)).find(|&v| !used[v as usize])
+    } else {
+        None
+    };
+    let key_bytes = key.unwrap_or(0).to_be_bytes();
+
+    let mut out = Vec::new();
+    out.try_reserve_exact(num_bytes)?;
+    out.extend_from_slice(&inp[..num_bytes]);
+    for i in 0..w * h as usize {
+        let start = i * pixel;
+        if !is_transparent(&out[start..start + pixel]) {
+            continue;
+        }
+        let source = match cleanup {
+            AlphaCleanup::LEFT if i % w > 0 => Some(start - pixel),
+            AlphaCleanup::UP if i >= w => Some(start - w * pixel),
+            _ => None,
+        };
+        match source {
+            Some(source) => out.copy_within(source..source + color_len, start),
+            None => for s in out[start..start + color_len].chunks_exact_mut(sample) {
+                s.copy_from_slice(&key_bytes[2 - sample..]);
+            },
+        }
+    }
+    Ok(Some(out))
+}
+
+/*Samples that have only sBIT significant bits are rescaled to use the full range of the bit depth,
+  rounded to the precision of `bits`. Only 8 and 16-bit non-palette images are rescaled, otherwise returns None.*/
+pub(crate) fn rescale_significant_bits(inp: &[u8], mode: &ColorMode, sbit: &SignificantBits, bits: u32, w: u32, h: u32) -> Result<Option<Vec<u8>>, Error> {
+    let depth = mode.bitdepth();
+    if mode.colortype == ColorType::PALETTE || (depth != 8 && depth != 16) {
+        return Ok(None);
+    }
+    let (channel_bits, channels) = sbit.channels(mode.colortype);
+    let precision = bits.min(depth);
+    let max_depth = (1u32 << depth) - 1;
+    let max_precision = (1u32 << precision) - 1;
+    let significant = channel_bits.map(|b| if b == 0 { depth } else { (b as u32).min(depth) });
+    if significant[..channels].iter().all(|&s| s == depth) && precision == depth {
+        return Ok(None);
+    }
+    let num_samples = w as usize * h as usize * channels;
+    let mut out = zero_vec(num_samples * depth as usize / 8)?;
+    for i in 0..num_samples {
+        let s = significant[i % channels];
+        let v = if depth == 16 { u16::from_be_bytes([inp[i * 2], inp[i * 2 + 1]]) as u32 } else { inp[i] as u32 };
+        let max_significant = (1u32 << s) - 1;
+        let v = v >> (depth - s);
+        let q = (v * max_precision + max_significant / 2) / max_significant;
+        let v = q * (max_depth / max_precision);
+        if depth == 16 {
+            out[i * 2..i * 2 + 2].copy_from_slice(&(v as u16).to_be_bytes());
+        } else {
+            out[i] = v as u8;
+        }
+    }
+    Ok(Some(out))
+}
+
+fn add_chunk_offs(out: &mut Vec<u8>, offset: &ImageOffset) -> Result<(), Error> {
+    if offset.x == i32::MIN || offset.y == i32::MIN {
+        return Err(Error::new(114));
+    }
+    let mut data = ChunkBuilder::new(out, b"oFFs");
+    data.extend_from_slice(&offset.x.to_be_bytes())?;
+    data.extend_from_slice(&offset.y.to_be_bytes())?;
+    data.push(offset.unit as u8);
+    data.finish()
+}
+
+fn add_chunk_scal(out: &mut Vec<u8>, scale: &PhysicalScale) -> Result<(), Error> {
+    if !(scale.pixel_width > 0. && scale.pixel_width.is_finite() && scale.pixel_height > 0. && scale.pixel_height.is_finite()) {
+        return Err(Error::new(115));
+    }
+    let mut data = ChunkBuilder::new(out, b"sCAL");
+    data.push(scale.unit as u8);
+    write!(data, "{}\0{}", scale.pixel_width, scale.pixel_height)?;
+    data.finish()
+}
+
+fn add_chunk_pcal(out: &mut Vec<u8>, cal: &PixelCalibration) -> Result<(), Error> {
+    if cal.name.is_empty() || cal.name.len() > 79 {
+        return Err(Error::new(89));
+    }
+    if cal.x0 == cal.x1 || cal.params.len() != cal.equation.num_params() || cal.unit.contains(&0) || !cal.params.iter().all(|p| p.is_finite()) {
+        return Err(Error::new(116));
+    }
+    let mut data = ChunkBuilder::new(out, b"pCAL");
+    data.extend_from_slice(&cal.name)?;
+    data.push(0);
+    data.extend_from_slice(&cal.x0.to_be_bytes())?;
+    data.extend_from_slice(&cal.x1.to_be_bytes())?;
+    data.push(cal.equation as u8);
+    data.push(cal.params.len() as u8);
+    data.extend_from_slice(&cal.unit)?;
+    for p in &cal.params {
+        write!(data, "\0{p}")?;
+    }
+    data.finish()
+}
+
+fn add_chunk_ster(out: &mut Vec<u8>, layout: StereoLayout) -> Result<(), Error> {
+    let mut data = ChunkBuilder::new(out, b"sTER");
+    data.push(layout as u8);
+    data.finish()
+}
+
 pub(crate) struct ChunkBuilder<'buf> {
     buf: &'buf mut Vec<u8>,
     buf_start: usize,
@@ -1499,17 +2047,17 @@ struct AdamConst {
     dy: u8,
 }
 
-struct AdamPass {
-    filtered_len: usize,
+pub(crate) struct AdamPass {
+    pub filtered_len: usize,
     padded_len: usize,
-    packed_len: usize,
-    w: u32,
-    h: u32,
+    pub packed_len: usize,
+    pub w: u32,
+    pub h: u32,
     adam: AdamConst,
 }
 
 #[inline]
-fn adam7_pass_values(w: u32, h: u32, bpp: NonZeroU8) -> impl Iterator<Item=AdamPass> + Clone {
+pub(crate) fn adam7_pass_values(w: u32, h: u32, bpp: NonZeroU8) -> impl Iterator<Item=AdamPass> + Clone {
     ADAM7.iter().map(move |adam| {
         let mut p_w = ((w as usize + adam.dx as usize - adam.ix as usize - 1) / adam.dx as usize) as u32;
         let mut p_h = ((h as usize + adam.dy as usize - adam.iy as usize - 1) / adam.dy as usize) as u32;
@@ -1548,40 +2096,41 @@ NOTE: comments about padding bits are only relevant if bpp < 8
 */
 #[cold]
 fn adam7_deinterlace(out: &mut [u8], inp: &[u8], w: u32, h: u32, bpp: NonZeroU8) {
-    let passes = adam7_pass_values(w, h, bpp);
-    let bpp = bpp.get();
-    let bytewidth = bpp / 8;
     let mut offset_packed = 0;
+    for pass in adam7_pass_values(w, h, bpp) {
+        adam7_deinterlace_pass(out, &inp[offset_packed..], w, bpp, &pass);
+        offset_packed += pass.packed_len;
+    }
+}
+
+/*puts pixels of one reduced image (without padding bits) at their place in the w*h image.
+  Same requirements for out as adam7_deinterlace*/
+pub(crate) fn adam7_deinterlace_pass(out: &mut [u8], inp: &[u8], w: u32, bpp: NonZeroU8, pass: &AdamPass) {
+    let adam = &pass.adam;
+    let bpp = bpp.get();
     if bpp >= 8 {
-        for pass in passes {
-            let adam = &pass.adam;
-            for y in 0..pass.h as usize {
-                for x in 0..pass.w as usize {
-                    let pixelinstart = offset_packed + (y * pass.w as usize + x) * bytewidth as usize;
-                    let pixeloutstart = ((adam.iy as usize + y * adam.dy as usize) * w as usize + adam.ix as usize + x * adam.dx as usize) * bytewidth as usize;
-                    out[pixeloutstart..(bytewidth as usize + pixeloutstart)].copy_from_slice(&inp[pixelinstart..(bytewidth as usize + pixelinstart)]);
-                }
+        let bytewidth = bpp as usize / 8;
+        for y in 0..pass.h as usize {
+            for x in 0..pass.w as usize {
+                let pixelinstart = (y * pass.w as usize + x) * bytewidth;
+                let pixeloutstart = ((adam.iy as usize + y * adam.dy as usize) * w as usize + adam.ix as usize + x * adam.dx as usize) * bytewidth;
+                out[pixeloutstart..(bytewidth + pixeloutstart)].copy_from_slice(&inp[pixelinstart..(bytewidth + pixelinstart)]);
             }
-            offset_packed += pass.packed_len;
         }
     } else {
-        for pass in passes {
-            let adam = &pass.adam;
-            let ilinebits = bpp as usize * pass.w as usize;
-            let olinebits = bpp as usize * w as usize;
-            for y in 0..pass.h as usize {
-                for x in 0..pass.w as usize {
-                    let mut ibp = (8 * offset_packed) + (y * ilinebits + x * bpp as usize);
-                    let mut obp = (adam.iy as usize + y * adam.dy as usize) *
-                        olinebits + (adam.ix as usize + x * adam.dx as usize) * bpp as usize;
-                    for _ in 0..bpp {
-                        let bit = read_bit_from_reversed_stream(ibp, inp); ibp += 1;
-                        /*note that this function assumes the out buffer is completely 0, use set_bit_of_reversed_stream otherwise*/
-                        set_bit_of_reversed_stream0(obp, out, bit); obp += 1;
-                    }
+        let ilinebits = bpp as usize * pass.w as usize;
+        let olinebits = bpp as usize * w as usize;
+        for y in 0..pass.h as usize {
+            for x in 0..pass.w as usize {
+                let mut ibp = y * ilinebits + x * bpp as usize;
+                let mut obp = (adam.iy as usize + y * adam.dy as usize) *
+                    olinebits + (adam.ix as usize + x * adam.dx as usize) * bpp as usize;
+                for _ in 0..bpp {
+                    let bit = read_bit_from_reversed_stream(ibp, inp); ibp += 1;
+                    /*note that this function assumes the out buffer is completely 0, use set_bit_of_reversed_stream otherwise*/
+                    set_bit_of_reversed_stream0(obp, out, bit); obp += 1;
                 }
             }
-            offset_packed += pass.packed_len;
         }
     }
 }
@@ -1590,7 +2139,7 @@ fn adam7_deinterlace(out: &mut [u8], inp: &[u8], w: u32, h: u32, bpp: NonZeroU8)
 /* / Reading and writing single bits and bytes from/to stream for LodePNG   / */
 /* ////////////////////////////////////////////////////////////////////////// */
 #[inline(always)]
-fn read_bit_from_reversed_stream(bitpointer: usize, bitstream: &[u8]) -> u8 {
+pub(crate) fn read_bit_from_reversed_stream(bitpointer: usize, bitstream: &[u8]) -> u8 {
     (bitstream[bitpointer >> 3] >> (7 - (bitpointer & 7))) & 1
 }
 
@@ -1602,7 +2151,7 @@ fn set_bit_of_reversed_stream0(bitpointer: usize, bitstream: &mut [u8], bit: u8)
     }
 }
 
-fn set_bit_of_reversed_stream(bitpointer: usize, bitstream: &mut [u8], bit: u8) {
+pub(crate) fn set_bit_of_reversed_stream(bitpointer: usize, bitstream: &mut [u8], bit: u8) {
     /*the current bit in bitstream may be 0 or 1 for this to work*/
     if bit == 0 {
         bitstream[bitpointer >> 3] &= (!(1 << (7 - (bitpointer & 7)))) as u8;
@@ -1628,7 +2177,7 @@ pub(crate) fn lodepng_chunk_generate_crc(chunk: &mut [u8]) {
 /* ////////////////////////////////////////////////////////////////////////// */
 /* / Color types and such                                                   / */
 /* ////////////////////////////////////////////////////////////////////////// */
-fn check_png_color_validity(colortype: ColorType, bd: u32) -> Result<(), Error> {
+pub(crate) fn check_png_color_validity(colortype: ColorType, bd: u32) -> Result<(), Error> {
     /*allowed color type / bits combination*/
     match colortype {
         ColorType::GREY => if !(bd == 1 || bd == 2 || bd == 4 || bd == 8 || bd == 16) {
@@ -1647,7 +2196,7 @@ fn check_png_color_validity(colortype: ColorType, bd: u32) -> Result<(), Error>
     Ok(())
 }
 /// Internally BGRA is allowed
-fn check_lode_color_validity(colortype: ColorType, bd: u32) -> Result<(), Error> {
+pub(crate) fn check_lode_color_validity(colortype: ColorType, bd: u32) -> Result<(), Error> {
     match colortype {
         ColorType::BGRA | ColorType::BGRX | ColorType::BGR if bd == 8 => {
             Ok(())
@@ -1783,7 +2332,9 @@ fn gray_palette<'a>(mode: &ColorMode, gray_pal: &'a mut [RGBA; 256]) -> &'a [RGB
     let colors = 1 << mode.bitdepth();
     gray_pal.iter_mut().enumerate().take(colors).for_each(|(value, pal)| {
         let t = ((value * 255) / (colors - 1)) as u8;
-        let a = if mode.key() == Some((t as u16, t as u16, t as u16)) {
+        /*the key uses the bit depth of the image*/
+        let v = value as u16;
+        let a = if mode.key() == Some((v, v, v)) {
             0
         } else {
             255
@@ -1804,7 +2355,7 @@ return value is error*/
   *) if adam7: 1) 7x unfilter 2) 7x remove padding bits 3) adam7_deinterlace
   NOTE: the in buffer will be overwritten with intermediate data!
   */
-fn postprocess_scanlines(mut inp: Vec<u8>, unfiltering_buffer: usize, w: u32, h: u32, info_png: &Info) -> Result<Vec<u8>, Error> {
+pub(crate) fn postprocess_scanlines(mut inp: Vec<u8>, unfiltering_buffer: usize, w: u32, h: u32, info_png: &Info) -> Result<Vec<u8>, Error> {
     let bpp = info_png.color.bpp_();
     let raw_size = info_png.color.raw_size_opt(w, h)?;
     Ok(if info_png.interlace_method == 0 {
@@ -1841,7 +2392,7 @@ fn postprocess_scanlines(mut inp: Vec<u8>, unfiltering_buffer: usize, w: u32, h:
 }
 
 #[inline(never)]
-fn unfilter_scanlines(mut inout: &mut [u8], input_offset: usize, w: u32, h: u32, bpp: NonZeroU8) -> Result<(), Error> {
+pub(crate) fn unfilter_scanlines(mut inout: &mut [u8], input_offset: usize, w: u32, h: u32, bpp: NonZeroU8) -> Result<(), Error> {
     let bytewidth = (bpp.get() + 7) / 8;
     let linebytes = linebytes_rounded(w, bpp);
     if linebytes == 0 {
@@ -1948,7 +2499,7 @@ fn filter_1<const N: usize>(out: &mut [u8], scanline: &[u8]) {
 }
 
 /// IDAT decompression ensures this is never needed
-fn unfilter_scanline_aliased(inout: &mut [u8], scanline_offset: usize, prevline: Option<&[u8]>, bytewidth: u8, filter_type: u8, length: usize) -> Option<()> {
+pub(crate) fn unfilter_scanline_aliased(inout: &mut [u8], scanline_offset: usize, prevline: Option<&[u8]>, bytewidth: u8, filter_type: u8, length: usize) -> Option<()> {
     let bytewidth = bytewidth as usize;
     // help the optimizer remove bounds checks
     if bytewidth > 8 || bytewidth < 1 || bytewidth > length || prevline.is_some_and(move |p| p.len() != length) || inout.len() < scanline_offset+bytewidth {
@@ -2058,7 +2609,7 @@ fn unfilter_scanline_aliased(inout: &mut [u8], scanline_offset: usize, prevline:
   only useful if (ilinebits - olinebits) is a value in the range 1..7
   */
 #[inline(never)]
-fn remove_padding_bits(out: &mut [u8], inp: &[u8], olinebits: usize, ilinebits: usize, h: u32) {
+pub(crate) fn remove_padding_bits(out: &mut [u8], inp: &[u8], olinebits: usize, ilinebits: usize, h: u32) {
     for y in 0..h as usize {
         let iline = y * ilinebits;
         let oline = y * olinebits;
@@ -2069,6 +2620,20 @@ fn remove_padding_bits(out: &mut [u8], inp: &[u8], olinebits: usize, ilinebits:
     }
 }
 
+/*copies a scanline with possible padding bits to line y of an image without padding bits*/
+pub(crate) fn set_image_line(out: &mut [u8], line: &[u8], linebits: usize, y: u32) {
+    if linebits % 8 == 0 {
+        let linebytes = linebits / 8;
+        out[y as usize * linebytes..][..linebytes].copy_from_slice(&line[..linebytes]);
+    } else {
+        let oline = y as usize * linebits;
+        for i in 0..linebits {
+            let bit = read_bit_from_reversed_stream(i, line);
+            set_bit_of_reversed_stream(oline + i, out, bit);
+        }
+    }
+}
+
 #[inline(never)]
 fn remove_padding_bits_aliased(inout: &mut [u8], out_off: usize, in_off: usize, olinebits: usize, ilinebits: usize, h: u32) {
     for y in 0..h as usize {
@@ -2154,7 +2719,7 @@ pub(crate) fn lodepng_inspect(decoder: &DecoderSettings, inp: &[u8], read_chunks
     }
     if ihdr.len() != 13 {
         /*error: header size must be 13 bytes*/
-        return Err(Error::new(94));
+        return Err(Error::new(94).with_mismatch(13, ihdr.len() as u64).in_chunk(*b"IHDR", 8));
     }
     /*read the values given in the header*/
     let w = u32::from_be_bytes(inp[16..][..4].try_into().unwrap());
@@ -2162,6 +2727,15 @@ pub(crate) fn lodepng_inspect(decoder: &DecoderSettings, inp: &[u8], read_chunks
     if w == 0 || h == 0 {
         return Err(Error::new(93));
     }
+    if w > decoder.limits.max_width {
+        return Err(Error::new(119));
+    }
+    if h > decoder.limits.max_height {
+        return Err(Error::new(120));
+    }
+    if w as u64 * h as u64 > decoder.limits.max_pixels {
+        return Err(Error::new(121));
+    }
     let bitdepth = inp[24];
     if bitdepth == 0 || bitdepth > 16 {
         return Err(Error::new(29));
@@ -2178,7 +2752,7 @@ pub(crate) fn lodepng_inspect(decoder: &DecoderSettings, inp: &[u8], read_chunks
     };
     info_png.interlace_method = inp[28];
     if !decoder.ignore_crc && !ihdr.check_crc() {
-        return Err(Error::new(57));
+        return Err(ihdr.crc_error().in_chunk(*b"IHDR", 8));
     }
     if info_png.interlace_method > 1 {
         /*error: only interlace methods 0 and 1 exist in the specification*/
@@ -2206,8 +2780,177 @@ pub(crate) fn lodepng_inspect(decoder: &DecoderSettings, inp: &[u8], read_chunks
     Ok((info_png, w, h))
 }
 
-/*read a PNG, the result will be in the same color type as the PNG (hence "generic")*/
-fn decode_generic(state: &mut State, inp: &[u8]) -> Result<(Vec<u8>, u32, u32), Error> {
+/*how much more decompressed text fits in Limits::max_text_size*/
+fn text_size_remaining(info: &Info, limits: &Limits) -> usize {
+    let used = info.texts.iter().map(|t| t.value.len()).sum::<usize>() +
+        info.itexts.iter().map(|t| t.value.len()).sum::<usize>() +
+        info.icc_profile.as_ref().map_or(0, |p| p.data.len());
+    usize::try_from(limits.max_text_size).unwrap_or(usize::MAX).saturating_sub(used)
+}
+
+/*anything after IEND is ignored with a warning*/
+pub(crate) fn check_trailing_data(info: &mut Info, decoder: &DecoderSettings, inp: &[u8], iend_end: usize) -> Result<(), Error> {
+    if iend_end < inp.len() {
+        warning::warn(info, decoder, WarningKind::TrailingData, None, iend_end)?;
+    }
+    Ok(())
+}
+
+/*position of the chunk's length field in the file, for error messages*/
+pub(crate) fn chunk_offset(inp: &[u8], ch: &ChunkRef<'_>) -> usize {
+    ch.whole_chunk_data().as_ptr() as usize - inp.as_ptr() as usize
+}
+
+/*checks length of a chunk before it's read*/
+pub(crate) fn check_chunk_length(decoder: &DecoderSettings, len: usize) -> Result<(), Error> {
+    /*error: chunk length larger than the max PNG chunk size*/
+    if len > (1 << 31) {
+        return Err(Error::new(63));
+    }
+    if len > decoder.limits.max_chunk_size as usize {
+        return Err(Error::new(123));
+    }
+    Ok(())
+}
+
+/*bytes allocated for decompressed scanlines of a w*h image, and the image converted to info_raw*/
+pub(crate) fn check_memory_limit(state: &State, w: u32, h: u32) -> Result<u64, Error> {
+    let info_png = &state.info_png;
+    let scanlines = if info_png.interlace_method == 0 {
+        info_png.color.raw_size_idat(w, h).map(|s| s + h as usize)
+    } else {
+        adam7_expected_size(&info_png.color, w, h)
+    }.ok_or(Error::new(92))?;
+    let convert = state.decoder.color_convert && !lodepng_color_mode_equal(&state.info_raw, &info_png.color);
+    let out = if convert { state.info_raw.raw_size_opt(w, h)? } else { 0 };
+    let bytes = scanlines as u64 + out as u64;
+    if bytes > state.decoder.limits.max_memory {
+        return Err(Error::new(122));
+    }
+    Ok(bytes)
+}
+
+/*reads any chunk that can appear between IHDR and IEND, and checks its CRC. IDAT is handled by the caller.
+  offset is the position of the chunk in the file, for warnings and errors.
+  A broken ancillary chunk is skipped with a warning. Sets unknown after the first unknown chunk*/
+pub(crate) fn read_chunk_checked(info: &mut Info, decoder: &DecoderSettings, critical_pos: &mut ChunkPosition, unknown: &mut bool, ch: ChunkRef<'_>, offset: usize) -> Result<(), Error> {
+    if !decoder.ignore_crc && !*unknown && !ch.check_crc() {
+        /*IEND has no data, so like an ancillary chunk its CRC can't make the image wrong*/
+        if !(ch.is_ancillary() || ch.is_type(b"IEND")) || decoder.is_fatal(WarningKind::ChecksumMismatch) {
+            return Err(ch.crc_error().in_chunk(ch.name(), offset));
+        }
+        return warning::warn(info, decoder, WarningKind::ChecksumMismatch, Some(ch.name()), offset);
+    }
+    if !ch.is_type(b"IEND") && !read_chunk_info(info, decoder, critical_pos, ch, offset).map_err(|e| e.in_chunk(ch.name(), offset))? {
+        *unknown = true;
+    }
+    Ok(())
+}
+
+/*a known ancillary chunk with invalid data leaves its field unset with a warning.
+  Running out of memory or over the limits is still an error*/
+fn check_ancillary_chunk(info: &mut Info, decoder: &DecoderSettings, res: Result<(), Error>, chunk_type: [u8; 4], offset: usize) -> Result<(), Error> {
+    match res {
+        Err(e) if !matches!(e.code().0, 83 | 124) => warning::warn(info, decoder, WarningKind::InvalidAncillaryChunk, Some(chunk_type), offset),
+        res => res,
+    }
+}
+
+/*Returns false if the chunk is unknown, in which case it's skipped or remembered*/
+fn read_chunk_info(info: &mut Info, decoder: &DecoderSettings, critical_pos: &mut ChunkPosition, ch: ChunkRef<'_>, offset: usize) -> Result<bool, Error> {
+    /*length of the data of the chunk, excluding the length bytes, chunk type and CRC bytes*/
+    let data = ch.data();
+    /*ancillary chunks that the spec requires before PLTE or IDAT are still read, as other decoders do*/
+    let must_precede = match &ch.name() {
+        b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"sBIT" | b"cICP" => Some(ChunkPosition::PLTE),
+        b"tRNS" | b"bKGD" | b"hIST" | b"pHYs" | b"sPLT" | b"oFFs" | b"pCAL" | b"sCAL" | b"sTER" | b"mDCv" | b"cLLi" => Some(ChunkPosition::IDAT),
+        _ => None,
+    };
+    if must_precede.is_some_and(|pos| *critical_pos as u8 >= pos as u8) {
+        warning::warn(info, decoder, WarningKind::ChunkOrder, Some(ch.name()), offset)?;
+    }
+    match &ch.name() {
+        b"PLTE" => {
+            read_chunk_plte(&mut info.color, data)?;
+            *critical_pos = ChunkPosition::PLTE;
+        },
+        b"tRNS" => {
+            read_chunk_trns(&mut info.color, data)?;
+        },
+        b"bKGD" => {
+            read_chunk_bkgd(info, data)?;
+        },
+        b"tEXt" => if decoder.read_text_chunks {
+            read_chunk_text(info, data)?;
+        },
+        b"zTXt" => if decoder.read_text_chunks {
+            read_chunk_ztxt(info, &decoder.zlibsettings, text_size_remaining(info, &decoder.limits), data)?;
+        },
+        b"iTXt" => if decoder.read_text_chunks {
+            read_chunk_itxt(info, &decoder.zlibsettings, text_size_remaining(info, &decoder.limits), data)?;
+        },
+        b"tIME" => {
+            read_chunk_time(info, data)?;
+            let t = &info.time;
+            if !(1..=12).contains(&t.month) || !(1..=31).contains(&t.day) || t.hour > 23 || t.minute > 59 || t.second > 60 {
+                warning::warn(info, decoder, WarningKind::InvalidTime, Some(ch.name()), offset)?;
+            }
+        },
+        b"pHYs" => {
+            read_chunk_phys(info, data)?;
+        },
+        b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"cICP" | b"mDCv" | b"cLLi" | b"sPLT" | b"hIST" |
+        b"oFFs" | b"sCAL" | b"pCAL" | b"sTER" | b"sBIT" => {
+            let res = match &ch.name() {
+                b"gAMA" => read_chunk_gama(info, data),
+                b"cHRM" => read_chunk_chrm(info, data),
+                b"sRGB" => read_chunk_srgb(info, data),
+                b"cICP" => read_chunk_cicp(info, data),
+                b"mDCv" => read_chunk_mdcv(info, data),
+                b"cLLi" => read_chunk_clli(info, data),
+                b"sPLT" => read_chunk_splt(info, data),
+                b"hIST" => read_chunk_hist(info, data),
+                b"oFFs" => read_chunk_offs(info, data),
+                b"sCAL" => read_chunk_scal(info, data),
+                b"pCAL" => read_chunk_pcal(info, data),
+                b"sTER" => read_chunk_ster(info, data),
+                b"sBIT" => read_chunk_sbit(info, data),
+                /*a custom zlib decoder is only used for the profile when it's asked for with get_icc*/
+                _ if decoder.zlibsettings.custom_zlib.is_some() => Ok(()),
+                _ => read_chunk_iccp(info, &decoder.zlibsettings, text_size_remaining(info, &decoder.limits), data),
+            };
+            check_ancillary_chunk(info, decoder, res, ch.name(), offset)?;
+            /*these used to be unknown chunks, so they're still kept for Info::get. The encoder prefers the typed fields*/
+            if decoder.remember_unknown_chunks {
+                info.push_unknown_chunk(*critical_pos, ch.whole_chunk_data())?;
+            }
+        },
+        /*eXIf with a broken header is kept only as an unknown chunk*/
+        b"eXIf" if exif::has_valid_header(data) => {
+            let mut exif = Vec::new();
+            exif.try_reserve_exact(data.len())?;
+            exif.extend_from_slice(data);
+            info.exif = Some(exif);
+            if decoder.remember_unknown_chunks {
+                info.push_unknown_chunk(*critical_pos, ch.whole_chunk_data())?;
+            }
+        },
+        _ => {
+            if !ch.is_ancillary() {
+                return Err(Error::new(69));
+            }
+            if decoder.remember_unknown_chunks {
+                info.push_unknown_chunk(*critical_pos, ch.whole_chunk_data())?;
+            }
+            return Ok(false);
+        },
+    }
+    Ok(true)
+}
+
+/*read a PNG, the result will be in the same color type as the PNG (hence "generic").
+  on_idat is called after every IDAT chunk has been given to the decompressor*/
+fn decode_generic(state: &mut State, inp: &[u8], mut on_idat: Option<&mut dyn FnMut(&mut State, &mut zlib::Decoder) -> Result<(), Error>>) -> Result<(Vec<u8>, u32, u32), Error> {
     let mut found_iend = false; /*the data from idat chunks*/
     /*for unknown chunk order*/
     let mut unknown = false;
@@ -2233,102 +2976,130 @@ fn decode_generic(state: &mut State, inp: &[u8]) -> Result<(Vec<u8>, u32, u32),
         data: inp.get(33..).ok_or(Error::new(27))?,
     };
 
-    /*predict output size, to allocate exact size for output buffer to avoid more dynamic allocation.
-      If the decompressed size does not match the prediction, the image must be corrupt.*/
-    let predict = if state.info_png.interlace_method == 0 {
-        /*The extra *h is added because this are the filter bytes every scanline starts with*/
-        state.info_png.color.raw_size_idat(w, h).ok_or(Error::new(91))? + h as usize
-    } else {
-        /*Adam-7 interlaced: predicted size is the sum of the 7 sub-images sizes*/
-        let color = &state.info_png.color;
-        adam7_expected_size(color, w, h).ok_or(Error::new(91))?
-    };
-
-    let bpp = state.info_png.color.bpp_();
-    let bytewidth = ((bpp.get() + 7) / 8) as usize;
-
-    // if unfiltering can shift the buffer by two lines, it can do unaliased unfiltering in-place
-    let unfiltering_buffer = if state.info_png.interlace_method == 0 { 2 * (1 + bytewidth * w as usize) } else { 0 };
-
-    let mut scanlines = Vec::new();
-    let capacity_required = predict + unfiltering_buffer;
-    let remainder = capacity_required % bytewidth;
-    // ensure the buffer is multiple of pixel size, so that it can be cheaply transmuted
-    scanlines.try_reserve_exact(capacity_required + if remainder == 0 {0} else {bytewidth - remainder})?;
-
-    scanlines.resize(unfiltering_buffer, 0);
-
-    let mut idat_decompressor = zlib::new_decompressor(scanlines, chunks.data.len(), &state.decoder.zlibsettings);
+    check_memory_limit(state, w, h)?;
+    /*created on the first IDAT chunk, unless iDOT allows decompressing the image data in two halves*/
+    let mut idat_decompressor = None;
+    let mut restart: Option<parallel::Restart> = None;
+    let mut halves = (Vec::new(), Vec::new());
 
     /*loop through the chunks, ignoring unknown chunks and stopping at IEND chunk.
       IDAT data is put at the start of the in buffer*/
     for ch in chunks {
         let ch = ch?;
-        /*length of the data of the chunk, excluding the length bytes, chunk type and CRC bytes*/
-        let data = ch.data();
-        match &ch.name() {
-            b"IDAT" => {
-                idat_decompressor.push(data)?;
-                critical_pos = ChunkPosition::IDAT;
-            },
-            b"IEND" => {
-                found_iend = true;
-            },
-            b"PLTE" => {
-                read_chunk_plte(&mut state.info_png.color, data)?;
-                critical_pos = ChunkPosition::PLTE;
-            },
-            b"tRNS" => {
-                read_chunk_trns(&mut state.info_png.color, data)?;
-            },
-            b"bKGD" => {
-                read_chunk_bkgd(&mut state.info_png, data)?;
-            },
-            b"tEXt" => if state.decoder.read_text_chunks {
-                read_chunk_text(&mut state.info_png, data)?;
-            },
-            b"zTXt" => if state.decoder.read_text_chunks {
-                read_chunk_ztxt(&mut state.info_png, &state.decoder.zlibsettings, data)?;
-            },
-            b"iTXt" => if state.decoder.read_text_chunks {
-                read_chunk_itxt(&mut state.info_png, &state.decoder.zlibsettings, data)?;
-            },
-            b"tIME" => {
-                read_chunk_time(&mut state.info_png, data)?;
-            },
-            b"pHYs" => {
-                read_chunk_phys(&mut state.info_png, data)?;
-            },
-            _ => {
-                if !ch.is_ancillary() {
-                    return Err(Error::new(69));
-                }
-                unknown = true;
-                if state.decoder.remember_unknown_chunks {
-                    state.info_png.push_unknown_chunk(critical_pos, ch.whole_chunk_data())?;
-                }
-            },
-        }
-        if !state.decoder.ignore_crc && !unknown && !ch.check_crc() {
-            return Err(Error::new(57));
+        let offset = chunk_offset(inp, &ch);
+        let at = |e: Error| e.in_chunk(ch.name(), offset);
+        check_chunk_length(&state.decoder, ch.len()).map_err(at)?;
+        if ch.is_type(b"IDAT") {
+            if !state.decoder.ignore_crc && !unknown && !ch.check_crc() {
+                return Err(at(ch.crc_error()));
+            }
+            critical_pos = ChunkPosition::IDAT;
+            if let Some(restart) = restart {
+                let half = if offset < restart.chunk_offset { &mut halves.0 } else { &mut halves.1 };
+                half.try_reserve(ch.len())?;
+                half.extend_from_slice(ch.data());
+                continue;
+            }
+            let idat = match &mut idat_decompressor {
+                Some(idat) => idat,
+                None => idat_decompressor.insert(IdatDecoder::new(&state.info_png, w, h, inp.len() - 33, &state.decoder.zlibsettings)?),
+            };
+            idat.zlib.push(ch.data()).map_err(at)?;
+            if let Some(on_idat) = &mut on_idat {
+                on_idat(state, &mut idat.zlib).map_err(at)?;
+            }
+        } else {
+            if ch.is_type(b"iDOT") && critical_pos != ChunkPosition::IDAT && on_idat.is_none() && parallel::can_split(state) {
+                restart = parallel::Restart::parse(ch.data(), offset, h);
+            }
+            read_chunk_checked(&mut state.info_png, &state.decoder, &mut critical_pos, &mut unknown, ch, offset)?;
         }
-        if found_iend {
+        if ch.is_type(b"IEND") {
+            found_iend = true;
+            check_trailing_data(&mut state.info_png, &state.decoder, inp, offset + ch.len() + 12)?;
             break;
         }
     }
-    if !found_iend && !state.decoder.ignore_crc {
-        return Err(Error::new(52));
+    if !found_iend {
+        warning::warn(&mut state.info_png, &state.decoder, WarningKind::MissingIend, None, inp.len())?;
     }
-    let scanlines = idat_decompressor.finish()?;
-    if scanlines.len() != predict + unfiltering_buffer {
-        /*decompressed size doesn't match prediction*/
-        return Err(Error::new(91));
-    }
-    let out = postprocess_scanlines(scanlines, unfiltering_buffer, w, h, &state.info_png)?;
+    let idat_decompressor = match idat_decompressor {
+        Some(idat) => idat,
+        None => {
+            if let Some(restart) = restart.filter(|_| !halves.0.is_empty() && !halves.1.is_empty()) {
+                let unfiltering_buffer = IdatDecoder::unfiltering_buffer(&state.info_png, w);
+                if let Some(scanlines) = parallel::inflate_halves(&halves.0, &halves.1, restart, w, h, &state.info_png, unfiltering_buffer) {
+                    /*unfiltering can't be split, because the first row of the second half depends on the last row of the first*/
+                    return Ok((postprocess_scanlines(scanlines?, unfiltering_buffer, w, h, &state.info_png)?, w, h));
+                }
+            }
+            /*the halves are parts of one zlib stream, which can also be decompressed normally*/
+            let mut idat = IdatDecoder::new(&state.info_png, w, h, halves.0.len() + halves.1.len(), &state.decoder.zlibsettings)?;
+            idat.zlib.push(&halves.0)?;
+            idat.zlib.push(&halves.1)?;
+            idat
+        },
+    };
+    let out = idat_decompressor.finish(w, h, &state.info_png)?;
     Ok((out, w, h))
 }
 
-fn adam7_expected_size(color: &ColorMode, w: u32, h: u32) -> Option<usize> {
+/*decompresses and unfilters image data of a w*h image, e.g. from IDAT chunks*/
+pub(crate) struct IdatDecoder {
+    pub zlib: zlib::Decoder,
+    predict: usize,
+    unfiltering_buffer: usize,
+}
+
+impl IdatDecoder {
+    pub fn new(info_png: &Info, w: u32, h: u32, zlib_data_size: usize, zlibsettings: &DecompressSettings) -> Result<Self, Error> {
+        /*predict output size, to allocate exact size for output buffer to avoid more dynamic allocation.
+          If the decompressed size does not match the prediction, the image must be corrupt.*/
+        let predict = if info_png.interlace_method == 0 {
+            /*The extra *h is added because this are the filter bytes every scanline starts with*/
+            info_png.color.raw_size_idat(w, h).ok_or(Error::new(91))? + h as usize
+        } else {
+            /*Adam-7 interlaced: predicted size is the sum of the 7 sub-images sizes*/
+            adam7_expected_size(&info_png.color, w, h).ok_or(Error::new(91))?
+        };
+
+        let bytewidth = ((info_png.color.bpp_().get() + 7) / 8) as usize;
+        let unfiltering_buffer = Self::unfiltering_buffer(info_png, w);
+
+        let mut scanlines = Vec::new();
+        let capacity_required = predict + unfiltering_buffer;
+        let remainder = capacity_required % bytewidth;
+        // ensure the buffer is multiple of pixel size, so that it can be cheaply transmuted
+        scanlines.try_reserve_exact(capacity_required + if remainder == 0 {0} else {bytewidth - remainder})?;
+
+        scanlines.resize(unfiltering_buffer, 0);
+
+        Ok(Self {
+            zlib: zlib::new_decompressor(scanlines, zlib_data_size, zlibsettings),
+            predict,
+            unfiltering_buffer,
+        })
+    }
+
+    /*space before the scanlines*/
+    pub fn unfiltering_buffer(info_png: &Info, w: u32) -> usize {
+        let bytewidth = ((info_png.color.bpp_().get() + 7) / 8) as usize;
+        // if unfiltering can shift the buffer by two lines, it can do unaliased unfiltering in-place
+        if info_png.interlace_method == 0 { 2 * (1 + bytewidth * w as usize) } else { 0 }
+    }
+
+    /*returns pixels in the PNG's color mode*/
+    pub fn finish(self, w: u32, h: u32, info_png: &Info) -> Result<Vec<u8>, Error> {
+        let scanlines = self.zlib.finish()?;
+        if scanlines.len() != self.predict + self.unfiltering_buffer {
+            /*decompressed size doesn't match prediction*/
+            return Err(Error::new(91).with_mismatch(self.predict as u64, (scanlines.len() - self.unfiltering_buffer) as u64));
+        }
+        postprocess_scanlines(scanlines, self.unfiltering_buffer, w, h, info_png)
+    }
+}
+
+pub(crate) fn adam7_expected_size(color: &ColorMode, w: u32, h: u32) -> Option<usize> {
     const fn div_ceil(x: u32, d: u8) -> u32 {
         ((x as u64 + (d as u64 - 1)) / d as u64) as u32
     }
@@ -2352,27 +3123,53 @@ fn adam7_expected_size(color: &ColorMode, w: u32, h: u32) -> Option<usize> {
     Some(predict)
 }
 
+/*sets info_raw to the PNG's color mode if color conversion is disabled.
+  Returns true if the decoded pixels need to be converted to info_raw*/
+pub(crate) fn needs_color_convert(state: &mut State) -> Result<bool, Error> {
+    if !state.decoder.color_convert {
+        /*store the info_png color settings on the info_raw so that the info_raw still reflects what colortype
+            the raw image has to the end user*/
+        state.info_raw = state.info_png.color.clone();
+        return Ok(false);
+    }
+    if lodepng_color_mode_equal(&state.info_raw, &state.info_png.color) {
+        return Ok(false);
+    }
+    /*TODO: check if this works according to the statement in the documentation: "The converter can convert
+        from greyscale input color type, to 8-bit greyscale or greyscale with alpha"*/
+    if !(state.info_raw.colortype == ColorType::RGB || state.info_raw.colortype == ColorType::RGBA) && (state.info_raw.bitdepth() != 8) {
+        return Err(Error::new(56)); /*unsupported color mode conversion*/
+    }
+    Ok(true)
+}
+
 #[inline(never)]
 pub(crate) fn lodepng_decode(state: &mut State, inp: &[u8]) -> Result<(Vec<u8>, u32, u32), Error> {
-    let (decoded, w, h) = decode_generic(state, inp)?;
+    let (decoded, w, h) = decode_generic(state, inp, None)?;
+    Ok((convert_decoded(state, decoded, w, h)?, w, h))
+}
 
-    if !state.decoder.color_convert || lodepng_color_mode_equal(&state.info_raw, &state.info_png.color) {
-        /*store the info_png color settings on the info_raw so that the info_raw still reflects what colortype
-            the raw image has to the end user*/
-        if !state.decoder.color_convert {
-            /*color conversion needed; sort of copy of the data*/
-            state.info_raw = state.info_png.color.clone();
+/*same as lodepng_decode, but gives access to the image data decompressed so far after every IDAT chunk*/
+pub(crate) fn lodepng_decode_with(state: &mut State, inp: &[u8], on_idat: &mut dyn FnMut(&mut State, &mut zlib::Decoder) -> Result<(), Error>) -> Result<(Vec<u8>, u32, u32), Error> {
+    let (decoded, w, h) = decode_generic(state, inp, Some(on_idat))?;
+    Ok((convert_decoded(state, decoded, w, h)?, w, h))
+}
+
+/*converts pixels in the PNG's color mode to info_raw, if needed*/
+pub(crate) fn convert_decoded(state: &mut State, mut decoded: Vec<u8>, w: u32, h: u32) -> Result<Vec<u8>, Error> {
+    let convert = needs_color_convert(state)?;
+    if let Some(sbit) = state.info_png.significant_bits.filter(|_| state.decoder.use_significant_bits && state.decoder.color_convert) {
+        let bits = if state.info_raw.colortype == ColorType::PALETTE { state.info_png.color.bitdepth() } else { state.info_raw.bitdepth() };
+        if let Some(rescaled) = rescale_significant_bits(&decoded, &state.info_png.color, &sbit, bits, w, h)? {
+            decoded = rescaled;
         }
-        Ok((decoded, w, h))
+    }
+    if !convert {
+        Ok(decoded)
     } else {
-        /*TODO: check if this works according to the statement in the documentation: "The converter can convert
-            from greyscale input color type, to 8-bit greyscale or greyscale with alpha"*/
-        if !(state.info_raw.colortype == ColorType::RGB || state.info_raw.colortype == ColorType::RGBA) && (state.info_raw.bitdepth() != 8) {
-            return Err(Error::new(56)); /*unsupported color mode conversion*/
-        }
         let mut out = zero_vec(state.info_raw.raw_size_opt(w, h)?)?;
         lodepng_convert(&mut out, &decoded, &state.info_raw, &state.info_png.color, w, h)?;
-        Ok((out, w, h))
+        Ok(out)
     }
 }
 
@@ -2385,10 +3182,34 @@ pub(crate) fn lodepng_decode_memory(inp: &[u8], colortype: ColorType, bitdepth:
 }
 
 #[inline]
-fn add_unknown_chunks(out: &mut Vec<u8>, data: &[u8]) -> Result<(), Error> {
+fn add_unknown_chunks(out: &mut Vec<u8>, data: &[u8], info: &Info) -> Result<(), Error> {
     debug_assert!(ChunksIter { data }.all(|ch| ch.is_ok()));
     out.try_reserve(data.len())?;
-    out.extend_from_slice(data);
+    for ch in (ChunksIter { data }) {
+        let ch = ch?;
+        /*copies of chunks that are written from the typed fields of Info*/
+        let duplicate = match &ch.name() {
+            b"gAMA" => info.gamma.is_some(),
+            b"cHRM" => info.chromaticities.is_some(),
+            b"sRGB" => info.srgb_intent.is_some(),
+            b"iCCP" => info.icc_profile.is_some(),
+            b"cICP" => info.cicp.is_some(),
+            b"mDCv" => info.mastering_display.is_some(),
+            b"cLLi" => info.content_light_level.is_some(),
+            b"eXIf" => info.exif.is_some(),
+            b"sPLT" => !info.suggested_palettes.is_empty(),
+            b"hIST" => !info.histogram.is_empty(),
+            b"oFFs" => info.offset.is_some(),
+            b"sCAL" => info.physical_scale.is_some(),
+            b"pCAL" => info.calibration.is_some(),
+            b"sTER" => info.stereo.is_some(),
+            b"sBIT" => info.significant_bits.is_some(),
+            _ => false,
+        };
+        if !duplicate {
+            out.extend_from_slice(ch.whole_chunk_data());
+        }
+    }
     Ok(())
 }
 
@@ -2404,52 +3225,156 @@ pub(crate) fn lodepng_encode(image: &[u8], w: u32, h: u32, state: &State) -> Res
     if (info.color.colortype == ColorType::PALETTE || state.encoder.force_palette) && (info.color.palette().is_empty() || info.color.palette().len() > 256) {
         return Err(Error::new(68));
     }
+    let rescaled;
+    let cleaned;
+    let mut image = image;
+    if let Some(c) = clean_alpha(image, w, h, &state.info_raw, state.encoder.alpha_cleanup)? {
+        cleaned = c;
+        image = &cleaned;
+    }
     if state.encoder.auto_convert {
+        /*samples with fewer significant bits may fit in a lower bit depth after rounding*/
+        if let Some(sbit) = &info.significant_bits {
+            let (channel_bits, channels) = sbit.channels(state.info_raw.colortype);
+            let max_bits = channel_bits[..channels].iter().copied().max().unwrap_or(0) as u32;
+            let bits = [1, 2, 4, 8, 16].into_iter().find(|&b| b >= max_bits).unwrap_or(16);
+            if bits < state.info_raw.bitdepth() {
+                if let Some(r) = rescale_significant_bits(image, &state.info_raw, sbit, bits, w, h)? {
+                    rescaled = r;
+                    image = &rescaled;
+                }
+            }
+        }
         info.color = auto_choose_color(image, w, h, &state.info_raw)?;
+        if info.color.colortype == ColorType::PALETTE {
+            sort_palette(&mut info.color, image, w, h, &state.info_raw, state.encoder.palette_sort)?;
+            if info.background_defined && state.info_png.color.colortype == ColorType::PALETTE {
+                /*bKGD is an index into the palette*/
+                let background = state.info_png.color.palette().get(info.background_r as usize);
+                if let Some(i) = background.and_then(|bg| info.color.palette().iter().position(|p| p == bg)) {
+                    info.background_r = i as u16;
+                }
+            }
+        }
+        if !lodepng_color_mode_equal(&info.color, &state.info_png.color) {
+            /*the histogram belongs to the old palette*/
+            info.histogram.clear();
+            info.significant_bits = info.significant_bits.map(|sbit| fit_significant_bits(&sbit, &info.color));
+        }
     }
     if state.info_png.interlace_method > 1 {
         return Err(Error::new(71));
     }
     check_png_color_validity(info.color.colortype, info.color.bitdepth())?; /*tEXt and/or zTXt */
     check_lode_color_validity(state.info_raw.colortype, state.info_raw.bitdepth())?; /*LodePNG version id in text chunk */
+    if state.encoder.add_histogram && info.histogram.is_empty() && info.color.colortype == ColorType::PALETTE {
+        info.histogram = palette_histogram(image, w, h, &state.info_raw, &info.color)?;
+    }
 
     let mut outv = Vec::new(); outv.try_reserve(1024 + w as usize * h as usize / 2)?;
-    write_signature(&mut outv);
+    add_chunks_before_idat(&mut outv, w, h, &info, &state.encoder)?;
 
-    add_chunk_ihdr(&mut outv, w, h, info.color.colortype, info.color.bitdepth() as u8, info.interlace_method)?;
-    add_unknown_chunks(&mut outv, &info.unknown_chunks[ChunkPosition::IHDR as usize])?;
-    if info.color.colortype == ColorType::PALETTE {
-        add_chunk_plte(&mut outv, &info.color)?;
+    let mut converted;
+    if !lodepng_color_mode_equal(&state.info_raw, &info.color) {
+        let raw_size = h as usize * linebytes_rounded(w, info.color.bpp_());
+        converted = zero_vec(raw_size)?;
+        lodepng_convert(&mut converted, image, &info.color, &state.info_raw, w, h)?;
+        image = &converted;
+    }
+    #[allow(deprecated)]
+    let custom_zlib = state.encoder.zlibsettings.custom_zlib.is_some();
+    if state.encoder.add_idot && info.interlace_method == 0 && h >= 2 && !custom_zlib && state.encoder.filter_strategy != FilterStrategy::PREDEFINED {
+        parallel::add_chunks_idat_split(&mut outv, image, w, h, &info, &state.encoder)?;
+    } else {
+        add_chunk_idat(&mut outv, image, w, h, &info, &state.encoder, &state.encoder.zlibsettings)?;
+    }
+
+    add_chunks_after_idat(&mut outv, &info, &state.encoder)?;
+    Ok(outv)
+}
+
+/*writes the signature, IHDR and all chunks that go before IDAT*/
+pub(crate) fn add_chunks_before_idat(outv: &mut Vec<u8>, w: u32, h: u32, info: &Info, encoder: &EncoderSettings) -> Result<(), Error> {
+    if info.srgb_intent.is_some() && info.icc_profile.is_some() {
+        return Err(Error::new(106));
+    }
+    write_signature(outv);
+
+    add_chunk_ihdr(outv, w, h, info.color.colortype, info.color.bitdepth() as u8, info.interlace_method)?;
+    add_unknown_chunks(outv, &info.unknown_chunks[ChunkPosition::IHDR as usize], info)?;
+    /*color space chunks must be before PLTE*/
+    if let Some(sbit) = &info.significant_bits {
+        add_chunk_sbit(outv, sbit, &info.color)?;
     }
-    if state.encoder.force_palette && (info.color.colortype == ColorType::RGB || info.color.colortype == ColorType::RGBA) {
-        add_chunk_plte(&mut outv, &info.color)?;
+    if let Some(chrm) = &info.chromaticities {
+        add_chunk_chrm(outv, chrm)?;
+    }
+    if let Some(gamma) = info.gamma {
+        add_chunk_gama(outv, gamma)?;
+    }
+    if let Some(icc) = &info.icc_profile {
+        add_chunk_iccp(outv, icc, &encoder.zlibsettings)?;
+    }
+    if let Some(intent) = info.srgb_intent {
+        add_chunk_srgb(outv, intent)?;
+    }
+    if let Some(cicp) = &info.cicp {
+        add_chunk_cicp(outv, cicp)?;
+    }
+    if let Some(mdcv) = &info.mastering_display {
+        add_chunk_mdcv(outv, mdcv)?;
+    }
+    if let Some(clli) = &info.content_light_level {
+        add_chunk_clli(outv, clli)?;
+    }
+    let has_plte = info.color.colortype == ColorType::PALETTE ||
+        (encoder.force_palette && (info.color.colortype == ColorType::RGB || info.color.colortype == ColorType::RGBA));
+    if has_plte {
+        add_chunk_plte(outv, &info.color)?;
     }
     if info.color.colortype == ColorType::PALETTE && get_palette_translucency(info.color.palette()) != PaletteTranslucency::Opaque {
-        add_chunk_trns(&mut outv, &info.color)?;
+        add_chunk_trns(outv, &info.color)?;
     }
     if (info.color.colortype == ColorType::GREY || info.color.colortype == ColorType::RGB) && info.color.key().is_some() {
-        add_chunk_trns(&mut outv, &info.color)?;
+        add_chunk_trns(outv, &info.color)?;
     }
     if info.background_defined {
-        add_chunk_bkgd(&mut outv, &info)?;
+        add_chunk_bkgd(outv, info)?;
     }
     if info.phys_defined {
-        add_chunk_phys(&mut outv, &info)?;
+        add_chunk_phys(outv, info)?;
     }
-    add_unknown_chunks(&mut outv, &info.unknown_chunks[ChunkPosition::PLTE as usize])?;
-
-    let mut converted;
-    let mut image = image;
-    if !lodepng_color_mode_equal(&state.info_raw, &info.color) {
-        let raw_size = h as usize * linebytes_rounded(w, info.color.bpp_());
-        converted = zero_vec(raw_size)?;
-        lodepng_convert(&mut converted, image, &info.color, &state.info_raw, w, h)?;
-        image = &converted;
+    if let Some(offset) = &info.offset {
+        add_chunk_offs(outv, offset)?;
+    }
+    if let Some(scale) = &info.physical_scale {
+        add_chunk_scal(outv, scale)?;
+    }
+    if let Some(cal) = &info.calibration {
+        add_chunk_pcal(outv, cal)?;
+    }
+    if let Some(layout) = info.stereo {
+        add_chunk_ster(outv, layout)?;
+    }
+    if let Some(exif) = &info.exif {
+        add_chunk_exif(outv, exif)?;
     }
-    add_chunk_idat(&mut outv, image, w, h, &info, &state.encoder, &state.encoder.zlibsettings)?;
+    for splt in &info.suggested_palettes {
+        add_chunk_splt(outv, splt)?;
+    }
+    if !info.histogram.is_empty() {
+        if !has_plte || info.histogram.len() != info.color.palette().len() {
+            return Err(Error::new(113));
+        }
+        add_chunk_hist(outv, &info.histogram)?;
+    }
+    add_unknown_chunks(outv, &info.unknown_chunks[ChunkPosition::PLTE as usize], info)
+}
 
+/*writes all chunks after IDAT, including IEND*/
+pub(crate) fn add_chunks_after_idat(outv: &mut Vec<u8>, info: &Info, encoder: &EncoderSettings) -> Result<(), Error> {
     if info.time_defined {
-        add_chunk_time(&mut outv, &info.time)?;
+        add_chunk_time(outv, &info.time)?;
     }
     for t in &info.texts {
         if t.key.len() > 79 {
@@ -2458,17 +3383,17 @@ pub(crate) fn lodepng_encode(image: &[u8], w: u32, h: u32, state: &State) -> Res
         if t.key.is_empty() {
             return Err(Error::new(67));
         }
-        if state.encoder.text_compression {
-            add_chunk_ztxt(&mut outv, &t.key, &t.value, &state.encoder.zlibsettings)?;
+        if encoder.text_compression {
+            add_chunk_ztxt(outv, &t.key, &t.value, &encoder.zlibsettings)?;
         } else {
-            add_chunk_text(&mut outv, &t.key, &t.value)?;
+            add_chunk_text(outv, &t.key, &t.value)?;
         }
     }
-    if state.encoder.add_id {
+    if encoder.add_id {
         let alread_added_id_text = info.texts.iter().any(|t| *t.key == b"LodePNG"[..]);
         if !alread_added_id_text {
             /*it's shorter as tEXt than as zTXt chunk*/
-            add_chunk_text(&mut outv, b"LodePNG", LODEPNG_VERSION_STRING)?;
+            add_chunk_text(outv, b"LodePNG", LODEPNG_VERSION_STRING)?;
         }
     }
     for (k, l, t, s) in info.itext_keys() {
@@ -2478,11 +3403,10 @@ pub(crate) fn lodepng_encode(image: &[u8], w: u32, h: u32, state: &State) -> Res
         if k.is_empty() {
             return Err(Error::new(67));
         }
-        add_chunk_itxt(&mut outv, state.encoder.text_compression, k, l, t, s, &state.encoder.zlibsettings)?;
+        add_chunk_itxt(outv, encoder.text_compression, k, l, t, s, &encoder.zlibsettings)?;
     }
-    add_unknown_chunks(&mut outv, &info.unknown_chunks[ChunkPosition::IDAT as usize])?;
-    add_chunk_iend(&mut outv)?;
-    Ok(outv)
+    add_unknown_chunks(outv, &info.unknown_chunks[ChunkPosition::IDAT as usize], info)?;
+    add_chunk_iend(outv)
 }
 
 /*profile must already have been inited with mode.
@@ -2570,7 +3494,7 @@ fn get_color_profile_low_bpp(inp: &[u8], w: u32, h: u32, mode: &ColorMode) -> Co
     let mut profile = ColorProfile::new();
     profile.bits = if mode.colortype == ColorType::PALETTE { 8 } else { 1 };
     profile.numcolors = numcolors;
-    for ((i, px), _) in palette.iter().enumerate().zip(used).filter(|&(_, used)| used) {
+    for (i, (px, _)) in palette.iter().zip(used).filter(|&(_, used)| used).enumerate() {
         profile.palette[i] = *px;
         if profile.bits < 8 {
             let bits = get_value_required_bits(px.r);
@@ -2581,7 +3505,8 @@ fn get_color_profile_low_bpp(inp: &[u8], w: u32, h: u32, mode: &ColorMode) -> Co
         if px.r != px.g || px.r != px.b {
             profile.colored = true;
         }
-        if px.a != 255 {
+        /*transparency of grey images is a key*/
+        if px.a != 255 && mode.colortype == ColorType::PALETTE {
             profile.alpha = true;
         }
     }
@@ -2595,6 +3520,93 @@ fn get_color_profile_low_bpp(inp: &[u8], w: u32, h: u32, mode: &ColorMode) -> Co
     profile
 }
 
+/*counts uses of every palette entry, scaled so that the most used color has 65535, and every used color at least 1*/
+fn palette_histogram(image: &[u8], w: u32, h: u32, mode_in: &ColorMode, palette_mode: &ColorMode) -> Result<Vec<u16>, Error> {
+    let indices = palette_indices(image, w, h, mode_in, palette_mode)?;
+    let mut counts = [0u64; 256];
+    for &i in indices.iter() {
+        counts[i as usize] += 1;
+    }
+    let max = counts.iter().copied().max().unwrap_or(0).max(1);
+    let mut histogram = Vec::new();
+    histogram.try_reserve_exact(palette_mode.palette().len())?;
+    histogram.extend(counts[..palette_mode.palette().len()].iter().map(|&c| {
+        if c == 0 { 0 } else { (c * 65535 / max).max(1) as u16 }
+    }));
+    Ok(histogram)
+}
+
+/*one byte per pixel with its index in the palette*/
+fn palette_indices<'a>(image: &'a [u8], w: u32, h: u32, mode_in: &ColorMode, palette_mode: &ColorMode) -> Result<Cow<'a, [u8]>, Error> {
+    let mut mode8 = palette_mode.clone();
+    mode8.set_bitdepth(8);
+    if lodepng_color_mode_equal(mode_in, &mode8) {
+        return Ok(Cow::Borrowed(image));
+    }
+    let mut converted = zero_vec(w as usize * h as usize)?;
+    lodepng_convert(&mut converted, image, &mode8, mode_in, w, h)?;
+    Ok(Cow::Owned(converted))
+}
+
+/*reorders the palette of palette_mode, which must have all colors of the image*/
+pub(crate) fn sort_palette(palette_mode: &mut ColorMode, image: &[u8], w: u32, h: u32, mode_in: &ColorMode, sort: PaletteSort) -> Result<(), Error> {
+    let palette = palette_mode.palette().to_vec();
+    let mut order: Vec<usize> = (0..palette.len()).collect();
+    match sort {
+        PaletteSort::NONE => return Ok(()),
+        PaletteSort::ALPHA => {},
+        PaletteSort::LUMINANCE => order.sort_by_key(|&i| {
+            let c = palette[i];
+            299 * c.r as u32 + 587 * c.g as u32 + 114 * c.b as u32
+        }),
+        PaletteSort::FREQUENCY | PaletteSort::NEIGHBORS => {
+            let indices = palette_indices(image, w, h, mode_in, palette_mode)?;
+            let mut counts = vec![0u64; palette.len()];
+            for &i in indices.iter() {
+                counts[i as usize] += 1;
+            }
+            order.sort_by_key(|&i| std::cmp::Reverse(counts[i]));
+            if sort == PaletteSort::NEIGHBORS {
+                order = neighbor_order(&indices, w as usize, &counts, order[0]);
+            }
+        },
+    }
+    /*stable sort keeps the order within transparent and opaque entries*/
+    order.sort_by_key(|&i| palette[i].a == 255);
+    palette_mode.palette_clear();
+    for i in order {
+        palette_mode.palette_add(palette[i])?;
+    }
+    Ok(())
+}
+
+/*nearest-neighbor traversal: each next color is the one most often left or above of the previous color, or vice versa*/
+fn neighbor_order(indices: &[u8], w: usize, counts: &[u64], first: usize) -> Vec<usize> {
+    let n = counts.len();
+    let mut pairs = vec![0u64; n * n];
+    for (pos, &c) in indices.iter().enumerate() {
+        let c = c as usize;
+        let left = if pos % w > 0 { Some(indices[pos - 1]) } else { None };
+        let up = pos.checked_sub(w).map(|up| indices[up]);
+        for other in [left, up].into_iter().flatten().map(usize::from).filter(|&o| o != c) {
+            pairs[c * n + other] += 1;
+            pairs[other * n + c] += 1;
+        }
+    }
+    let mut visited = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    let mut last = first;
+    loop {
+        visited[last] = true;
+        order.push(last);
+        /*ties go to the more used color*/
+        match (0..n).filter(|&i| !visited[i]).max_by_key(|&i| (pairs[last * n + i], counts[i], std::cmp::Reverse(i))) {
+            Some(next) => last = next,
+            None => return order,
+        }
+    }
+}
+
 #[inline(never)]
 pub(crate) fn get_color_profile(inp: &[u8], w: u32, h: u32, mode: &ColorMode) -> ColorProfile {
     let numpixels: usize = w as usize * h as usize;
@@ -2693,10 +3705,8 @@ Updates values of mode with a potentially smaller color model. mode_out should
 contain the user chosen color model, but will be overwritten with the new chosen one.*/
 #[inline(never)]
 pub(crate) fn auto_choose_color(image: &[u8], w: u32, h: u32, mode_in: &ColorMode) -> Result<ColorMode, Error> {
-    let mut mode_out = ColorMode::new();
     let mut prof = get_color_profile(image, w, h, mode_in);
 
-    mode_out.clear_key();
     if prof.key && w * h <= 16 {
         prof.alpha = true;
         prof.key = false;
@@ -2719,39 +3729,60 @@ pub(crate) fn auto_choose_color(image: &[u8], w: u32, h: u32, mode_in: &ColorMod
         (w as usize * h as usize >= (n * 2) as usize) &&
         (prof.colored || prof.bits > palettebits);
     if palette_ok {
-        let pal = &prof.palette[0..prof.numcolors as usize];
-        /*remove potential earlier palette*/
-        mode_out.palette_clear();
-        for p in pal {
-            mode_out.palette_add(*p)?;
-        }
-        mode_out.colortype = ColorType::PALETTE;
-        mode_out.try_set_bitdepth(palettebits.into())?;
+        let mode_out = palette_color_mode(&prof.palette[0..prof.numcolors as usize])?;
         if mode_in.colortype == ColorType::PALETTE && mode_in.palette().len() >= mode_out.palette().len() && mode_in.bitdepth == mode_out.bitdepth {
             /*If input should have same palette colors, keep original to preserve its order and prevent conversion*/
-            mode_out = mode_in.clone();
+            return Ok(mode_in.clone());
         }
+        Ok(mode_out)
     } else {
-        mode_out.try_set_bitdepth(prof.bits.into())?;
-        mode_out.colortype = if prof.alpha {
-            if prof.colored {
-                ColorType::RGBA
-            } else {
-                ColorType::GREY_ALPHA
-            }
-        } else if prof.colored {
-            ColorType::RGB
+        direct_color_mode(&prof)
+    }
+}
+
+/*palette mode with the fewest bits that fit the colors*/
+pub(crate) fn palette_color_mode(palette: &[RGBA]) -> Result<ColorMode, Error> {
+    let n = palette.len();
+    let palettebits = if n <= 2 {
+        1
+    } else if n <= 4 {
+        2
+    } else if n <= 16 {
+        4
+    } else {
+        8
+    };
+    let mut mode_out = ColorMode::new();
+    for p in palette {
+        mode_out.palette_add(*p)?;
+    }
+    mode_out.colortype = ColorType::PALETTE;
+    mode_out.try_set_bitdepth(palettebits)?;
+    Ok(mode_out)
+}
+
+/*grey or RGB mode, with alpha or a color key if needed, that fits all colors of the profile*/
+pub(crate) fn direct_color_mode(prof: &ColorProfile) -> Result<ColorMode, Error> {
+    let mut mode_out = ColorMode::new();
+    mode_out.try_set_bitdepth(prof.bits.into())?;
+    mode_out.colortype = if prof.alpha {
+        if prof.colored {
+            ColorType::RGBA
         } else {
-            ColorType::GREY
-        };
-        if prof.key {
-            let mask = ((1 << mode_out.bitdepth()) - 1) as u16;
-            /*profile always uses 16-bit, mask converts it*/
-            mode_out.set_key(
-                prof.key_r & mask,
-                prof.key_g & mask,
-                prof.key_b & mask);
+            ColorType::GREY_ALPHA
         }
+    } else if prof.colored {
+        ColorType::RGB
+    } else {
+        ColorType::GREY
+    };
+    if prof.key {
+        let mask = ((1 << mode_out.bitdepth()) - 1) as u16;
+        /*profile always uses 16-bit, mask converts it*/
+        mode_out.set_key(
+            prof.key_r & mask,
+            prof.key_g & mask,
+            prof.key_b & mask);
     }
     Ok(mode_out)
 }
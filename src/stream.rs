@@ -0,0 +1,619 @@
+use crate::rustimpl::{adam7_expected_size, add_chunks_after_idat, add_chunks_before_idat, add_padding_bits_line, check_chunk_length, check_lode_color_validity, check_memory_limit, check_png_color_validity, linebits_exact, linebits_rounded, linebytes_rounded, lodepng_color_mode_equal, lodepng_convert, lodepng_inspect, make_filter, needs_color_convert, postprocess_scanlines, read_chunk_checked, unfilter_scanline_aliased};
+use crate::ffi::State;
+use crate::zlib;
+use crate::warning;
+use crate::{zero_vec, ChunkPosition, ChunkRef, ColorMode, ColorType, DecoderSettings, DecompressSettings, EncoderSettings, Error, Info, Pod, WarningKind};
+use flate2::write::ZlibEncoder;
+use std::io;
+use std::io::{Read, Write};
+
+/// IDAT data is read and inflated in pieces of this size, so that there's never much decompressed data waiting
+const IDAT_READ_SIZE: usize = 1 << 14;
+
+enum Idat {
+    /// In the middle of an IDAT chunk
+    Data { remaining: usize, crc: crc32fast::Hasher },
+    /// Found a chunk that isn't IDAT
+    Ended,
+}
+
+/// Decodes an image row by row from `io::Read`. See `Decoder::stream`.
+///
+/// It's an iterator of rows, but `next_row()` can avoid allocating a `Vec` for every row.
+pub struct RowReader<R> {
+    reader: R,
+    state: State,
+    width: u32,
+    height: u32,
+    /// rows returned so far
+    y: u32,
+    convert: bool,
+    critical_pos: ChunkPosition,
+    /// CRCs aren't checked after an unknown chunk, same as `decode`
+    unknown: bool,
+    idat: Idat,
+    scanlines: Scanlines,
+    /// row after color conversion
+    out_line: Vec<u8>,
+    /// Adam7 images are decoded whole
+    image: Option<Vec<u8>>,
+    /// header of the chunk after the last IDAT
+    next_chunk: Option<(usize, [u8; 4])>,
+    /// file offset of the last chunk header read, and of the chunk after it, for errors
+    chunk_start: usize,
+    next_chunk_start: usize,
+    chunk_buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> RowReader<R> {
+    pub(crate) fn new(mut reader: R, decoder: DecoderSettings, info_raw: ColorMode) -> Result<Self, Error> {
+        let mut header = [0; 33];
+        if read_fully(&mut reader, &mut header)? != header.len() {
+            /*error: the data length is smaller than the length of a PNG header*/
+            return Err(Error::new(27));
+        }
+        let (info_png, width, height) = lodepng_inspect(&decoder, &header, false)?;
+
+        let state = State {
+            decoder,
+            info_raw,
+            info_png,
+            ..State::default()
+        };
+        let mut this = Self {
+            reader,
+            scanlines: Scanlines::new(&state.info_png.color, width, &state.decoder.zlibsettings)?,
+            state,
+            width,
+            height,
+            y: 0,
+            convert: false,
+            critical_pos: ChunkPosition::IHDR,
+            unknown: false,
+            idat: Idat::Ended,
+            out_line: Vec::new(),
+            image: None,
+            next_chunk: None,
+            chunk_start: 0,
+            next_chunk_start: 33,
+            chunk_buf: Vec::new(),
+            done: false,
+        };
+
+        /*read everything before the image data*/
+        loop {
+            let (len, name) = this.read_chunk_header()?.ok_or(Error::new(30))?;
+            match &name {
+                b"IDAT" => {
+                    this.start_idat(len, name);
+                    break;
+                },
+                /*error: no image data*/
+                b"IEND" => return Err(Error::new(91)),
+                _ => this.read_chunk(len, name)?,
+            }
+        }
+
+        /*interlaced images are decoded whole*/
+        if this.state.info_png.interlace_method != 0 {
+            check_memory_limit(&this.state, width, height)?;
+        }
+        this.convert = needs_color_convert(&mut this.state)?;
+        if this.convert {
+            let out_linebytes = linebytes_rounded(width, this.state.info_raw.bpp_());
+            this.out_line.try_reserve_exact(out_linebytes)?;
+            this.out_line.resize(out_linebytes, 0);
+        }
+        Ok(this)
+    }
+
+    /// Width of the image in pixels
+    #[inline]
+    #[must_use]
+    pub fn width(&self) -> usize {
+        self.width as usize
+    }
+
+    /// Number of rows the image has
+    #[inline]
+    #[must_use]
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    /// Color mode of the rows returned
+    #[inline]
+    #[must_use]
+    pub fn info_raw(&self) -> &ColorMode {
+        &self.state.info_raw
+    }
+
+    /// Metadata of the file. Chunks after the image data are added after the last row has been read.
+    #[inline]
+    #[must_use]
+    pub fn info_png(&self) -> &Info {
+        &self.state.info_png
+    }
+
+    /// Returns the next row of pixels, or `None` after the end of the file has been checked.
+    ///
+    /// Rows with less than 8 bits per pixel are padded to a whole byte.
+    pub fn next_row(&mut self) -> Result<Option<&[u8]>, Error> {
+        if self.y >= self.height {
+            self.finish()?;
+            return Ok(None);
+        }
+        if self.state.info_png.interlace_method != 0 {
+            return self.next_interlaced_row().map(Some);
+        }
+
+        while !self.scanlines.unfilter_next()? {
+            if !self.read_idat()? {
+                self.scanlines.finish_inflater()?;
+                if !self.scanlines.unfilter_next()? {
+                    /*image data ended before the last row*/
+                    return Err(Error::new(91));
+                }
+                break;
+            }
+        }
+        self.y += 1;
+
+        let line = self.scanlines.line();
+        Ok(Some(if self.convert {
+            lodepng_convert(&mut self.out_line, line, &self.state.info_raw, &self.state.info_png.color, self.width, 1)?;
+            &self.out_line
+        } else {
+            line
+        }))
+    }
+
+    #[cold]
+    fn next_interlaced_row(&mut self) -> Result<&[u8], Error> {
+        if self.image.is_none() {
+            while self.read_idat()? {}
+            self.scanlines.finish_inflater()?;
+            let (w, h) = (self.width, self.height);
+            let mut image = self.scanlines.take_interlaced(w, h, &self.state.info_png)?;
+            if self.convert {
+                let mut out = crate::zero_vec(self.state.info_raw.raw_size_opt(w, h)?)?;
+                lodepng_convert(&mut out, &image, &self.state.info_raw, &self.state.info_png.color, w, h)?;
+                image = out;
+            }
+            let linebytes = linebytes_rounded(w, self.state.info_raw.bpp_());
+            self.out_line.clear();
+            self.out_line.try_reserve_exact(linebytes)?;
+            self.out_line.resize(linebytes, 0);
+            self.image = Some(image);
+        }
+        let image = self.image.as_deref().unwrap_or_default();
+        let y = self.y;
+        self.y += 1;
+
+        let bpp = self.state.info_raw.bpp_();
+        let linebits = linebits_exact(self.width, bpp);
+        let linebits_padded = linebits_rounded(self.width, bpp);
+        if linebits == linebits_padded {
+            let linebytes = linebits / 8;
+            image.get(y as usize * linebytes..(y as usize + 1) * linebytes).ok_or(Error::new(91))
+        } else {
+            add_padding_bits_line(&mut self.out_line, image, linebits_padded, linebits, y);
+            Ok(&self.out_line)
+        }
+    }
+
+    /// Feeds the next piece of IDAT data to the inflater. Returns false when there are no more IDAT chunks.
+    fn read_idat(&mut self) -> Result<bool, Error> {
+        loop {
+            match &mut self.idat {
+                Idat::Ended => return Ok(false),
+                Idat::Data { remaining: 0, crc } => {
+                    let checksum = crc.clone().finalize();
+                    let mut stored = [0; 4];
+                    read_exact(&mut self.reader, &mut stored)?;
+                    let stored = u32::from_be_bytes(stored);
+                    if !self.state.decoder.ignore_crc && !self.unknown && checksum != stored {
+                        return Err(Error::new(57).with_mismatch(checksum.into(), stored.into()).in_chunk(*b"IDAT", self.chunk_start));
+                    }
+                    match self.read_chunk_header()? {
+                        Some((len, name)) if &name == b"IDAT" => self.start_idat(len, name),
+                        next => {
+                            self.next_chunk = next;
+                            self.idat = Idat::Ended;
+                        },
+                    }
+                },
+                Idat::Data { remaining, crc } => {
+                    let len = (*remaining).min(IDAT_READ_SIZE);
+                    self.chunk_buf.clear();
+                    self.chunk_buf.try_reserve(len)?;
+                    self.chunk_buf.resize(len, 0);
+                    read_exact(&mut self.reader, &mut self.chunk_buf)?;
+                    crc.update(&self.chunk_buf);
+                    *remaining -= len;
+
+                    self.scanlines.push(&self.chunk_buf).map_err(|e| e.in_chunk(*b"IDAT", self.chunk_start))?;
+                    return Ok(true);
+                },
+            }
+        }
+    }
+
+    fn start_idat(&mut self, len: usize, name: [u8; 4]) {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&name);
+        self.idat = Idat::Data { remaining: len, crc };
+        self.critical_pos = ChunkPosition::IDAT;
+    }
+
+    /// Checks the rest of the image data and reads chunks after it
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.done {
+            return Ok(());
+        }
+        while self.read_idat()? {}
+        self.scanlines.finish_inflater()?;
+        if self.image.is_none() {
+            self.scanlines.check_end()?;
+        }
+        self.scanlines = Scanlines::default();
+
+        loop {
+            let header = match self.next_chunk.take() {
+                Some(header) => Some(header),
+                None => self.read_chunk_header()?,
+            };
+            let Some((len, name)) = header else {
+                warning::warn(&mut self.state.info_png, &self.state.decoder, WarningKind::MissingIend, None, self.next_chunk_start)?;
+                break;
+            };
+            if &name == b"IDAT" {
+                /*IDAT chunks must be consecutive*/
+                return Err(Error::new(91));
+            }
+            self.read_chunk(len, name)?;
+            if &name == b"IEND" {
+                break;
+            }
+        }
+        self.done = true;
+        Ok(())
+    }
+
+    /// Returns `None` at the end of the file
+    fn read_chunk_header(&mut self) -> Result<Option<(usize, [u8; 4])>, Error> {
+        let mut header = [0; 8];
+        match read_fully(&mut self.reader, &mut header)? {
+            0 => return Ok(None),
+            8 => {},
+            _ => return Err(Error::new(30)),
+        }
+        let len = crate::chunk_length(&header);
+        let name = header[4..8].try_into().unwrap();
+        self.chunk_start = self.next_chunk_start;
+        check_chunk_length(&self.state.decoder, len).map_err(|e| e.in_chunk(name, self.chunk_start))?;
+        self.next_chunk_start += len + 12;
+        Ok(Some((len, name)))
+    }
+
+    /// Reads the rest of a non-IDAT chunk
+    fn read_chunk(&mut self, len: usize, name: [u8; 4]) -> Result<(), Error> {
+        self.chunk_buf.clear();
+        self.chunk_buf.try_reserve(len + 12)?;
+        self.chunk_buf.extend_from_slice(&(len as u32).to_be_bytes());
+        self.chunk_buf.extend_from_slice(&name);
+        self.chunk_buf.resize(len + 12, 0);
+        read_exact(&mut self.reader, &mut self.chunk_buf[8..])?;
+
+        let ch = ChunkRef::new(&self.chunk_buf)?;
+        read_chunk_checked(&mut self.state.info_png, &self.state.decoder, &mut self.critical_pos, &mut self.unknown, ch, self.chunk_start)
+    }
+}
+
+/// Unfilters scanlines of a non-interlaced image as soon as they have been decompressed
+#[derive(Default)]
+pub(crate) struct Scanlines {
+    inflater: Option<zlib::Decoder>,
+    /// Decompressed data after the inflater has finished
+    inflated: Vec<u8>,
+    /// bytes at the start of the decompressed data that have been unfiltered already
+    consumed: usize,
+    /// filter byte + scanline, unfiltered in place
+    line: Vec<u8>,
+    /// previous unfiltered scanline (with one spare byte)
+    prev_line: Vec<u8>,
+    bytewidth: u8,
+    /// scanlines unfiltered so far
+    y: u32,
+}
+
+impl Scanlines {
+    pub(crate) fn new(color: &ColorMode, w: u32, settings: &DecompressSettings) -> Result<Self, Error> {
+        let bpp = color.bpp_();
+        let linebytes = linebytes_rounded(w, bpp);
+        let mut line = Vec::new();
+        line.try_reserve_exact(linebytes + 1)?;
+        line.resize(linebytes + 1, 0);
+        Ok(Self {
+            inflater: Some(zlib::new_decompressor(Vec::new(), 0, settings)),
+            inflated: Vec::new(),
+            consumed: 0,
+            prev_line: line.clone(),
+            line,
+            bytewidth: (bpp.get() + 7) / 8,
+            y: 0,
+        })
+    }
+
+    /// Adds more zlib data
+    pub(crate) fn push(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Some(dec) = &mut self.inflater {
+            if let Some(inflated) = dec.output_mut()? {
+                inflated.drain(..self.consumed);
+                self.consumed = 0;
+            }
+            dec.push(data)?;
+        }
+        Ok(())
+    }
+
+    /// Call after the last IDAT
+    pub(crate) fn finish_inflater(&mut self) -> Result<(), Error> {
+        if let Some(dec) = self.inflater.take() {
+            self.inflated = dec.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Unfilters the next scanline if there's enough decompressed data for it. Get it from `line()`.
+    pub(crate) fn unfilter_next(&mut self) -> Result<bool, Error> {
+        let inflated = match &mut self.inflater {
+            Some(dec) => match dec.output_mut()? {
+                Some(out) => out,
+                None => return Ok(false),
+            },
+            None => &mut self.inflated,
+        };
+        let Some(filtered) = inflated.get(self.consumed..self.consumed + self.line.len()) else {
+            return Ok(false);
+        };
+        self.line.copy_from_slice(filtered);
+        self.consumed += self.line.len();
+
+        let filter_type = self.line[0];
+        if filter_type > 4 {
+            return Err(Error::new(36));
+        }
+        let linebytes = self.line.len() - 1;
+        let prev_line = if self.y > 0 { Some(&self.prev_line[..linebytes]) } else { None };
+        unfilter_scanline_aliased(&mut self.line, 1, prev_line, self.bytewidth, filter_type, linebytes)
+            .ok_or(Error::new(77))?;
+        std::mem::swap(&mut self.line, &mut self.prev_line);
+        self.y += 1;
+        Ok(true)
+    }
+
+    /// The last unfiltered scanline
+    #[inline]
+    pub(crate) fn line(&self) -> &[u8] {
+        &self.prev_line[..self.line.len().saturating_sub(1)]
+    }
+
+    /// Error if there's more decompressed data than scanlines
+    pub(crate) fn check_end(&self) -> Result<(), Error> {
+        if self.inflated.len() != self.consumed {
+            /*decompressed size doesn't match prediction*/
+            return Err(Error::new(91));
+        }
+        Ok(())
+    }
+
+    /// Decodes the whole Adam7 image after `finish_inflater`
+    pub(crate) fn take_interlaced(&mut self, w: u32, h: u32, info_png: &Info) -> Result<Vec<u8>, Error> {
+        let expected = adam7_expected_size(&info_png.color, w, h).ok_or(Error::new(91))?;
+        if self.inflated.len() != expected {
+            /*decompressed size doesn't match prediction*/
+            return Err(Error::new(91));
+        }
+        postprocess_scanlines(std::mem::take(&mut self.inflated), 0, w, h, info_png)
+    }
+}
+
+impl<R: Read> Iterator for RowReader<R> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_row() {
+            Ok(row) => row.map(|row| Ok(row.to_vec())),
+            Err(err) => {
+                /*stop after the first error*/
+                self.y = self.height;
+                self.done = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+/// Like `read_exact`, but returns how much has been read before the end of the file
+pub(crate) fn read_fully(reader: &mut impl Read, mut buf: &mut [u8]) -> Result<usize, Error> {
+    let len = buf.len();
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => break,
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(len - buf.len())
+}
+
+pub(crate) fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), Error> {
+    if read_fully(reader, buf)? != buf.len() {
+        /*chunk broken off at end of file*/
+        return Err(Error::new(30));
+    }
+    Ok(())
+}
+
+/// Encodes an image row by row to `io::Write`. See `Encoder::stream`.
+pub struct RowWriter<'enc, W: Write> {
+    zlib: ZlibEncoder<IdatChunks<W>>,
+    info_png: Info,
+    info_raw: ColorMode,
+    encoder: &'enc EncoderSettings,
+    filter: Box<dyn FnMut(&mut [u8], &[u8], Option<&[u8]>) + 'enc>,
+    width: u32,
+    height: u32,
+    /// rows written so far
+    y: u32,
+    convert: bool,
+    /// row in the PNG's color mode
+    line: Vec<u8>,
+    prev_line: Vec<u8>,
+    /// filter byte + filtered row
+    filtered: Vec<u8>,
+}
+
+impl<'enc, W: Write> RowWriter<'enc, W> {
+    pub(crate) fn new(mut writer: W, width: u32, height: u32, state: &State, encoder: &'enc EncoderSettings) -> Result<Self, Error> {
+        if width == 0 || height == 0 {
+            return Err(Error::new(93));
+        }
+        let info_png = state.info_png.clone();
+        if (info_png.color.colortype == ColorType::PALETTE || encoder.force_palette) && (info_png.color.palette().is_empty() || info_png.color.palette().len() > 256) {
+            return Err(Error::new(68));
+        }
+        if info_png.interlace_method > 1 {
+            return Err(Error::new(71));
+        }
+        #[allow(deprecated)]
+        if info_png.interlace_method != 0 || encoder.zlibsettings.custom_zlib.is_some() {
+            return Err(Error::new(95));
+        }
+        check_png_color_validity(info_png.color.colortype, info_png.color.bitdepth())?;
+        check_lode_color_validity(state.info_raw.colortype, state.info_raw.bitdepth())?;
+
+        let mut head = Vec::new();
+        add_chunks_before_idat(&mut head, width, height, &info_png, encoder)?;
+        writer.write_all(&head)?;
+
+        let linebytes = linebytes_rounded(width, info_png.color.bpp_());
+        let idat = IdatChunks {
+            writer,
+            buf: Vec::new(),
+            chunk_size: DEFAULT_IDAT_SIZE,
+        };
+        Ok(Self {
+            zlib: zlib::new_compressor(idat, &encoder.zlibsettings),
+            filter: make_filter(width, height, &info_png.color, encoder)?,
+            convert: !lodepng_color_mode_equal(&state.info_raw, &info_png.color),
+            line: zero_vec(linebytes)?,
+            prev_line: zero_vec(linebytes)?,
+            filtered: zero_vec(linebytes + 1)?,
+            info_raw: state.info_raw.clone(),
+            info_png,
+            encoder,
+            width,
+            height,
+            y: 0,
+        })
+    }
+
+    /// Maximum size of data in each `IDAT` chunk. Applies to chunks written from now on.
+    pub fn set_idat_size(&mut self, bytes: usize) {
+        self.zlib.get_mut().chunk_size = bytes.clamp(1, (1 << 31) - 1);
+    }
+
+    /// Number of rows written so far
+    #[inline]
+    #[must_use]
+    pub fn rows_written(&self) -> usize {
+        self.y as usize
+    }
+
+    /// Compresses one or more whole rows in the `info_raw()` color mode of the `Encoder`.
+    ///
+    /// Rows with less than 8 bits per pixel must be padded to a whole byte.
+    pub fn write_rows<PixelType: Pod>(&mut self, rows: &[PixelType]) -> Result<(), Error> {
+        let rows: &[u8] = crate::bytemuck::cast_slice(rows);
+        let linebytes = linebytes_rounded(self.width, self.info_raw.bpp_());
+        if rows.len() % linebytes != 0 {
+            return Err(Error::new(84));
+        }
+        if rows.len() / linebytes > (self.height - self.y) as usize {
+            return Err(Error::new(96));
+        }
+        for row in rows.chunks_exact(linebytes) {
+            if self.convert {
+                lodepng_convert(&mut self.line, row, &self.info_png.color, &self.info_raw, self.width, 1)?;
+            } else {
+                self.line.copy_from_slice(row);
+            }
+            (self.filter)(&mut self.filtered, &self.line, if self.y > 0 { Some(&self.prev_line) } else { None });
+            self.zlib.write_all(&self.filtered)?;
+            std::mem::swap(&mut self.line, &mut self.prev_line);
+            self.y += 1;
+        }
+        Ok(())
+    }
+
+    /// Writes the rest of the image data and chunks after it. Fails if not all rows have been written.
+    pub fn finish(self) -> Result<W, Error> {
+        if self.y != self.height {
+            return Err(Error::new(96));
+        }
+        let mut idat = self.zlib.finish()?;
+        if !idat.buf.is_empty() {
+            idat.write_chunk(idat.buf.len())?;
+        }
+        let mut tail = Vec::new();
+        add_chunks_after_idat(&mut tail, &self.info_png, self.encoder)?;
+        idat.writer.write_all(&tail)?;
+        idat.writer.flush()?;
+        Ok(idat.writer)
+    }
+}
+
+const DEFAULT_IDAT_SIZE: usize = 1 << 16;
+
+/// Splits compressed image data into `IDAT` chunks
+struct IdatChunks<W> {
+    writer: W,
+    /// compressed data not written yet
+    buf: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<W: Write> IdatChunks<W> {
+    fn write_chunk(&mut self, len: usize) -> io::Result<()> {
+        let data = &self.buf[..len];
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(b"IDAT");
+        crc.update(data);
+        self.writer.write_all(&(len as u32).to_be_bytes())?;
+        self.writer.write_all(b"IDAT")?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&crc.finalize().to_be_bytes())?;
+        self.buf.drain(..len);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for IdatChunks<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.try_reserve(data.len()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= self.chunk_size {
+            self.write_chunk(self.chunk_size)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Chunks are written only when full
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
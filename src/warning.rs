@@ -0,0 +1,97 @@
+use crate::ffi::ErrorCode;
+use crate::{DecoderSettings, Error, Info};
+use std::fmt;
+
+/// Problem in a file that the decoder could work around. See `Info::warnings`.
+///
+/// `DecoderSettings::fatal_warnings` makes chosen kinds of warnings errors instead.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// CRC of an ancillary chunk or IEND doesn't match its data. The chunk has been skipped.
+    ChecksumMismatch = 0,
+    /// The file ends without an `IEND` chunk
+    MissingIend = 1,
+    /// There's more data after the `IEND` chunk. It's ignored.
+    TrailingData = 2,
+    /// `tIME` chunk has a date or time out of range, e.g. month 13
+    InvalidTime = 3,
+    /// Chunk that must come before `PLTE` or `IDAT` comes after it
+    ChunkOrder = 4,
+    /// Data of a known ancillary chunk, e.g. `gAMA` or `sBIT`, is invalid. Its `Info` field is left unset.
+    InvalidAncillaryChunk = 5,
+}
+
+impl WarningKind {
+    /// All kinds of warnings, e.g. for `Decoder::set_fatal_warnings` to be strict
+    pub const ALL: [Self; 6] = [Self::ChecksumMismatch, Self::MissingIend, Self::TrailingData, Self::InvalidTime, Self::ChunkOrder, Self::InvalidAncillaryChunk];
+
+    /// Error code the warning becomes when it's fatal
+    #[must_use]
+    pub fn code(self) -> ErrorCode {
+        ErrorCode(match self {
+            Self::ChecksumMismatch => 57,
+            Self::MissingIend => 52,
+            Self::TrailingData => 125,
+            Self::InvalidTime => 126,
+            Self::ChunkOrder => 127,
+            Self::InvalidAncillaryChunk => 139,
+        })
+    }
+
+    /// Bit for `DecoderSettings::fatal_warnings`
+    #[inline]
+    #[must_use]
+    pub const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Problem found while decoding that didn't stop it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// Type of the chunk with the problem, if it's about a chunk
+    pub chunk_type: Option<[u8; 4]>,
+    /// Byte offset in the file of the chunk, of the trailing data, or of the end of the file
+    pub offset: u64,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.kind.code().as_str())?;
+        if let Some(chunk_type) = self.chunk_type {
+            write!(f, " in {} chunk", String::from_utf8_lossy(&chunk_type))?;
+        }
+        write!(f, " at byte {}", self.offset)
+    }
+}
+
+impl From<Warning> for Error {
+    fn from(w: Warning) -> Self {
+        let err = Self::new(w.kind.code().0);
+        match w.chunk_type {
+            Some(chunk_type) => err.in_chunk(chunk_type, w.offset as usize),
+            None => err.at_offset(w.offset as usize),
+        }
+    }
+}
+
+impl DecoderSettings {
+    #[inline]
+    pub(crate) fn is_fatal(&self, kind: WarningKind) -> bool {
+        self.fatal_warnings & kind.bit() != 0
+    }
+}
+
+/*records a warning in the info, or returns it as an error if the decoder settings make it fatal*/
+#[cold]
+pub(crate) fn warn(info: &mut Info, decoder: &DecoderSettings, kind: WarningKind, chunk_type: Option<[u8; 4]>, offset: usize) -> Result<(), Error> {
+    let warning = Warning { kind, chunk_type, offset: offset as u64 };
+    if decoder.is_fatal(kind) {
+        return Err(warning.into());
+    }
+    info.warnings.try_reserve(1)?;
+    info.warnings.push(warning);
+    Ok(())
+}
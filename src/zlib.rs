@@ -1,9 +1,10 @@
 use crate::{CompressSettings, DecompressSettings, Error, Result};
 use flate2::Compression;
 use flate2::write::{ZlibEncoder, ZlibDecoder};
+use std::io;
 use std::io::Write;
 
-fn check_zlib_stream(inp: &[u8]) -> Result<(), Error> {
+pub(crate) fn check_zlib_stream(inp: &[u8]) -> Result<(), Error> {
     if inp.len() < 2 {
         return Err(Error::new(53));
     }
@@ -28,12 +29,12 @@ fn check_zlib_stream(inp: &[u8]) -> Result<(), Error> {
     Ok(())
 }
 
-pub(crate) enum Decoder<'settings> {
+pub(crate) enum Decoder {
     Flate(ZlibDecoder<Vec<u8>>),
-    Custom(&'settings DecompressSettings, Vec<u8>, Vec<u8>),
+    Custom(DecompressSettings, Vec<u8>, Vec<u8>),
 }
 
-impl Decoder<'_> {
+impl Decoder {
     pub fn push(&mut self, chunk: &[u8]) -> Result<(), Error> {
         match self {
             Self::Flate(dec) => {
@@ -46,6 +47,17 @@ impl Decoder<'_> {
         Ok(())
     }
 
+    /// Decompressed data available so far. Custom decompressors don't output anything before `finish`.
+    pub fn output_mut(&mut self) -> Result<Option<&mut Vec<u8>>, Error> {
+        match self {
+            Self::Flate(dec) => {
+                dec.flush().map_err(|_| Error::new(23))?;
+                Ok(Some(dec.get_mut()))
+            },
+            Self::Custom(..) => Ok(None),
+        }
+    }
+
     pub fn finish(self) -> Result<Vec<u8>, Error> {
         match self {
             Self::Flate(dec) => {
@@ -56,18 +68,18 @@ impl Decoder<'_> {
 
                 out.try_reserve((buf.len() * 3 / 2).max(16*1024))?;
                 let cb = settings.custom_zlib.ok_or(Error::new(87))?; // can't fail
-                (cb)(&buf, &mut out, settings)?;
+                (cb)(&buf, &mut out, &settings)?;
                 Ok(out)
             }
         }
     }
 }
 
-pub(crate) fn new_decompressor(out: Vec<u8>, zlib_data_size: usize, settings: &DecompressSettings) -> Decoder<'_> {
+pub(crate) fn new_decompressor(out: Vec<u8>, zlib_data_size: usize, settings: &DecompressSettings) -> Decoder {
     if settings.custom_zlib.is_some() {
         let mut buf = Vec::new();
         let _ = buf.try_reserve_exact(zlib_data_size);
-        Decoder::Custom(settings, buf, out)
+        Decoder::Custom(settings.clone(), buf, out)
     } else {
         Decoder::Flate(ZlibDecoder::new(out))
     }
@@ -83,6 +95,50 @@ pub(crate) fn decompress_into_vec(inp: &[u8]) -> Result<Vec<u8>, Error> {
     dec.finish().map_err(|_| Error::new(23))
 }
 
+/*output that fails instead of growing larger than max_size*/
+struct LimitedVec {
+    vec: Vec<u8>,
+    max_size: usize,
+    exceeded: bool,
+}
+
+impl Write for LimitedVec {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.max_size - self.vec.len() {
+            self.exceeded = true;
+            return Err(io::ErrorKind::OutOfMemory.into());
+        }
+        self.vec.try_reserve(buf.len()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        self.vec.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/*same as decompress, but fails with error 124 if the output would be larger than max_size*/
+pub(crate) fn decompress_limited(inp: &[u8], settings: &DecompressSettings, max_size: usize) -> Result<Vec<u8>, Error> {
+    if settings.custom_zlib.is_some() {
+        let out = decompress(inp, settings)?;
+        if out.len() > max_size {
+            return Err(Error::new(124));
+        }
+        return Ok(out);
+    }
+    check_zlib_stream(inp)?;
+    let mut vec = Vec::new();
+    vec.try_reserve((inp.len() * 3 / 2).max(16*1024).min(max_size))?;
+    let mut dec = ZlibDecoder::new(LimitedVec { vec, max_size, exceeded: false });
+    let res = dec.write_all(inp).and_then(|()| dec.try_finish());
+    if dec.get_ref().exceeded {
+        return Err(Error::new(124));
+    }
+    res.map_err(|_| Error::new(23))?;
+    Ok(dec.finish().map_err(|_| Error::new(23))?.vec)
+}
+
 pub(crate) fn decompress(inp: &[u8], settings: &DecompressSettings) -> Result<Vec<u8>, Error> {
     if let Some(cb) = settings.custom_zlib {
         let mut out = Vec::new(); out.try_reserve(inp.len() * 3 / 2)?;
@@ -93,14 +149,31 @@ pub(crate) fn decompress(inp: &[u8], settings: &DecompressSettings) -> Result<Ve
     }
 }
 
-pub(crate) fn new_compressor<W: Write>(outv: W, settings: &CompressSettings) -> ZlibEncoder<W> {
+pub(crate) fn compression(settings: &CompressSettings) -> Compression {
     let level = settings.level();
-    let level = if level == 0 {
+    if level == 0 {
         Compression::none()
     } else {
         Compression::new(level.min(9).into())
-    };
-    ZlibEncoder::new(outv, level)
+    }
+}
+
+pub(crate) fn new_compressor<W: Write>(outv: W, settings: &CompressSettings) -> ZlibEncoder<W> {
+    ZlibEncoder::new(outv, compression(settings))
+}
+
+/*continues the Adler-32 checksum, which is (1, 0) for no data, with more data*/
+pub(crate) fn adler32_update((mut a, mut b): (u32, u32), data: &[u8]) -> (u32, u32) {
+    /*the largest number of bytes that can't overflow before the modulo*/
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (a, b)
 }
 
 #[inline(never)]
@@ -186,3 +186,1362 @@ fn test_low_bpp(bitdepth: u8, interlace: bool) {
         px.r
     }).eq(pixels.iter().copied()));
 }
+
+/// Returns data in tiny pieces, like a network socket
+struct Trickle<'a>(&'a [u8]);
+
+impl std::io::Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.0.len()).min(7);
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+fn check_stream(path: &str, colortype: ColorType, bitdepth: u32) {
+    let file = std::fs::read(path).unwrap();
+    let mut d = Decoder::new();
+    d.info_raw_mut().colortype = colortype;
+    d.info_raw_mut().set_bitdepth(bitdepth);
+    let expected = d.decode(&file).unwrap();
+
+    let mut rows = d.stream(Trickle(&file)).unwrap();
+    assert_eq!(expected.width(), rows.width());
+    assert_eq!(expected.height(), rows.height());
+    let mut decoded = Vec::new();
+    while let Some(row) = rows.next_row().unwrap() {
+        decoded.extend_from_slice(row);
+    }
+    assert_eq!(expected.bytes(), &decoded[..], "{path}");
+    assert_eq!(d.info_png().text_keys().count(), rows.info_png().text_keys().count());
+}
+
+#[test]
+fn stream_rows() {
+    check_stream("tests/fry-test.png", ColorType::RGBA, 8);
+    check_stream("tests/fry-test.png", ColorType::RGB, 16);
+    check_stream("tests/graytest.png", ColorType::GREY, 8);
+    check_stream("tests/test.png", ColorType::RGBA, 8);
+    check_stream("lodepng_unittest/tests/pngtestsuite/basn3p04.png", ColorType::RGBA, 8);
+    check_stream("lodepng_unittest/tests/pngtestsuite/basn0g16.png", ColorType::GREY, 8);
+    check_stream("lodepng_unittest/tests/pngtestsuite/basi6a16.png", ColorType::RGBA, 16);
+    check_stream("lodepng_unittest/tests/pngtestsuite/basi3p02.png", ColorType::RGB, 8);
+    check_stream("lodepng_unittest/tests/pngtestsuite/f99n0g04.png", ColorType::GREY, 8);
+
+    let file = std::fs::read("tests/fry-test.png").unwrap();
+    let mut d = Decoder::new();
+    d.color_convert(false);
+    let rows = d.stream(&file[..file.len() - 100]).unwrap();
+    assert!(rows.last().unwrap().is_err());
+}
+
+fn check_partial(path: &str, colortype: ColorType, bitdepth: u32) {
+    let file = std::fs::read(path).unwrap();
+    let mut d = Decoder::new();
+    d.info_raw_mut().colortype = colortype;
+    d.info_raw_mut().set_bitdepth(bitdepth);
+    let expected = d.decode(&file).unwrap();
+
+    let mut partial = d.partial();
+    let mut events = Vec::new();
+    let mut rest = &file[..];
+    let mut piece = 1;
+    while !rest.is_empty() {
+        let (now, later) = rest.split_at(piece.min(rest.len()));
+        rest = later;
+        piece = (piece * 7 + 3) % 251;
+        partial.feed(now).unwrap();
+        events.extend(std::iter::from_fn(|| partial.next_event()));
+    }
+    assert_eq!(events.first(), Some(&DecodeEvent::Header), "{path}");
+    assert_eq!(events.last(), Some(&DecodeEvent::Done), "{path}");
+    assert_eq!(d.info_png().color.palette().is_empty(), !events.contains(&DecodeEvent::Palette));
+    let rows = events.iter().filter_map(|e| match e { DecodeEvent::Rows(n) => Some(*n), _ => None }).collect::<Vec<_>>();
+    assert!(rows.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(rows.last().copied(), Some(expected.height()));
+    assert!(partial.is_done());
+    assert_eq!(expected.bytes(), partial.finish().unwrap().bytes(), "{path}");
+}
+
+#[test]
+fn partial_decoder() {
+    check_partial("tests/fry-test.png", ColorType::RGBA, 8);
+    check_partial("tests/graytest.png", ColorType::GREY, 8);
+    check_partial("tests/test.png", ColorType::RGB, 16);
+    check_partial("lodepng_unittest/tests/pngtestsuite/basn3p04.png", ColorType::RGBA, 8);
+    check_partial("lodepng_unittest/tests/pngtestsuite/basi0g16.png", ColorType::RGBA, 8);
+
+    let file = std::fs::read("tests/fry-test.png").unwrap();
+    let mut partial = Decoder::new().partial();
+    partial.feed(&file[..file.len() / 2]).unwrap();
+    assert!(partial.rows_decoded() > 0);
+    assert!(partial.rows_decoded() < partial.height());
+    assert!(partial.finish().is_err());
+
+    let mut corrupt = file.clone();
+    corrupt[file.len() / 3] ^= 1;
+    let mut d = Decoder::new();
+    d.color_convert(false);
+    let mut partial = d.partial();
+    partial.feed(&corrupt[..file.len() / 3]).unwrap();
+    assert!(partial.feed(&corrupt[file.len() / 3..]).is_err());
+    assert!(partial.feed(&[]).is_err());
+}
+
+fn check_progressive(path: &str, colortype: ColorType, bitdepth: u32, fill: PreviewFill) {
+    let file = std::fs::read(path).unwrap();
+    let mut d = Decoder::new();
+    d.info_raw_mut().colortype = colortype;
+    d.info_raw_mut().set_bitdepth(bitdepth);
+    let expected = d.decode(&file).unwrap();
+
+    let mut previews = Vec::new();
+    let image = d.decode_progressive(&file, fill, |pass, preview| previews.push((pass, preview.to_vec()))).unwrap();
+    assert_eq!(expected.bytes(), image.bytes(), "{path}");
+    assert_eq!((1..=7).collect::<Vec<_>>(), previews.iter().map(|&(pass, _)| pass).collect::<Vec<_>>());
+    assert_eq!(expected.bytes(), &previews[6].1[..]);
+
+    // the first pass has every 8th pixel of every 8th row
+    if let Image::RGBA(expected) = expected {
+        let first: &[RGBA] = bytemuck::cast_slice(&previews[0].1);
+        for y in 0..expected.height {
+            for x in 0..expected.width {
+                let known = expected.buffer[(y & !7) * expected.width + (x & !7)];
+                if fill == PreviewFill::Replicate || (x % 8 == 0 && y % 8 == 0) {
+                    assert_eq!(known, first[y * expected.width + x]);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn progressive_passes() {
+    for fill in [PreviewFill::Replicate, PreviewFill::Bilinear] {
+        check_progressive("lodepng_unittest/tests/pngtestsuite/basi6a08.png", ColorType::RGBA, 8, fill);
+        check_progressive("lodepng_unittest/tests/pngtestsuite/basi3p02.png", ColorType::RGBA, 8, fill);
+        check_progressive("lodepng_unittest/tests/pngtestsuite/basi0g01.png", ColorType::GREY, 1, fill);
+        check_progressive("lodepng_unittest/tests/pngtestsuite/basi2c16.png", ColorType::RGB, 16, fill);
+        check_progressive("lodepng_unittest/tests/pngtestsuite/s03i3p01.png", ColorType::RGBA, 8, fill);
+    }
+
+    let file = std::fs::read("tests/fry-test.png").unwrap();
+    let mut calls = 0;
+    Decoder::new().decode_progressive(&file, PreviewFill::Replicate, |_, _| calls += 1).unwrap();
+    assert_eq!(0, calls);
+}
+
+fn check_stream_encode(path: &str, raw: ColorType, png: ColorType, bitdepth: u32) {
+    let mut d = Decoder::new();
+    d.info_raw_mut().colortype = raw;
+    d.info_raw_mut().set_bitdepth(bitdepth);
+    let image = d.decode_file(path).unwrap();
+
+    let mut e = Encoder::new();
+    *e.info_raw_mut() = d.info_raw().clone();
+    e.info_png_mut().color = d.info_raw().clone();
+    e.info_png_mut().color.colortype = png;
+    let mut rows = e.stream(Vec::new(), image.width(), image.height()).unwrap();
+    rows.set_idat_size(1000);
+    let linebytes = (image.width() * d.info_raw().bpp() as usize + 7) / 8;
+    for block in image.bytes().chunks(linebytes * 3) {
+        rows.write_rows(block).unwrap();
+    }
+    assert_eq!(image.height(), rows.rows_written());
+    let file = rows.finish().unwrap();
+
+    let mut d2 = Decoder::new();
+    *d2.info_raw_mut() = d.info_raw().clone();
+    assert_eq!(image.bytes(), d2.decode(&file).unwrap().bytes(), "{path}");
+    assert_eq!(png, d2.info_png().color.colortype);
+}
+
+#[test]
+fn stream_encode() {
+    check_stream_encode("tests/fry-test.png", ColorType::RGBA, ColorType::RGBA, 8);
+    check_stream_encode("tests/fry-test.png", ColorType::RGB, ColorType::RGBA, 8);
+    check_stream_encode("tests/fry-test.png", ColorType::RGBA, ColorType::RGB, 16);
+    check_stream_encode("tests/graytest.png", ColorType::GREY, ColorType::GREY, 8);
+    check_stream_encode("lodepng_unittest/tests/pngtestsuite/basn0g01.png", ColorType::GREY, ColorType::GREY, 1);
+
+    let mut e = Encoder::new();
+    let mut rows = e.stream(Vec::new(), 2, 2).unwrap();
+    rows.write_rows(&[RGBA::new(1, 2, 3, 4); 2]).unwrap();
+    assert!(rows.write_rows(&[RGBA::new(1, 2, 3, 4); 3]).is_err());
+    assert!(rows.finish().is_err());
+
+    e.info_png_mut().interlace_method = 1;
+    assert!(e.stream(Vec::new(), 2, 2).is_err());
+}
+
+fn idat_data(png: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut rest = &png[8..];
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        if &rest[4..8] == b"IDAT" {
+            data.extend_from_slice(&rest[8..8 + len]);
+        }
+        rest = &rest[12 + len..];
+    }
+    data
+}
+
+/// fcTL chunk data, with a delay in 1/10s
+struct Fctl {
+    seq: u32,
+    size: [u32; 2],
+    offset: [u32; 2],
+    delay: u16,
+    dispose: u8,
+    blend: u8,
+}
+
+impl Fctl {
+    fn bytes(&self) -> Vec<u8> {
+        let mut d = Vec::new();
+        for n in [self.seq, self.size[0], self.size[1], self.offset[0], self.offset[1]] {
+            d.extend_from_slice(&n.to_be_bytes());
+        }
+        d.extend_from_slice(&self.delay.to_be_bytes());
+        d.extend_from_slice(&10u16.to_be_bytes());
+        d.extend_from_slice(&[self.dispose, self.blend]);
+        d
+    }
+}
+
+/// 4x3 canvas, 3 frames. `sequence` can reorder the last fcTL and fdAT.
+fn make_apng(num_frames: u32, sequence: [u32; 2], second_x: u32) -> (Vec<u8>, Vec<Vec<RGBA>>) {
+    let frames = vec![
+        (0..12).map(|i| RGBA::new(i, 0, 0, 255)).collect::<Vec<_>>(),
+        (0..4).map(|i| RGBA::new(0, i, 0, 128)).collect(),
+        (0..12).map(|i| RGBA::new(0, 0, i, 0)).collect(),
+    ];
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    let idat = |pixels: &[RGBA], w, h| idat_data(&e.encode(pixels, w, h).unwrap());
+    let second = idat(&frames[1], 2, 2);
+    let third = idat(&frames[2], 4, 3);
+
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    let info = e.info_png_mut();
+    info.create_chunk(ChunkPosition::IHDR, "acTL", &[&num_frames.to_be_bytes()[..], &5u32.to_be_bytes()].concat()).unwrap();
+    info.create_chunk(ChunkPosition::PLTE, "fcTL", &Fctl { seq: 0, size: [4, 3], offset: [0, 0], delay: 1, dispose: 0, blend: 0 }.bytes()).unwrap();
+    info.create_chunk(ChunkPosition::IDAT, "fcTL", &Fctl { seq: 1, size: [2, 2], offset: [second_x, 1], delay: 2, dispose: 1, blend: 1 }.bytes()).unwrap();
+    info.create_chunk(ChunkPosition::IDAT, "fdAT", &[&2u32.to_be_bytes()[..], &second].concat()).unwrap();
+    info.create_chunk(ChunkPosition::IDAT, "fcTL", &Fctl { seq: sequence[0], size: [4, 3], offset: [0, 0], delay: 3, dispose: 2, blend: 0 }.bytes()).unwrap();
+    info.create_chunk(ChunkPosition::IDAT, "fdAT", &[&sequence[1].to_be_bytes()[..], &third[..10]].concat()).unwrap();
+    info.create_chunk(ChunkPosition::IDAT, "fdAT", &[&(sequence[1] + 1).to_be_bytes()[..], &third[10..]].concat()).unwrap();
+    (e.encode(&frames[0], 4, 3).unwrap(), frames)
+}
+
+#[test]
+fn decode_apng() {
+    let (png, frames) = make_apng(3, [3, 4], 2);
+    let mut d = Decoder::new();
+    let anim = d.decode_animation(&png).unwrap();
+    assert_eq!((4, 3, 5, 3), (anim.width, anim.height, anim.num_plays, anim.num_frames()));
+    assert!(anim.default_image_is_first_frame);
+    for (frame, expected) in anim.frames.iter().zip(&frames) {
+        let Image::RGBA(img) = &frame.image else { panic!() };
+        assert_eq!(&img.buffer, expected);
+    }
+    let f = &anim.frames[1];
+    assert_eq!((2, 1, 2, 2), (f.x_offset, f.y_offset, f.image.width(), f.image.height()));
+    assert_eq!((DisposeOp::Background, BlendOp::Over), (f.dispose_op, f.blend_op));
+    assert_eq!(std::time::Duration::from_millis(200), f.delay());
+    assert_eq!(DisposeOp::Previous, anim.frames[2].dispose_op);
+
+    // same as the default image
+    assert_eq!(d.decode(&png).unwrap().bytes(), anim.frames[0].image.bytes());
+
+    assert!(d.decode_animation(make_apng(3, [4, 3], 2).0).is_err());
+    assert!(d.decode_animation(make_apng(3, [3, 5], 2).0).is_err());
+    assert!(d.decode_animation(make_apng(3, [3, 4], 3).0).is_err());
+    assert!(d.decode_animation(make_apng(2, [3, 4], 2).0).is_err());
+
+    let anim = d.decode_animation(std::fs::read("tests/test.png").unwrap()).unwrap();
+    assert_eq!(1, anim.num_frames());
+}
+
+#[test]
+fn encode_apng() {
+    let (w, h) = (16, 12);
+    let mut canvases = Vec::new();
+    let mut canvas: Vec<_> = (0..w * h).map(|i| RGBA::new((i * 37) as u8, (i * 101 / 7) as u8, (i * i) as u8, 255)).collect();
+    canvases.push(canvas.clone());
+    // small moving square
+    for i in 0..4 {
+        canvas[(2 + i) * w + 3 + i] = RGBA::new(200, 0, 0, 255);
+        canvas[(2 + i) * w + 4 + i] = RGBA::new(0, 0, 0, 0);
+        canvases.push(canvas.clone());
+    }
+    canvases.push(canvas.clone()); // unchanged
+    canvases.push(canvases[1].clone()); // back to an earlier frame
+    let mut diagonal = canvases[1].clone();
+    for i in 0..h {
+        diagonal[i * w + i] = RGBA::new(255, 255, 0, 255);
+    }
+    canvases.push(diagonal);
+    canvases.push(vec![RGBA::new(0, 0, 0, 0); w * h]);
+
+    let enc = Encoder::new();
+    let mut anim = enc.animation(w, h).unwrap();
+    assert!(anim.encode().is_err());
+    assert!(anim.add_frame(&canvas[1..], 1, 10).is_err());
+    for (i, c) in canvases.iter().enumerate() {
+        anim.add_frame(c, i as u16, 100).unwrap();
+    }
+    anim.set_num_plays(2);
+    let png = anim.encode().unwrap();
+
+    let mut dec = Decoder::new();
+    dec.info_raw_mut().colortype = ColorType::RGBA;
+    let decoded = dec.decode_animation(&png).unwrap();
+    assert_eq!((w, h, 2, canvases.len()), (decoded.width, decoded.height, decoded.num_plays, decoded.num_frames()));
+    assert!(decoded.default_image_is_first_frame);
+    assert!(decoded.frames.iter().any(|f| f.blend_op == BlendOp::Over));
+    assert!(decoded.frames[1..7].iter().all(|f| f.image.width() < w && f.image.height() < h));
+
+    for (i, (frame, (screen, expected))) in decoded.frames.iter().zip(decoded.composite().zip(&canvases)).enumerate() {
+        assert_eq!(i as u16, frame.delay_num);
+        assert_eq!(&screen.unwrap().buffer, expected, "frame {i}");
+    }
+}
+
+#[test]
+fn composite_apng() {
+    let (png, frames) = make_apng(3, [3, 4], 2);
+    let anim = Decoder::new().decode_animation(&png).unwrap();
+    let canvases: Vec<_> = anim.composite().map(|c| c.unwrap().buffer).collect();
+    let canvases16: Vec<_> = anim.composite16().map(|c| c.unwrap().buffer).collect();
+    assert_eq!(3, canvases.len());
+    assert_eq!(canvases[0], frames[0]);
+    assert_eq!(canvases16[0][5], rgb::Rgba::new(5 * 257, 0, 0, 65535));
+
+    let over = |s: f64, sa: f64, d: f64, max: f64| ((s * sa + d * (max - sa)) / max).round();
+    for (i, (px, px16)) in canvases[1].iter().zip(&canvases16[1]).enumerate() {
+        let (x, y) = (i % 4, i / 4);
+        if (2..4).contains(&x) && (1..3).contains(&y) {
+            let g = ((y - 1) * 2 + x - 2) as f64;
+            let expected = RGBA::new(over(0., 128., i as f64, 255.) as u8, over(g, 128., 0., 255.) as u8, 0, 255);
+            assert_eq!(expected, *px);
+            let expected = rgb::Rgba::new(over(0., 32896., i as f64 * 257., 65535.) as u16, over(g * 257., 32896., 0., 65535.) as u16, 0, 65535);
+            assert_eq!(expected, *px16);
+        } else {
+            assert_eq!(frames[0][i], *px);
+        }
+    }
+    // replaced with transparent pixels after the blended area has been cleared
+    assert_eq!(canvases[2], frames[2]);
+
+    // translucent over translucent
+    let mut enc = Encoder::new();
+    enc.set_auto_convert(false);
+    let mut anim = enc.animation(1, 1).unwrap();
+    anim.add_frame(&[RGBA::new(200, 0, 0, 100)], 1, 1).unwrap();
+    anim.add_frame(&[RGBA::new(0, 0, 200, 100)], 1, 1).unwrap();
+    let mut anim = Decoder::new().decode_animation(anim.encode().unwrap()).unwrap();
+    anim.frames[1].blend_op = BlendOp::Over;
+    anim.frames[0].dispose_op = DisposeOp::None;
+    let last = anim.composite().last().unwrap().unwrap().buffer[0];
+    // a = 100 + 100 * 155 / 255 = 160.78
+    assert_eq!(RGBA::new(76, 0, 124, 161), last);
+    let last = anim.composite16().last().unwrap().unwrap().buffer[0];
+    assert_eq!(161, (last.a + 128) / 257);
+    assert_eq!(124, (last.b + 128) / 257);
+}
+
+fn chunk_names(png: &[u8]) -> Vec<[u8; 4]> {
+    ChunksIter::new(&png[8..]).map(|c| c.unwrap().name()).collect()
+}
+
+#[test]
+fn colorimetry_chunks() {
+    let mut d = Decoder::new();
+    d.decode_file("tests/profile.png").unwrap();
+    let icc = d.info_png().icc_profile.clone().unwrap();
+    assert_eq!(&b"ICC Profile"[..], &*icc.name);
+    assert_eq!(b"appl", &icc.data[4..8]);
+    assert_eq!(None, d.info_png().srgb_intent);
+
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.set_palette(&[RGBA::new(1, 2, 3, 255), RGBA::new(4, 5, 6, 255)]).unwrap();
+    let info = e.info_png_mut();
+    info.gamma = Some(45455);
+    info.chromaticities = Some(Chromaticities {
+        white_x: 31270, white_y: 32900, red_x: 64000, red_y: 33000,
+        green_x: 30000, green_y: 60000, blue_x: 15000, blue_y: 6000,
+    });
+    info.srgb_intent = Some(RenderingIntent::Perceptual);
+    let png = e.encode(&[0u8, 1], 2, 1).unwrap();
+    let names = chunk_names(&png);
+    assert_eq!(&names[..5], &[*b"IHDR", *b"cHRM", *b"gAMA", *b"sRGB", *b"PLTE"]);
+
+    let mut d = Decoder::new();
+    d.decode(&png).unwrap();
+    assert_eq!(e.info_png().gamma, d.info_png().gamma);
+    assert_eq!(e.info_png().chromaticities, d.info_png().chromaticities);
+    assert_eq!(Some(RenderingIntent::Perceptual), d.info_png().srgb_intent);
+
+    e.info_png_mut().icc_profile = Some(icc.clone());
+    assert!(e.encode(&[0u8, 1], 2, 1).is_err());
+    e.info_png_mut().srgb_intent = None;
+    let png = e.encode(&[0u8, 1], 2, 1).unwrap();
+    assert_eq!(&chunk_names(&png)[..5], &[*b"IHDR", *b"cHRM", *b"gAMA", *b"iCCP", *b"PLTE"]);
+
+    // remembered chunks aren't written twice
+    let mut d = Decoder::new();
+    d.remember_unknown_chunks(true);
+    d.decode(&png).unwrap();
+    assert_eq!(Some(&icc), d.info_png().icc_profile.as_ref());
+    assert!(d.info_png().get("iCCP").is_some());
+    let mut e = Encoder::new();
+    *e.info_png_mut() = d.info_png().clone();
+    *e.info_raw_mut() = d.info_png().color.clone();
+    e.set_auto_convert(false);
+    let png2 = e.encode(&[0u8, 1], 2, 1).unwrap();
+    assert_eq!(1, chunk_names(&png2).iter().filter(|n| n == &b"iCCP").count());
+}
+
+#[test]
+fn hdr_chunks() {
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.set_palette(&[RGBA::new(1, 2, 3, 255), RGBA::new(4, 5, 6, 255)]).unwrap();
+    let info = e.info_png_mut();
+    info.gamma = Some(45455);
+    info.cicp = Some(Cicp { color_primaries: 9, transfer_function: 16, matrix_coefficients: 0, video_full_range: true });
+    info.mastering_display = Some(MasteringDisplay {
+        primaries: [(35400, 14600), (8500, 39850), (6550, 2300)],
+        white_point: (15635, 16450),
+        max_luminance: 10_000_000,
+        min_luminance: 1,
+    });
+    info.content_light_level = Some(ContentLightLevel { max_cll: 4_000_000, max_fall: 1_000_000 });
+    let png = e.encode(&[0u8, 1], 2, 1).unwrap();
+    assert_eq!(&chunk_names(&png)[..6], &[*b"IHDR", *b"gAMA", *b"cICP", *b"mDCv", *b"cLLi", *b"PLTE"]);
+
+    let mut d = Decoder::new();
+    d.decode(&png).unwrap();
+    assert_eq!(e.info_png().cicp, d.info_png().cicp);
+    assert_eq!(e.info_png().mastering_display, d.info_png().mastering_display);
+    assert_eq!(e.info_png().content_light_level, d.info_png().content_light_level);
+    assert_eq!(ColorSpace { transfer: TransferFunction::Pq, primaries: Primaries::Bt2020 }, d.info_png().color_space());
+
+    let mut info = Info::new();
+    assert_eq!(ColorSpace { transfer: TransferFunction::Unknown, primaries: Primaries::Unknown }, info.color_space());
+    info.gamma = Some(100000);
+    assert_eq!(TransferFunction::Gamma(100000), info.color_space().transfer);
+    info.srgb_intent = Some(RenderingIntent::Perceptual);
+    assert_eq!(ColorSpace { transfer: TransferFunction::Srgb, primaries: Primaries::Bt709 }, info.color_space());
+    info.icc_profile = Some(IccProfile { name: b"icc"[..].into(), data: vec![0; 4] });
+    assert_eq!(Primaries::IccProfile, info.color_space().primaries);
+
+    // matrix coefficients must be 0 in PNG
+    e.info_png_mut().cicp.as_mut().unwrap().matrix_coefficients = 1;
+    assert!(e.encode(&[0u8, 1], 2, 1).is_err());
+    let mut bad = png.clone();
+    let pos = bad.windows(4).position(|w| w == b"cICP").unwrap();
+    bad[pos + 6] = 1;
+    let crc = crc32fast::hash(&bad[pos..pos + 8]);
+    bad[pos + 8..pos + 12].copy_from_slice(&crc.to_be_bytes());
+    let mut d = Decoder::new();
+    d.decode(&bad).unwrap();
+    assert_eq!(None, d.info_png().cicp);
+    d.set_fatal_warnings(&[WarningKind::InvalidAncillaryChunk]);
+    assert!(d.decode(&bad).is_err());
+}
+
+fn exif_blob(big_endian: bool) -> Vec<u8> {
+    let u16b = |v: u16| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+    let u32b = |v: u32| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+    let mut e = Vec::new();
+    e.extend_from_slice(if big_endian { b"MM\0*" } else { b"II*\0" });
+    e.extend_from_slice(&u32b(8));
+    e.extend_from_slice(&u16b(4));
+    let date_offset = 8 + 2 + 4 * 12 + 4;
+    let make_offset = date_offset + 20;
+    for (tag, typ, count, value) in [
+        (0x010F, 2, 6, u32b(make_offset)),
+        (0x0110, 2, 3, *b"X1\0\0"),
+        (0x0112, 3, 1, { let mut v = [0; 4]; v[..2].copy_from_slice(&u16b(6)); v }),
+        (0x0132, 2, 20, u32b(date_offset)),
+    ] {
+        e.extend_from_slice(&u16b(tag));
+        e.extend_from_slice(&u16b(typ));
+        e.extend_from_slice(&u32b(count));
+        e.extend_from_slice(&value);
+    }
+    e.extend_from_slice(&u32b(0));
+    e.extend_from_slice(b"2024:02:29 12:34:56\0");
+    e.extend_from_slice(b"Apple\0");
+    e
+}
+
+#[test]
+fn exif_chunk() {
+    for big_endian in [true, false] {
+        let exif = Exif::parse(&exif_blob(big_endian)).unwrap();
+        assert_eq!(Some(6), exif.orientation);
+        assert_eq!(Some("2024:02:29 12:34:56"), exif.date_time.as_deref());
+        assert_eq!(Some("Apple"), exif.make.as_deref());
+        assert_eq!(Some("X1"), exif.model.as_deref());
+    }
+    assert!(Exif::parse(b"Exif\0\0MM\0*").is_err());
+    assert!(Exif::parse(b"MM\0*\0\0\xff\xff").is_err());
+
+    let mut e = Encoder::new();
+    e.info_png_mut().exif = Some(exif_blob(true));
+    let png = e.encode(&[RGBA::new(1, 2, 3, 4)], 1, 1).unwrap();
+    assert!(chunk_names(&png).iter().position(|n| n == b"eXIf") < chunk_names(&png).iter().position(|n| n == b"IDAT"));
+    let mut d = Decoder::new();
+    d.decode(&png).unwrap();
+    assert_eq!(e.info_png().exif, d.info_png().exif);
+    assert_eq!(Some(6), d.info_png().exif_tags().unwrap().unwrap().orientation);
+
+    e.info_png_mut().exif = Some(b"Exif\0\0MM\0*".to_vec());
+    assert!(e.encode(&[RGBA::new(1, 2, 3, 4)], 1, 1).is_err());
+}
+
+#[test]
+fn palette_chunks() {
+    let palette = [RGBA::new(1, 2, 3, 255), RGBA::new(4, 5, 6, 255), RGBA::new(7, 8, 9, 255)];
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.set_histogram(true);
+    e.set_palette(&palette).unwrap();
+    e.info_png_mut().suggested_palettes = vec![
+        SuggestedPalette { name: b"8bit"[..].into(), sample_depth: 8, entries: vec![SuggestedColor { color: rgb::RGBA16::new(1, 2, 3, 255), frequency: 9 }] },
+        SuggestedPalette { name: b"16bit"[..].into(), sample_depth: 16, entries: vec![SuggestedColor { color: rgb::RGBA16::new(1000, 2, 65535, 0), frequency: 0 }; 2] },
+    ];
+    let png = e.encode(&[0u8, 1, 0, 0], 4, 1).unwrap();
+    let names = chunk_names(&png);
+    let plte = names.iter().position(|n| n == b"PLTE").unwrap();
+    let hist = names.iter().position(|n| n == b"hIST").unwrap();
+    assert!(plte < hist && hist < names.iter().position(|n| n == b"IDAT").unwrap());
+
+    let mut d = Decoder::new();
+    d.decode(&png).unwrap();
+    assert_eq!(&[65535, 21845, 0][..], &d.info_png().histogram[..]);
+    assert_eq!(e.info_png().suggested_palettes, d.info_png().suggested_palettes);
+
+    e.info_png_mut().histogram = vec![1, 2];
+    assert!(e.encode(&[0u8, 1, 0, 0], 4, 1).is_err());
+
+    // hIST must match the palette
+    let mut bad = png.clone();
+    let pos = bad.windows(4).position(|w| w == b"hIST").unwrap();
+    bad[pos - 1] = 4;
+    bad.drain(pos + 8..pos + 10);
+    let crc = crc32fast::hash(&bad[pos..pos + 8]);
+    bad[pos + 8..pos + 12].copy_from_slice(&crc.to_be_bytes());
+    let mut d = Decoder::new();
+    d.decode(&bad).unwrap();
+    assert!(d.info_png().histogram.is_empty());
+    d.set_fatal_warnings(&[WarningKind::InvalidAncillaryChunk]);
+    assert!(d.decode(&bad).is_err());
+}
+
+#[test]
+fn physical_layout_chunks() {
+    let mut e = Encoder::new();
+    let info = e.info_png_mut();
+    info.offset = Some(ImageOffset { x: -5, y: 1000, unit: OffsetUnit::Micrometer });
+    info.physical_scale = Some(PhysicalScale { unit: ScaleUnit::Meter, pixel_width: 0.0001, pixel_height: 2.5e-5 });
+    info.calibration = Some(PixelCalibration {
+        name: b"temperature"[..].into(),
+        x0: 0,
+        x1: 65535,
+        equation: CalibrationEquation::Linear,
+        unit: b"K"[..].into(),
+        params: vec![-273.15, 0.01],
+    });
+    info.stereo = Some(StereoLayout::DivergingFuse);
+    let png = e.encode(&[RGBA::new(1, 2, 3, 4)], 1, 1).unwrap();
+    let names = chunk_names(&png);
+    let idat = names.iter().position(|n| n == b"IDAT").unwrap();
+    for name in [b"oFFs", b"sCAL", b"pCAL", b"sTER"] {
+        assert!(names.iter().position(|n| n == name).unwrap() < idat);
+    }
+
+    let mut d = Decoder::new();
+    d.decode(&png).unwrap();
+    assert_eq!(e.info_png().offset, d.info_png().offset);
+    assert_eq!(e.info_png().physical_scale, d.info_png().physical_scale);
+    assert_eq!(e.info_png().calibration, d.info_png().calibration);
+    assert_eq!(Some(StereoLayout::DivergingFuse), d.info_png().stereo);
+
+    e.info_png_mut().calibration.as_mut().unwrap().params.pop();
+    assert!(e.encode(&[RGBA::new(1, 2, 3, 4)], 1, 1).is_err());
+    e.info_png_mut().calibration = None;
+    e.info_png_mut().physical_scale.as_mut().unwrap().pixel_width = 0.;
+    assert!(e.encode(&[RGBA::new(1, 2, 3, 4)], 1, 1).is_err());
+
+    // sCAL sizes must be in the PNG float syntax
+    let mut bad = png.clone();
+    let pos = bad.windows(4).position(|w| w == b"sCAL").unwrap();
+    let len = u32::from_be_bytes(bad[pos - 4..pos].try_into().unwrap()) as usize;
+    bad[pos + 5] = b'x';
+    let crc = crc32fast::hash(&bad[pos..pos + 4 + len]);
+    bad[pos + 4 + len..pos + 8 + len].copy_from_slice(&crc.to_be_bytes());
+    let mut d = Decoder::new();
+    d.decode(&bad).unwrap();
+    assert_eq!(None, d.info_png().physical_scale);
+    d.set_fatal_warnings(&[WarningKind::InvalidAncillaryChunk]);
+    assert!(d.decode(&bad).is_err());
+}
+
+#[test]
+fn significant_bits() {
+    // 10-bit samples stored in a 16-bit PNG by shifting
+    let samples: Vec<u8> = [0u16, 3, 512, 1023].iter().flat_map(|v| (v << 6).to_be_bytes()).collect();
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.info_raw_mut().colortype = ColorType::GREY;
+    e.info_raw_mut().set_bitdepth(16);
+    e.info_png_mut().color.colortype = ColorType::GREY;
+    e.info_png_mut().color.set_bitdepth(16);
+    e.info_png_mut().significant_bits = Some(SignificantBits { red: 10, green: 10, blue: 10, alpha: 0 });
+    let png = e.encode(&samples, 4, 1).unwrap();
+
+    let mut d = Decoder::new();
+    d.info_raw_mut().colortype = ColorType::GREY;
+    d.info_raw_mut().set_bitdepth(8);
+    assert_eq!(&[0, 0, 128, 255][..], d.decode(&png).unwrap().bytes());
+    assert_eq!(Some(SignificantBits { red: 10, green: 10, blue: 10, alpha: 0 }), d.info_png().significant_bits);
+    d.use_significant_bits(true);
+    assert_eq!(&[0, 1, 128, 255][..], d.decode(&png).unwrap().bytes());
+
+    e.info_png_mut().significant_bits = Some(SignificantBits { red: 17, green: 17, blue: 17, alpha: 0 });
+    assert!(e.encode(&samples, 4, 1).is_err());
+
+    // 8 significant bits in 16-bit samples can be stored as 8-bit
+    let pixels: Vec<u8> = (0..4u16).flat_map(|i| [i * 50, 255 - i, i].map(|v| v << 8)).flat_map(u16::to_be_bytes).collect();
+    let mut e = Encoder::new();
+    e.info_raw_mut().colortype = ColorType::RGB;
+    e.info_raw_mut().set_bitdepth(16);
+    let png16 = e.encode(&pixels, 4, 1).unwrap();
+    e.info_png_mut().significant_bits = Some(SignificantBits { red: 8, green: 8, blue: 8, alpha: 0 });
+    let png8 = e.encode(&pixels, 4, 1).unwrap();
+    let mut d = Decoder::new();
+    d.decode(&png16).unwrap();
+    assert_eq!(16, d.info_png().color.bitdepth());
+
+    let mut d = Decoder::new();
+    d.info_raw_mut().colortype = ColorType::RGB;
+    d.info_raw_mut().set_bitdepth(8);
+    let image = d.decode(&png8).unwrap();
+    assert_eq!(8, d.info_png().color.bitdepth());
+    assert_eq!(Some(SignificantBits { red: 8, green: 8, blue: 8, alpha: 0 }), d.info_png().significant_bits);
+    let expected: Vec<u8> = (0..4u8).flat_map(|i| [i * 50, 255 - i, i]).collect();
+    assert_eq!(&expected[..], image.bytes());
+}
+
+struct CountingReader<R> {
+    inner: R,
+    bytes_read: usize,
+}
+
+impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n;
+        Ok(n)
+    }
+}
+
+impl<R: std::io::Seek> std::io::Seek for CountingReader<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn read_metadata() {
+    let pixels: Vec<RGBA> = (0..64 * 64u32).map(|i| RGBA::new((i * 7919 % 251) as u8, (i % 256) as u8, (i / 64) as u8, 255)).collect();
+    let mut e = Encoder::new();
+    e.info_png_mut().add_text("Title", "metadata").unwrap();
+    e.info_png_mut().phys_defined = true;
+    e.info_png_mut().phys_x = 2835;
+    e.info_png_mut().phys_y = 2835;
+    e.info_png_mut().gamma = Some(45455);
+    let mut png = e.encode(&pixels, 64, 64).unwrap();
+    let idat = png.windows(4).position(|w| w == b"IDAT").unwrap();
+    let idat_len = u32::from_be_bytes(png[idat - 4..idat].try_into().unwrap()) as usize;
+    // image data isn't decompressed
+    png[idat + 10] ^= 0xFF;
+
+    let mut d = Decoder::new();
+    let mut reader = CountingReader { inner: std::io::Cursor::new(&png), bytes_read: 0 };
+    assert_eq!((64, 64), d.read_metadata_seek(&mut reader).unwrap());
+    assert!(reader.bytes_read <= png.len() - idat_len);
+    assert_eq!(Some(45455), d.info_png().gamma);
+    assert_eq!(2835, d.info_png().phys_x);
+    assert!(d.info_png().text_keys().any(|(k, v)| k == b"Title" && v == b"metadata"));
+
+    let mut d = Decoder::new();
+    assert_eq!((64, 64), d.read_metadata(&png[..]).unwrap());
+    assert!(d.info_png().text_keys().any(|(k, v)| k == b"Title" && v == b"metadata"));
+    assert!(Decoder::new().decode(&png).is_err());
+
+    // no IEND
+    let mut d = Decoder::new();
+    assert!(d.read_metadata(&png[..png.len() - 12]).is_ok());
+    assert_eq!(WarningKind::MissingIend, d.info_png().warnings[0].kind);
+}
+
+#[test]
+fn decoder_limits() {
+    let pixels: Vec<RGBA> = (0..40 * 30u32).map(|i| RGBA::new(i as u8, (i / 7) as u8, 3, 255)).collect();
+    let mut e = Encoder::new();
+    e.set_text_compression(true);
+    e.info_png_mut().add_itext("Comment", "", "", &"bomb ".repeat(10_000)).unwrap();
+    let png = e.encode(&pixels, 40, 30).unwrap();
+
+    let code = |limits: Limits| {
+        let mut d = Decoder::new();
+        d.set_limits(limits);
+        d.decode(&png).err().map(|e| ErrorCode::from(e).0)
+    };
+    let limits = |set: fn(&mut Limits)| {
+        let mut limits = Limits::new();
+        set(&mut limits);
+        limits
+    };
+    assert_eq!(None, code(Limits::default()));
+    assert_eq!(Some(119), code(limits(|l| l.set_max_width(39))));
+    assert_eq!(Some(120), code(limits(|l| l.set_max_height(29))));
+    assert_eq!(Some(121), code(limits(|l| l.set_max_pixels(40 * 30 - 1))));
+    assert_eq!(None, code(limits(|l| {
+        l.set_max_pixels(40 * 30);
+        l.set_max_memory(40 * 30 * 8 + 30);
+    })));
+    assert_eq!(Some(122), code(limits(|l| l.max_memory = 40 * 30 * 4)));
+    assert_eq!(Some(123), code(limits(|l| l.set_max_chunk_size(100))));
+    assert_eq!(Some(124), code(limits(|l| l.set_max_text_size(49_999))));
+    assert_eq!(None, code(limits(|l| l.set_max_text_size(50_000))));
+
+    let mut d = Decoder::new();
+    d.set_limits(limits(|l| l.set_max_chunk_size(100)));
+    assert!(d.stream(&png[..]).is_err());
+    assert!(d.read_metadata(&png[..]).is_err());
+}
+
+#[test]
+fn structured_errors() {
+    let pixels: Vec<RGBA> = (0..20 * 10u32).map(|i| RGBA::new(i as u8, 7, 3, 255)).collect();
+    let mut e = Encoder::new();
+    e.set_text_compression(false);
+    e.info_png_mut().add_text("Title", "test").unwrap();
+    let png = e.encode(&pixels, 20, 10).unwrap();
+    let idat = png.windows(4).position(|w| w == b"IDAT").unwrap() - 4;
+    let crc_pos = idat + 8 + u32::from_be_bytes(png[idat..idat + 4].try_into().unwrap()) as usize;
+    let mut bad = png.clone();
+    bad[crc_pos] ^= 1;
+    let stored = u32::from_be_bytes(bad[crc_pos..crc_pos + 4].try_into().unwrap());
+
+    let check = |err: Error| {
+        assert_eq!(ErrorKind::Checksum, err.kind());
+        assert_eq!(Some(*b"IDAT"), err.chunk_type());
+        assert_eq!(Some(idat as u64), err.offset());
+        assert_eq!(Some(stored as u64 ^ (1 << 24)), err.expected());
+        assert_eq!(Some(stored as u64), err.actual());
+        assert_eq!(57, ErrorCode::from(err.clone()).0);
+        assert!(err.to_string().contains(&format!("IDAT chunk at byte {idat}")), "{err}");
+    };
+    let mut d = Decoder::new();
+    check(d.decode(&bad).unwrap_err());
+
+    let mut rows = d.stream(&bad[..]).unwrap();
+    let err = loop {
+        if let Err(err) = rows.next_row() {
+            break err;
+        }
+    };
+    check(err);
+
+    let mut partial = d.partial();
+    let err = bad.chunks(7).find_map(|piece| partial.feed(piece).err()).unwrap();
+    check(err);
+
+    /*metadata chunks have context too. Bad CRC of an ancillary chunk is only a warning by default*/
+    d.set_fatal_warnings(&[WarningKind::ChecksumMismatch]);
+    let text = png.windows(4).position(|w| w == b"tEXt").unwrap() - 4;
+    let mut bad = png.clone();
+    bad[text + 10] ^= 1;
+    let err = d.read_metadata(&bad[..]).unwrap_err();
+    assert_eq!((Some(*b"tEXt"), Some(text as u64)), (err.chunk_type(), err.offset()));
+
+    let err = d.decode(&png[..40]).unwrap_err();
+    assert_eq!(ErrorKind::Format, err.kind());
+
+    struct Failing;
+    impl std::io::Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "disconnected"))
+        }
+    }
+    let err = d.read_metadata(Failing).unwrap_err();
+    assert_eq!(ErrorKind::Io, err.kind());
+    assert_eq!("disconnected", std::error::Error::source(&err).unwrap().to_string());
+}
+
+#[test]
+fn decode_warnings() {
+    let pixels: Vec<RGBA> = (0..20 * 10u32).map(|i| RGBA::new(i as u8, 7, 3, 255)).collect();
+    let mut e = Encoder::new();
+    e.set_text_compression(false);
+    e.info_png_mut().add_text("Title", "test").unwrap();
+    e.info_png_mut().time_defined = true;
+    e.info_png_mut().time = Time { year: 2024, month: 13, day: 1, hour: 0, minute: 0, second: 0 };
+    e.info_png_mut().create_chunk(ChunkPosition::IDAT, "gAMA", &45455u32.to_be_bytes()).unwrap();
+    let png = e.encode(&pixels, 20, 10).unwrap();
+    let chunk_pos = |name: &[u8]| png.windows(4).position(|w| w == name).unwrap() - 4;
+
+    let mut d = Decoder::new();
+    let (image, warnings) = d.decode_with_warnings(&png).unwrap();
+    assert_eq!(20, image.width());
+    let kinds: Vec<_> = warnings.iter().map(|w| (w.kind, w.chunk_type, w.offset)).collect();
+    assert_eq!(kinds, [
+        (WarningKind::InvalidTime, Some(*b"tIME"), chunk_pos(b"tIME") as u64),
+        (WarningKind::ChunkOrder, Some(*b"gAMA"), chunk_pos(b"gAMA") as u64),
+    ]);
+    assert!(warnings[0].to_string().contains("tIME chunk at byte"));
+
+    let mut bad = png.clone();
+    let text = chunk_pos(b"tEXt");
+    bad[text + 10] ^= 1;
+    bad.truncate(bad.len() - 12);
+    let iend = bad.len() as u64;
+    bad.extend_from_slice(&png[png.len() - 12..]);
+    bad.extend_from_slice(b"junk");
+    let (_, warnings) = d.decode_with_warnings(&bad).unwrap();
+    let kinds: Vec<_> = warnings.iter().map(|w| (w.kind, w.offset)).collect();
+    assert!(kinds.contains(&(WarningKind::ChecksumMismatch, text as u64)), "{kinds:?}");
+    assert!(kinds.contains(&(WarningKind::TrailingData, iend + 12)), "{kinds:?}");
+    assert_eq!(0, d.info_png().text_keys().count());
+
+    let mut partial = d.partial();
+    for piece in bad.chunks(9) {
+        partial.feed(piece).unwrap();
+    }
+    assert!(partial.info_png().warnings.iter().any(|w| w.kind == WarningKind::TrailingData));
+    assert_eq!(d.read_metadata(&bad[..]).unwrap(), (20, 10));
+    assert_eq!(d.info_png().warnings.len(), 3);
+
+    let no_iend = &png[..png.len() - 12];
+    let (_, warnings) = d.decode_with_warnings(no_iend).unwrap();
+    assert_eq!(warnings.last().map(|w| (w.kind, w.offset)), Some((WarningKind::MissingIend, no_iend.len() as u64)));
+
+    d.set_fatal_warnings(&[WarningKind::ChecksumMismatch]);
+    let err = d.decode(&bad).unwrap_err();
+    assert_eq!((57, Some(*b"tEXt"), Some(text as u64)), (ErrorCode::from(err.clone()).0, err.chunk_type(), err.offset()));
+    assert!(d.decode(&png).is_ok());
+
+    d.set_fatal_warnings(&[WarningKind::MissingIend]);
+    assert_eq!(52, ErrorCode::from(d.decode(no_iend).unwrap_err()).0);
+    let mut partial = d.partial();
+    partial.feed(no_iend).unwrap();
+    assert_eq!(52, ErrorCode::from(partial.finish().unwrap_err()).0);
+
+    d.set_fatal_warnings(&WarningKind::ALL);
+    assert_eq!(126, ErrorCode::from(d.decode(&png).unwrap_err()).0);
+}
+
+#[test]
+fn crc_checked_after_typed_chunks() {
+    let mut e = Encoder::new();
+    e.set_text_compression(false);
+    e.info_png_mut().create_chunk(ChunkPosition::IHDR, "gAMA", &45455u32.to_be_bytes()).unwrap();
+    e.info_png_mut().add_text("Title", "test").unwrap();
+    let png = e.encode(&[RGBA::new(1, 2, 3, 255)], 1, 1).unwrap();
+    let chunk_pos = |name: &[u8]| png.windows(4).position(|w| w == name).unwrap() - 4;
+    assert!(chunk_pos(b"gAMA") < chunk_pos(b"tEXt"));
+
+    let mut bad = png.clone();
+    let text = chunk_pos(b"tEXt");
+    bad[text + 10] ^= 1;
+    let mut d = Decoder::new();
+    let (_, warnings) = d.decode_with_warnings(&bad).unwrap();
+    let kinds: Vec<_> = warnings.iter().map(|w| (w.kind, w.chunk_type, w.offset)).collect();
+    assert_eq!(kinds, [(WarningKind::ChecksumMismatch, Some(*b"tEXt"), text as u64)]);
+    assert_eq!(Some(45455), d.info_png().gamma);
+
+    let mut bad = png.clone();
+    let idat = chunk_pos(b"IDAT");
+    bad[idat + 10] ^= 1;
+    assert_eq!(57, ErrorCode::from(d.decode(&bad).unwrap_err()).0);
+}
+
+/// Chunk type, invalid data, and a check that its `Info` field is unset
+type InvalidChunk = (&'static str, &'static [u8], fn(&Info) -> bool);
+
+#[test]
+fn invalid_ancillary_chunks() {
+    let invalid: [InvalidChunk; 15] = [
+        ("gAMA", &[0, 1, 2], |i| i.gamma.is_none()),
+        ("cHRM", &[0; 31], |i| i.chromaticities.is_none()),
+        ("sRGB", &[9], |i| i.srgb_intent.is_none()),
+        ("iCCP", b"ICC\0\0not zlib", |i| i.icc_profile.is_none()),
+        ("cICP", &[9, 16, 1, 1], |i| i.cicp.is_none()),
+        ("mDCv", &[0; 23], |i| i.mastering_display.is_none()),
+        ("cLLi", &[0; 9], |i| i.content_light_level.is_none()),
+        ("sPLT", b"pal\0\x04\0\0\0\0\0\0", |i| i.suggested_palettes.is_empty()),
+        ("hIST", &[0, 1], |i| i.histogram.is_empty()),
+        ("oFFs", &[0; 8], |i| i.offset.is_none()),
+        ("sCAL", b"\x011.5\0x", |i| i.physical_scale.is_none()),
+        ("pCAL", b"cal\0\0\0\0\0\0\0\0\0\0\x02", |i| i.calibration.is_none()),
+        ("sTER", &[2], |i| i.stereo.is_none()),
+        ("sBIT", &[8, 8], |i| i.significant_bits.is_none()),
+        ("sBIT", &[8, 9, 8], |i| i.significant_bits.is_none()),
+    ];
+    for (name, data, is_unset) in invalid {
+        let mut e = Encoder::new();
+        e.info_png_mut().create_chunk(ChunkPosition::IHDR, name, data).unwrap();
+        let png = e.encode(&[RGBA::new(1, 2, 3, 255)], 1, 1).unwrap();
+
+        let mut d = Decoder::new();
+        d.remember_unknown_chunks(true);
+        let (_, warnings) = d.decode_with_warnings(&png).unwrap();
+        let kinds: Vec<_> = warnings.iter().map(|w| (w.kind, w.chunk_type)).collect();
+        assert_eq!(kinds, [(WarningKind::InvalidAncillaryChunk, Some(name.as_bytes().try_into().unwrap()))]);
+        assert!(is_unset(d.info_png()), "{name}");
+        assert_eq!(data, d.info_png().get(name).unwrap().data());
+
+        d.set_fatal_warnings(&[WarningKind::InvalidAncillaryChunk]);
+        assert_eq!(139, ErrorCode::from(d.decode(&png).unwrap_err()).0);
+    }
+}
+
+#[test]
+fn recovery_decoding() {
+    let noise = |i: u32| (i.wrapping_mul(i).wrapping_mul(2654435761) >> 24) as u8;
+    let pixels: Vec<RGBA> = (0..32 * 32u32).map(|i| RGBA::new(noise(i), (i / 32) as u8, noise(i + 99), 255)).collect();
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.info_png_mut().color.colortype = ColorType::RGB;
+    e.info_png_mut().background_defined = true;
+    (e.info_png_mut().background_r, e.info_png_mut().background_g, e.info_png_mut().background_b) = (10, 20, 30);
+    let png = e.encode(&pixels, 32, 32).unwrap();
+    assert!(png.len() > 2000, "{}", png.len());
+    let mut d = Decoder::new();
+
+    let (image, recovery) = d.decode_recovering(&png, MissingFill::Transparent).unwrap();
+    assert!(recovery.is_complete());
+    assert_eq!(32, recovery.valid_rows);
+    assert_eq!(image.bytes(), d.decode(&png).unwrap().bytes());
+
+    let truncated = &png[..png.len() * 2 / 3];
+    assert!(d.decode(truncated).is_err());
+    let (image, recovery) = d.decode_recovering(truncated, MissingFill::Transparent).unwrap();
+    let Image::RGBA(image) = image else { panic!() };
+    assert!(!recovery.is_complete());
+    let valid = recovery.valid_rows;
+    assert!(valid > 0 && valid < 32, "{valid}");
+    assert_eq!(image.buffer[..valid * 32], pixels[..valid * 32]);
+    assert!(image.buffer[valid * 32..].iter().all(|&px| px == RGBA::new(0, 0, 0, 0)));
+
+    let (image, _) = d.decode_recovering(truncated, MissingFill::Background).unwrap();
+    let Image::RGBA(image) = image else { panic!() };
+    assert_eq!(image.buffer[32 * 32 - 1], RGBA::new(10, 20, 30, 255));
+
+    /*damaged compressed data*/
+    let idat = png.windows(4).position(|w| w == b"IDAT").unwrap();
+    let mut corrupt = png.clone();
+    corrupt[idat + 1000..idat + 1050].fill(0xFF);
+    let (_, recovery) = d.decode_recovering(&corrupt, MissingFill::Transparent).unwrap();
+    assert!(recovery.error.is_some());
+    assert!(recovery.valid_rows < 32);
+
+    e.info_png_mut().interlace_method = 1;
+    let png = e.encode(&pixels, 32, 32).unwrap();
+    let (image, recovery) = d.decode_recovering(&png[..png.len() / 2], MissingFill::Transparent).unwrap();
+    let Image::RGBA(image) = image else { panic!() };
+    assert!(recovery.passes > 0 && recovery.passes < 7, "{}", recovery.passes);
+    assert_eq!(0, recovery.valid_rows);
+    /*pixels from the first pass are exact, others are copied from them*/
+    assert_eq!(image.buffer[8 * 32 + 8], pixels[8 * 32 + 8]);
+    assert_eq!(image.buffer[32 * 32 - 1].a, 255);
+}
+
+#[test]
+fn validate_png() {
+    let pixels: Vec<RGBA> = (0..16 * 8u32).map(|i| RGBA::new(i as u8, 1, 2, 255)).collect();
+    let mut e = Encoder::new();
+    e.set_text_compression(false);
+    e.info_png_mut().add_text("Title", "ok").unwrap();
+    let png = e.encode(&pixels, 16, 8).unwrap();
+    let report = validate(&png);
+    assert!(report.is_valid(), "{:?}", report.issues);
+    assert_eq!(report.chunks.first(), Some(b"IHDR"));
+    assert_eq!(report.chunks.last(), Some(b"IEND"));
+
+    let codes = |png: &[u8]| -> Vec<(u32, bool)> {
+        validate(png).issues.iter().map(|i| (ErrorCode::from(i.error.clone()).0, i.fatal)).collect()
+    };
+
+    e.info_png_mut().add_text(" bad  key", "x").unwrap();
+    e.info_png_mut().create_chunk(ChunkPosition::PLTE, "gAMA", &45455u32.to_be_bytes()).unwrap();
+    e.info_png_mut().create_chunk(ChunkPosition::IDAT, "gAMA", &45455u32.to_be_bytes()).unwrap();
+    let mut png = e.encode(&pixels, 16, 8).unwrap();
+    png.extend_from_slice(b"junk");
+    let report = validate(&png);
+    assert!(report.is_decodable());
+    let found = codes(&png);
+    for code in [128, 129, 125, 127] {
+        assert!(found.contains(&(code, false)), "{code} {found:?}");
+    }
+    let dup = report.issues.iter().find(|i| ErrorCode::from(i.error.clone()).0 == 128).unwrap();
+    assert_eq!(dup.error.chunk_type(), Some(*b"gAMA"));
+
+    /*CRCs are checked after known and unknown chunks alike, and a mismatch is reported once*/
+    let mut e = Encoder::new();
+    e.set_text_compression(false);
+    e.info_png_mut().create_chunk(ChunkPosition::IHDR, "gAMA", &45455u32.to_be_bytes()).unwrap();
+    e.info_png_mut().create_chunk(ChunkPosition::IHDR, "prVt", b"x").unwrap();
+    e.info_png_mut().add_text("Title", "ok").unwrap();
+    let mut png = e.encode(&pixels, 16, 8).unwrap();
+    let text = png.windows(4).position(|w| w == b"tEXt").unwrap() - 4;
+    png[text + 10] ^= 1;
+    assert_eq!(codes(&png), [(57, false)]);
+    assert_eq!(validate(&png).issues[0].error.offset(), Some(text as u64));
+
+    /*stored zlib blocks make the filter bytes and pixels easy to find*/
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.set_filter_strategy(FilterStrategy::ZERO, false);
+    e.settings_mut().set_level(0);
+    let png = e.encode(&pixels, 16, 8).unwrap();
+    assert!(validate(&png).is_valid());
+    e.info_png_mut().interlace_method = 1;
+    assert!(validate(&e.encode(&pixels, 16, 8).unwrap()).is_valid());
+    let data = png.windows(4).position(|w| w == b"IDAT").unwrap() + 4 + 2 + 5;
+
+    let mut bad_filter = png.clone();
+    bad_filter[data + 65] = 7;
+    let found = codes(&bad_filter);
+    assert!(found.contains(&(36, true)) && found.contains(&(57, true)), "{found:?}");
+
+    let mut bad_adler = png.clone();
+    bad_adler[data + 1] ^= 1;
+    let report = validate(&bad_adler);
+    assert!(!report.is_decodable());
+    let adler = report.issues.iter().find(|i| ErrorCode::from(i.error.clone()).0 == 58).unwrap();
+    assert_eq!(adler.error.chunk_type(), Some(*b"IDAT"));
+
+    let found = codes(&png[..png.len() - 12]);
+    assert_eq!(found, [(52, false)]);
+    assert!(!validate(&png[..40]).is_decodable());
+}
+
+#[test]
+fn rewrite_chunks() {
+    let pixels: Vec<RGBA> = (0..30 * 20u32).map(|i| RGBA::new(i as u8, (i >> 3) as u8, 9, 255)).collect();
+    let mut e = Encoder::new();
+    e.set_text_compression(false);
+    e.info_png_mut().add_text("Title", "keep").unwrap();
+    e.info_png_mut().add_text("Comment", "drop").unwrap();
+    e.info_png_mut().time_defined = true;
+    e.info_png_mut().create_chunk(ChunkPosition::IDAT, "prvt", b"secret").unwrap();
+    let png = e.encode(&pixels, 30, 20).unwrap();
+    let idat = |png: &[u8]| -> Vec<u8> {
+        ChunksIter::new(&png[8..]).filter_map(Result::ok).filter(|c| c.is_type("IDAT")).flat_map(|c| c.data().to_vec()).collect()
+    };
+    let names = |png: &[u8]| -> Vec<[u8; 4]> { ChunksIter::new(&png[8..]).map(|c| c.unwrap().name()).collect() };
+
+    let mut rewriter = ChunkRewriter::new();
+    assert!(rewriter.remove("IDAT").is_err());
+    assert!(rewriter.insert(ChunkPosition::IHDR, "PLTE", &[0; 3]).is_err());
+    assert!(rewriter.insert(ChunkPosition::IHDR, "a1cd", &[]).is_err());
+    rewriter.remove("tIME").unwrap();
+    rewriter.retain(|c| !c.is_private() && (!c.is_type("tEXt") || !c.data().starts_with(b"Comment\0")));
+    rewriter.replace(ChunkPosition::IHDR, "gAMA", &45455u32.to_be_bytes()).unwrap();
+    rewriter.insert(ChunkPosition::PLTE, "pHYs", &[0, 0, 11, 19, 0, 0, 11, 19, 1]).unwrap();
+    rewriter.insert(ChunkPosition::IDAT, "tEXt", b"Author\0me").unwrap();
+    let out = rewriter.rewrite(&png).unwrap();
+    assert_eq!(idat(&png), idat(&out));
+    assert_eq!(&out[..33], &png[..33]);
+    assert!(validate(&out).is_valid(), "{:?}", validate(&out).issues);
+
+    let chunks = names(&out);
+    assert_eq!(chunks, [*b"IHDR", *b"gAMA", *b"pHYs", *b"IDAT", *b"tEXt", *b"tEXt", *b"IEND"], "{:?}", chunks.iter().map(|c| String::from_utf8_lossy(c).into_owned()).collect::<Vec<_>>());
+
+    let mut d = Decoder::new();
+    let original = d.decode(&png).unwrap();
+    assert_eq!(original.bytes(), d.decode(&out).unwrap().bytes());
+    let texts: Vec<_> = d.info_png().text_keys().map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+    assert_eq!(texts, [(b"Title".to_vec(), b"keep".to_vec()), (b"Author".to_vec(), b"me".to_vec())]);
+    assert!(!d.info_png().time_defined);
+    assert_eq!(d.info_png().gamma, Some(45455));
+
+    let mut streamed = Vec::new();
+    rewriter.rewrite_stream(std::io::Cursor::new(&png), &mut streamed).unwrap();
+    assert_eq!(streamed, out);
+    assert!(rewriter.rewrite(&png[..20]).is_err());
+}
+
+#[test]
+#[cfg(feature = "parallel")]
+fn parallel_encode() {
+    let (w, h) = (1100, 1000);
+    let pixels: Vec<RGBA> = (0..w * h).map(|i| RGBA::new((i % w) as u8, (i / w) as u8, (i * 7 / 3) as u8, (i % 253) as u8)).collect();
+    let png = encode32(&pixels, w, h).unwrap();
+    assert!(validate(&png).is_valid());
+    let Image::RGBA(image) = decode_memory(&png, ColorType::RGBA, 8).unwrap() else { panic!() };
+    assert_eq!(image.buffer, pixels);
+}
+
+#[test]
+fn idot_split() {
+    let (w, h) = (70, 61);
+    let pixels: Vec<RGBA> = (0..w * h).map(|i| RGBA::new((i % w) as u8, (i / w) as u8, (i * 13) as u8, 255 - (i % 7) as u8)).collect();
+    let mut e = Encoder::new();
+    e.set_idot(true);
+    let png = e.encode(&pixels, w, h).unwrap();
+    let report = validate(&png);
+    assert!(report.is_valid(), "{:?}", report.issues);
+    let idat = report.chunks.iter().position(|c| c == b"IDAT").unwrap();
+    assert_eq!(&report.chunks[idat - 1..=idat + 1], [*b"iDOT", *b"IDAT", *b"IDAT"]);
+
+    let decode = |png: &[u8]| {
+        let Image::RGBA(image) = Decoder::new().decode(png).unwrap() else { panic!() };
+        image.buffer
+    };
+    assert_eq!(decode(&png), pixels);
+
+    /*a wrong restart offset makes the decoder decompress the data as one stream*/
+    let idot = png.windows(4).position(|w| w == b"iDOT").unwrap() + 4;
+    let mut moved = png.clone();
+    moved[idot + 24..idot + 28].copy_from_slice(&0x7FFF_0000u32.to_be_bytes());
+    let crc = crc32fast::hash(&moved[idot - 4..idot + 28]);
+    moved[idot + 28..idot + 32].copy_from_slice(&crc.to_be_bytes());
+    assert_eq!(decode(&moved), pixels);
+
+    /*the rewriter keeps iDOT only if it still has the offset of the second half*/
+    let restart_chunk = |png: &[u8]| -> Option<[u8; 4]> {
+        let idot = png.windows(4).position(|w| w == b"iDOT")? - 4;
+        let offset = u32::from_be_bytes(png[idot + 32..idot + 36].try_into().unwrap()) as usize;
+        png.get(idot + offset + 4..idot + offset + 8).map(|name| name.try_into().unwrap())
+    };
+    assert_eq!(restart_chunk(&png), Some(*b"IDAT"));
+    let mut rewriter = ChunkRewriter::new();
+    rewriter.insert(ChunkPosition::IHDR, "tEXt", b"Title\0kept").unwrap();
+    let kept = rewriter.rewrite(&png).unwrap();
+    assert_eq!(restart_chunk(&kept), Some(*b"IDAT"));
+    assert_eq!(decode(&kept), pixels);
+    rewriter.insert(ChunkPosition::PLTE, "tEXt", b"Title\0moved").unwrap();
+    let dropped = rewriter.rewrite(&png).unwrap();
+    assert_eq!(restart_chunk(&dropped), None);
+    assert_eq!(decode(&dropped), pixels);
+    assert_eq!(ChunksIter::new(&dropped[8..]).filter(|c| c.as_ref().unwrap().is_type("tEXt")).count(), 2);
+
+    e.info_png_mut().interlace_method = 1;
+    let png = e.encode(&pixels, w, h).unwrap();
+    assert!(!png.windows(4).any(|w| w == b"iDOT"));
+}
+
+#[test]
+fn optimize_png() {
+    let (w, h) = (40, 30);
+    let pixels: Vec<RGBA> = (0..w * h).map(|i| {
+        let v = [0, 80, 160, 240][(i % w / 10 + i / w / 8) % 4];
+        RGBA::new(v, v, v, 255)
+    }).collect();
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.set_filter_strategy(FilterStrategy::ZERO, false);
+    e.settings_mut().set_level(1);
+    e.info_png_mut().add_text("Title", "optimized").unwrap();
+    e.info_png_mut().gamma = Some(45455);
+    e.info_png_mut().background_defined = true;
+    (e.info_png_mut().background_r, e.info_png_mut().background_g, e.info_png_mut().background_b) = (160, 160, 160);
+    let png = e.encode(&pixels, w, h).unwrap();
+
+    let decode = |png: &[u8]| {
+        let mut d = Decoder::new();
+        let Image::RGBA(image) = d.decode(png).unwrap() else { panic!() };
+        assert_eq!(image.buffer, pixels);
+        d.info_png().clone()
+    };
+    let options = OptimizeOptions { levels: vec![9], ..OptimizeOptions::default() };
+    let out = optimize(&png, &options).unwrap();
+    assert!(out.len() < png.len());
+    assert!(validate(&out).is_valid());
+    let info = decode(&out);
+    assert!(info.color.colortype != ColorType::RGBA);
+    assert_eq!(info.text_keys().next(), Some((&b"Title"[..], &b"optimized"[..])));
+    assert_eq!(info.gamma, Some(45455));
+    assert!(info.background_defined);
+
+    let options = OptimizeOptions { keep: KeepMetadata::ColorSpace, try_interlace: false, ..options };
+    let info = decode(&optimize(&png, &options).unwrap());
+    assert_eq!(info.text_keys().count(), 0);
+    assert_eq!(info.gamma, Some(45455));
+    assert!(!info.background_defined);
+
+    /*an already optimized file is returned unchanged*/
+    let options = OptimizeOptions { keep: KeepMetadata::All, ..options };
+    assert_eq!(optimize(&out, &options).unwrap(), out);
+
+    /*iDOT of the input doesn't fit the new IDAT chunks*/
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.settings_mut().set_level(1);
+    e.set_idot(true);
+    let png = e.encode(&pixels, w, h).unwrap();
+    assert!(png.windows(4).any(|w| w == b"iDOT"));
+    let out = optimize(&png, &options).unwrap();
+    assert!(!out.windows(4).any(|w| w == b"iDOT"));
+    decode(&out);
+
+    /*bKGD out of range for the bit depth is dropped*/
+    let mut e = Encoder::new();
+    e.set_auto_convert(false);
+    e.info_raw_mut().colortype = ColorType::GREY;
+    e.info_raw_mut().set_bitdepth(1);
+    e.info_png_mut().color.colortype = ColorType::GREY;
+    e.info_png_mut().color.set_bitdepth(1);
+    e.info_png_mut().create_chunk(ChunkPosition::PLTE, "bKGD", &[0, 2]).unwrap();
+    let png = e.encode(&[0b1010_0000u8], 4, 1).unwrap();
+    let mut d = Decoder::new();
+    d.decode(optimize(&png, &options).unwrap()).unwrap();
+    assert!(!d.info_png().background_defined);
+
+    let e = Encoder::new();
+    let mut anim = e.animation(2, 2).unwrap();
+    anim.add_frame(&[RGBA::new(0, 0, 0, 255); 4], 1, 10).unwrap();
+    anim.add_frame(&[RGBA::new(9, 9, 9, 255); 4], 1, 10).unwrap();
+    assert_eq!(optimize(anim.encode().unwrap(), &options).unwrap_err().code(), ErrorCode(137));
+}
+
+#[test]
+fn palette_sort() {
+    let (w, h) = (16, 16);
+    let colors = [RGBA::new(200, 10, 10, 255), RGBA::new(10, 10, 10, 255), RGBA::new(90, 200, 90, 255), RGBA::new(0, 0, 0, 0), RGBA::new(250, 250, 250, 255)];
+    /*the dark color is the most used, and the transparent one is found late*/
+    let pixels: Vec<RGBA> = (0..w * h).map(|i| colors[if i % 3 == 0 { 1 } else { i / w % colors.len() }]).collect();
+
+    let encode = |sort| {
+        let mut e = Encoder::new();
+        e.set_palette_sort(sort);
+        let png = e.encode(&pixels, w, h).unwrap();
+        let mut d = Decoder::new();
+        let Image::RGBA(image) = d.decode(&png).unwrap() else { panic!() };
+        assert_eq!(image.buffer, pixels);
+        assert_eq!(d.info_png().color.colortype, ColorType::PALETTE);
+        d.info_png().color.palette().to_vec()
+    };
+    assert_eq!(encode(PaletteSort::NONE)[0], colors[1]);
+    assert_eq!(encode(PaletteSort::ALPHA)[..2], [colors[3], colors[1]]);
+    assert_eq!(encode(PaletteSort::LUMINANCE), [colors[3], colors[1], colors[0], colors[2], colors[4]]);
+    assert_eq!(encode(PaletteSort::FREQUENCY)[..2], [colors[3], colors[1]]);
+    let neighbors = encode(PaletteSort::NEIGHBORS);
+    assert_eq!(neighbors[..2], [colors[3], colors[1]]);
+    assert_eq!(neighbors.len(), colors.len());
+
+    /*the background index follows its color*/
+    let mut e = Encoder::new();
+    e.set_palette_sort(PaletteSort::LUMINANCE);
+    e.info_raw_mut().colortype = ColorType::PALETTE;
+    e.info_raw_mut().set_bitdepth(8);
+    e.info_raw_mut().set_palette(&colors).unwrap();
+    e.info_png_mut().color = e.info_raw().clone();
+    e.info_png_mut().background_defined = true;
+    e.info_png_mut().background_r = 4;
+    let indices: Vec<u8> = (0..w * h).map(|i| (i % colors.len()) as u8).collect();
+    let png = e.encode(&indices, w, h).unwrap();
+    let mut d = Decoder::new();
+    d.decode(&png).unwrap();
+    let info = d.info_png();
+    assert_eq!(info.color.palette()[info.background_r as usize], colors[4]);
+    assert_eq!(info.color.palette()[0], colors[3]);
+}
+
+#[test]
+fn alpha_cleanup() {
+    let (w, h) = (20, 10);
+    /*a black and white image, where transparent pixels have random colors*/
+    let pixels: Vec<RGBA> = (0..w * h).map(|i| {
+        let noise = (i as u32).wrapping_mul(2654435761).to_be_bytes();
+        match i % 3 {
+            0 => RGBA::new(noise[0], noise[1], noise[2], 0),
+            1 => RGBA::new(0, 0, 0, 255),
+            _ => RGBA::new(255, 255, 255, 255),
+        }
+    }).collect();
+
+    let encode = |cleanup| {
+        let mut e = Encoder::new();
+        e.set_alpha_cleanup(cleanup);
+        let png = e.encode(&pixels, w, h).unwrap();
+        let mut d = Decoder::new();
+        let Image::RGBA(image) = d.decode(&png).unwrap() else { panic!() };
+        for (out, px) in image.buffer.iter().zip(&pixels) {
+            assert_eq!(out.a, px.a);
+            if px.a != 0 {
+                assert_eq!(out, px);
+            }
+        }
+        (image.buffer, d.info_png().color.clone(), png.len())
+    };
+    let (none, _, none_len) = encode(AlphaCleanup::NONE);
+    assert_eq!(none, pixels);
+
+    let (black, _, black_len) = encode(AlphaCleanup::BLACK);
+    assert!(black.iter().filter(|px| px.a == 0).all(|&px| px == RGBA::new(0, 0, 0, 0)));
+    assert!(black_len < none_len);
+
+    let (left, _, _) = encode(AlphaCleanup::LEFT);
+    assert!((1..left.len()).filter(|&i| left[i].a == 0 && i % w != 0).all(|i| left[i].rgb() == left[i - 1].rgb()));
+
+    let (up, _, _) = encode(AlphaCleanup::UP);
+    assert!((w..up.len()).filter(|&i| up[i].a == 0).all(|i| up[i].rgb() == up[i - w].rgb()));
+
+    /*black and white are used, so the key is another grey*/
+    let (key, color, _) = encode(AlphaCleanup::KEY);
+    assert!(!color.is_alpha_type());
+    let key_color = key[0];
+    assert!(key_color.r != 0 && key_color.r != 255 && key_color.r == key_color.b);
+    assert!(key.iter().filter(|px| px.a == 0).all(|&px| px == key_color));
+
+    /*grey+alpha 16-bit*/
+    let grey: Vec<u16> = (0..w * h).flat_map(|i| if i % 2 == 0 { [i as u16 * 300, 0] } else { [0, 65535] }).collect();
+    let mut e = Encoder::new();
+    e.set_alpha_cleanup(AlphaCleanup::KEY);
+    e.info_raw_mut().colortype = ColorType::GREY_ALPHA;
+    e.info_raw_mut().set_bitdepth(16);
+    let png = e.encode(&grey, w, h).unwrap();
+    let mut d = Decoder::new();
+    d.info_raw_mut().set_bitdepth(16);
+    let Image::RGBA16(image) = d.decode(&png).unwrap() else { panic!() };
+    assert_eq!((d.info_png().color.colortype, d.info_png().color.bitdepth()), (ColorType::GREY, 1));
+    assert!(image.buffer.iter().zip(grey.chunks(2)).all(|(out, px)| out.a == px[1] && out.r == if px[1] == 0 { 65535 } else { 0 }));
+}
+
+#[test]
+fn low_bit_depth_grey_key() {
+    for bitdepth in [1u32, 2, 4] {
+        let values: Vec<u8> = (0..32).map(|i| (i % (1 << bitdepth)) as u8).collect();
+        let packed: Vec<u8> = values.chunks(8 / bitdepth as usize)
+            .map(|c| c.iter().fold(0, |byte, &v| byte << bitdepth | v))
+            .collect();
+        /*the key is a raw sample value, not scaled to 8 bits*/
+        for key in [0, 1] {
+            let mut e = Encoder::new();
+            e.info_raw_mut().colortype = ColorType::GREY;
+            e.info_raw_mut().set_bitdepth(bitdepth);
+            e.info_raw_mut().set_key(key, key, key);
+            let png = e.encode(&packed, 32, 1).unwrap();
+
+            let mut d = Decoder::new();
+            d.info_raw_mut().set_bitdepth(16);
+            let Image::RGBA16(image) = d.decode(&png).unwrap() else { panic!() };
+            assert_eq!((d.info_png().color.colortype, d.info_png().color.bitdepth()), (ColorType::GREY, bitdepth));
+            assert!(image.buffer.iter().zip(&values).all(|(px, &v)| px.a == if v == key as u8 { 0 } else { 65535 }));
+        }
+    }
+}